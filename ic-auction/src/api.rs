@@ -1,35 +1,108 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use ic_canister::{generate_exports, generate_idl, state_getter, update, Canister, Idl, PreUpdate};
-#[cfg(feature = "debug-logs")]
+use ic_canister::{
+    generate_exports, generate_idl, inspect_message, query, state_getter, update, AsyncReturn,
+    Canister, Idl, PreUpdate,
+};
 use ic_exports::ic_cdk;
 use ic_exports::ic_cdk::export::candid::Principal;
-use ic_metrics::Interval;
+use ic_helpers::tokens::Tokens128;
+use ic_metrics::{Interval, MetricsMap};
 
 use crate::error::{AuctionError, Result};
-use crate::state::{AuctionInfo, AuctionState, BiddingInfo};
+use crate::events::{self, AuctionEvent};
+use crate::fee_ratio::{FeeRatioConfig, FeeRatioPolicy};
+use crate::format::{self, AuctionFormat};
+use crate::history::{self, AuctionPage, BidderPage};
+use crate::inspect;
+use crate::rewards;
+use crate::scheduler;
+use crate::state::{
+    AuctionInfo, AuctionMetrics, AuctionState, BiddingInfo, FailedPayout, RewardPayout,
+    UnclaimedRewards,
+};
+use crate::top_up::{self, IcpBiddingConfig, IcpBidsRetry};
 
-pub trait Auction: Canister + Sized {
+/// Cycle auction API of a canister.
+///
+/// The auction keeps its history in the stable memory, so the canister must follow the stable
+/// memory layout described in [`crate::memory`]. In particular, the canister must not save its
+/// state with `ic_storage::stable::write`, which overwrites the auction stable structures.
+pub trait Auction: Canister + Sized + 'static {
     #[state_getter]
     fn auction_state(&self) -> Rc<RefCell<AuctionState>>;
 
+    /// Runs the auction before any update call if the auction is due, unless the auction
+    /// scheduler timer is running. If the scheduler is enabled, but its timer is not armed (e.g.
+    /// after an upgrade), the timer is re-armed after the auction attempt.
     fn canister_pre_update(&self, method_name: &str, _method_type: ic_canister::MethodType) {
+        self.auction_state().borrow_mut().migrate_history();
+
+        let scheduler_enabled = self.auction_state().borrow().scheduler_enabled;
         if method_name == "run_auction" {
             #[cfg(feature = "debug-logs")]
             if !self.auction_state().borrow().bidding_state.is_auction_due() {
                 ic_cdk::println!("Too early to begin auction");
             }
-        } else if let Err(_auction_error) = self.run_auction() {
-            #[cfg(feature = "debug-logs")]
-            ic_cdk::println!("Auction error: {_auction_error:#?}");
+        } else if scheduler::runs_on_update(scheduler_enabled) {
+            let result = self.run_auction();
+            if scheduler_enabled {
+                scheduler::schedule(self, result.is_err());
+            }
+
+            if let Err(_auction_error) = result {
+                #[cfg(feature = "debug-logs")]
+                ic_cdk::println!("Auction error: {_auction_error:#?}");
+            }
         }
     }
 
-    // TODO [CPROD-1056]: Remove default implementation as the
-    // user may forget to overwrite it
+    /// Rejects the ingress messages to the auction API which can't succeed. See
+    /// [`crate::inspect::inspect_message`] for the rules.
+    #[inspect_message(trait = true)]
+    fn inspect_auction_message(
+        &self,
+        method: &str,
+        caller: Principal,
+    ) -> std::result::Result<(), String> {
+        inspect::inspect_message(&self.auction_state().borrow(), method, caller)
+            .map(|_| ())
+            .map_err(String::from)
+    }
+
+    /// Arms the auction scheduler timer if the scheduler is enabled, or stops it otherwise.
+    ///
+    /// Timers are not preserved over upgrades, so the canister must call this method in its
+    /// `post_upgrade` method. See [`crate::scheduler`] for the details.
+    fn start_auction_timer(&self) {
+        scheduler::schedule(self, false);
+    }
+
+    /// Distributes the reward pool between the bidders in proportion to their cycle bids.
+    ///
+    /// The rewards are credited to the internal reward balances of the bidders, and if the
+    /// auction is configured with [`RewardPayout::Transfer`], transferred to the bidders' token
+    /// accounts asynchronously. With [`RewardPayout::Claim`], the bidders claim the rewards
+    /// themselves. See [`crate::rewards`] for the details.
     fn disburse_rewards(&self) -> Result<AuctionInfo> {
-        panic!("disburse_rewards is unimplemented")
+        let (info, rewards) = self.auction_state().borrow_mut().disburse_rewards();
+
+        let payout = self.auction_state().borrow().reward_payout.clone();
+        if let RewardPayout::Transfer(config) = payout {
+            ic_cdk::spawn(async move {
+                rewards::pay_out::<Self>(config, rewards).await;
+            });
+        }
+
+        Ok(info)
+    }
+
+    /// Returns the policy to compute the fee ratio after an auction. By default, the policy
+    /// configured with [`set_fee_ratio_policy`](Self::set_fee_ratio_policy) is used. See
+    /// [`crate::fee_ratio`] for the details.
+    fn fee_ratio_policy(&self) -> Box<dyn FeeRatioPolicy> {
+        Box::new(self.auction_state().borrow().fee_ratio_policy.clone())
     }
 
     /// Starts the cycle auction.
@@ -44,6 +117,7 @@ pub trait Auction: Canister + Sized {
         let auction_state = self.auction_state();
 
         if auction_state.borrow().bidding_state.bids.is_empty() {
+            auction_state.borrow_mut().skip_sealed_bid_auction();
             return Err(AuctionError::NoBids);
         }
 
@@ -56,15 +130,37 @@ pub trait Auction: Canister + Sized {
             ));
         }
 
+        let reserve_check = auction_state.borrow_mut().check_reserve();
+        if let Err(e) = reserve_check {
+            events::flush(&auction_state);
+            return Err(e);
+        }
+
+        auction_state.borrow_mut().migrate_history();
+        let bidders = auction_state.borrow().bidding_state.bids.len() as u64;
         let result = self.disburse_rewards();
+        events::flush(&auction_state);
 
-        auction_state.borrow_mut().reset_bidding_state();
+        let policy = self.fee_ratio_policy();
+        let next_fee_ratio = auction_state
+            .borrow_mut()
+            .reset_bidding_state_with(policy.as_ref());
 
-        if let Ok(result) = result.clone() {
-            auction_state.borrow_mut().history.push(result);
-        }
+        let mut info = result?;
+        info.next_fee_ratio = Some(next_fee_ratio);
+        history::push(info.clone());
+        auction_state
+            .borrow_mut()
+            .record_auction_metrics(&info, bidders);
+        auction_state
+            .borrow_mut()
+            .emit(AuctionEvent::AuctionCompleted {
+                info: info.clone(),
+                bidders,
+            });
+        events::flush(&auction_state);
 
-        result
+        Ok(info)
     }
 
     /// Bid cycles for the next cycle auction.
@@ -74,7 +170,127 @@ pub trait Auction: Canister + Sized {
     /// saved for the next auction.
     #[update(trait = true)]
     fn bid_cycles(&self, bidder: Principal) -> Result<u64> {
-        self.auction_state().borrow_mut().bid_cycles(bidder)
+        let state = self.auction_state();
+        let result = state.borrow_mut().bid_cycles(bidder);
+        events::flush(&state);
+        result
+    }
+
+    /// Returns the account identifier the caller must transfer ICP to before calling
+    /// [`bid_icp`](Self::bid_icp).
+    #[query(trait = true)]
+    fn icp_deposit_account(&self) -> String {
+        top_up::deposit_account(ic_exports::ic_kit::ic::caller()).to_hex()
+    }
+
+    /// Commits a sealed bid of the caller for the next cycle auction.
+    ///
+    /// This method must be called with the cycles provided in the call as the deposit for the bid.
+    /// The bid must be revealed with [`reveal_bid`](Self::reveal_bid) in the reveal phase. See
+    /// [`crate::format`] for the details.
+    #[update(trait = true)]
+    fn commit_bid(&self, commitment: Vec<u8>) -> Result<u64> {
+        let state = self.auction_state();
+        let result = state
+            .borrow_mut()
+            .commit_bid(ic_exports::ic_kit::ic::caller(), commitment);
+        events::flush(&state);
+        result
+    }
+
+    /// Reveals the sealed bid of the caller committed with [`commit_bid`](Self::commit_bid). The
+    /// rest of the deposit is returned to the caller.
+    ///
+    /// The bid is recorded even if the deposit can't be returned, in which case
+    /// [`AuctionError::RefundFailed`] is returned, and the refund can be retried with
+    /// [`retry_refund`](Self::retry_refund).
+    #[update(trait = true)]
+    fn reveal_bid(&self, cycles: u64, salt: Vec<u8>) -> AsyncReturn<Result<u64>> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        let state = self.auction_state();
+        let revealed = state.borrow_mut().reveal_bid(caller, cycles, &salt);
+        events::flush(&state);
+        Box::pin(async move {
+            let (cycles, refund) = revealed?;
+            if refund > 0 {
+                if let Err(e) = format::refund_deposit(caller, refund).await {
+                    state.borrow_mut().add_pending_refund(caller, refund);
+                    return Err(e);
+                }
+            }
+
+            Ok(cycles)
+        })
+    }
+
+    /// Returns the cycles of the sealed bid deposit refunds of the caller, which failed.
+    #[query(trait = true)]
+    fn pending_refund(&self) -> u64 {
+        self.auction_state()
+            .borrow()
+            .pending_refund(ic_exports::ic_kit::ic::caller())
+    }
+
+    /// Retries to return the rest of the sealed bid deposits to the caller, which failed in
+    /// [`reveal_bid`](Self::reveal_bid). Returns the refunded cycles.
+    #[update(trait = true)]
+    fn retry_refund(&self) -> AsyncReturn<Result<u64>> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        let state = self.auction_state();
+        let refund = state.borrow_mut().take_pending_refund(caller);
+        Box::pin(async move {
+            if refund == 0 {
+                return Err(AuctionError::NothingToRefund);
+            }
+
+            if let Err(e) = format::refund_deposit(caller, refund).await {
+                state.borrow_mut().add_pending_refund(caller, refund);
+                return Err(e);
+            }
+
+            Ok(refund)
+        })
+    }
+
+    /// Bid ICP for the next cycle auction.
+    ///
+    /// The `amount` of ICP e8s must be transferred to the caller's
+    /// [`icp_deposit_account`](Self::icp_deposit_account) before the call. The ICP is converted to
+    /// cycles by the cycles minting canister, and the minted cycles are saved as the caller's bid.
+    /// The ledger fee is deducted from the amount. See [`crate::top_up`] for the details.
+    #[update(trait = true)]
+    fn bid_icp(&self, amount: u64) -> AsyncReturn<Result<u64>> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        let state = self.auction_state();
+        let config = state.borrow().icp_bidding.clone();
+        let open_bidding = state.borrow().check_open_bidding();
+        Box::pin(async move {
+            let config = config.ok_or(AuctionError::IcpBiddingDisabled)?;
+            open_bidding?;
+            let result = top_up::bid_icp(state.clone(), config, caller, amount).await;
+            events::flush(&state);
+            result
+        })
+    }
+
+    /// Retries to convert the ICP bids of the caller, which failed after the ICP was transferred
+    /// to the cycles minting canister. Returns the amount of cycles bid and the bids which are
+    /// still pending.
+    ///
+    /// Like [`bid_icp`](Self::bid_icp), this method can't be called in a sealed-bid auction.
+    #[update(trait = true)]
+    fn retry_icp_bids(&self) -> AsyncReturn<Result<IcpBidsRetry>> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        let state = self.auction_state();
+        let config = state.borrow().icp_bidding.clone();
+        let open_bidding = state.borrow().check_open_bidding();
+        Box::pin(async move {
+            let config = config.ok_or(AuctionError::IcpBiddingDisabled)?;
+            open_bidding?;
+            let result = top_up::retry_pending(state.clone(), config, caller).await;
+            events::flush(&state);
+            Ok(result)
+        })
     }
 
     /// Current information about bids and auction.
@@ -84,9 +300,88 @@ pub trait Auction: Canister + Sized {
     }
 
     /// Returns the information about a previously held auction.
-    #[update(trait = true)]
+    #[query(trait = true)]
     fn auction_info(&self, id: usize) -> Result<AuctionInfo> {
-        self.auction_state().borrow_mut().auction_info(id)
+        self.auction_state().borrow().auction_info(id)
+    }
+
+    /// Returns up to `limit` previously held auctions starting from the auction with the `offset`
+    /// id. At most [`history::MAX_PAGE_SIZE`] auctions are returned.
+    #[query(trait = true)]
+    fn auction_history(&self, offset: u64, limit: u64) -> AuctionPage {
+        history::page(offset, limit)
+    }
+
+    /// Returns up to `limit` records of the bids and the rewards of the bidder, starting from the
+    /// auction with the `from_auction_id` id. At most [`history::MAX_PAGE_SIZE`] records are
+    /// returned.
+    #[query(trait = true)]
+    fn bidder_history(&self, bidder: Principal, from_auction_id: u64, limit: u64) -> BidderPage {
+        history::bidder_page(bidder, from_auction_id, limit)
+    }
+
+    /// Returns the reward balance of the caller.
+    #[query(trait = true)]
+    fn reward_balance(&self) -> Tokens128 {
+        self.auction_state()
+            .borrow()
+            .reward_balance(ic_exports::ic_kit::ic::caller())
+    }
+
+    /// Returns the rewards of the caller which are not claimed yet.
+    #[query(trait = true)]
+    fn unclaimed_rewards(&self) -> UnclaimedRewards {
+        self.auction_state()
+            .borrow()
+            .unclaimed_rewards(ic_exports::ic_kit::ic::caller())
+    }
+
+    /// Withdraws all the rewards of the caller to the caller's token account. Returns the amount
+    /// the caller received after the transfer fees.
+    ///
+    /// This method can only be called if the auction is configured with [`RewardPayout::Claim`],
+    /// or with [`RewardPayout::Transfer`] to withdraw the rewards left by the failed transfers.
+    #[update(trait = true)]
+    fn claim_rewards(&self) -> AsyncReturn<Result<Tokens128>> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        let state = self.auction_state();
+        let token = state.borrow().reward_payout.token().cloned();
+        Box::pin(async move {
+            let token = token.ok_or(AuctionError::ClaimsDisabled)?;
+            let result = rewards::claim::<Self>(token, caller).await;
+            events::flush(&state);
+            result
+        })
+    }
+
+    /// Returns the reward transfers which failed and are not retried successfully yet. See
+    /// [`FailedPayout`] for the details.
+    #[query(trait = true)]
+    fn failed_payouts(&self) -> Vec<FailedPayout> {
+        self.auction_state().borrow().failed_payouts.clone()
+    }
+
+    /// Recovers the reward transfers interrupted by IC errors and retries the failed reward
+    /// transfers. Returns the transfers which still fail. See [`rewards::retry_payouts`] for the
+    /// details.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn retry_reward_payouts(&self) -> AsyncReturn<Result<Vec<FailedPayout>>> {
+        let state = self.auction_state();
+        let authorized = state.borrow_mut().authorize_owner().map(|_| ());
+        let token = state.borrow().reward_payout.token().cloned();
+        Box::pin(async move {
+            authorized?;
+            let token = token.ok_or(AuctionError::ClaimsDisabled)?;
+            Ok(rewards::retry_payouts::<Self>(token).await)
+        })
+    }
+
+    /// Returns the metrics of the held auctions.
+    #[query(trait = true)]
+    fn auction_metrics(&self) -> MetricsMap<AuctionMetrics> {
+        self.auction_state().borrow().metrics.clone()
     }
 
     /// Returns the minimum cycles set for the canister.
@@ -123,6 +418,55 @@ pub trait Auction: Canister + Sized {
         Ok(())
     }
 
+    /// Sets the built-in policy to compute the fee ratio after an auction. The ratios of the
+    /// policy must be between `0.0` and `1.0`.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_fee_ratio_policy(&self, policy: FeeRatioConfig) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_fee_ratio_policy(policy)
+    }
+
+    /// Sets the way the auction rewards are paid to the bidders.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_reward_payout(&self, payout: RewardPayout) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_reward_payout(payout);
+        Ok(())
+    }
+
+    /// Enables bidding with ICP with the given ledger and cycles minting canister, or disables it
+    /// if `config` is `None`.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_icp_bidding(&self, config: Option<IcpBiddingConfig>) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_icp_bidding(config);
+        Ok(())
+    }
+
+    /// Sets the format of the auction. See [`crate::format`] for the details.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_auction_format(&self, format: AuctionFormat) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_auction_format(format);
+        Ok(())
+    }
+
     /// Sets the minimum time between two consecutive auctions, in seconds.
     ///
     /// Only the owner is allowed to call this method.
@@ -132,6 +476,21 @@ pub trait Auction: Canister + Sized {
             .borrow_mut()
             .authorize_owner()?
             .set_auction_period(interval);
+        self.start_auction_timer();
+        Ok(())
+    }
+
+    /// Enables or disables running the auctions by a timer exactly when they are due, instead of
+    /// running them on the first update call after that.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_auction_scheduler(&self, enabled: bool) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_scheduler_enabled(enabled);
+        self.start_auction_timer();
         Ok(())
     }
 
@@ -144,3 +503,85 @@ pub trait Auction: Canister + Sized {
 }
 
 generate_exports!(Auction);
+
+#[cfg(test)]
+mod tests {
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_kit::MockContext;
+    use ic_helpers::management::CanisterIDArg;
+
+    use super::*;
+    use crate::test_utils::TestAuction;
+
+    #[tokio::test]
+    async fn icp_bids_are_not_retried_in_sealed_bid_auction() {
+        MockContext::new().inject();
+        let canister = TestAuction::init_instance();
+        {
+            let state = canister.auction_state();
+            let mut state = state.borrow_mut();
+            state.icp_bidding = Some(IcpBiddingConfig {
+                ledger: Principal::from_slice(&[10]),
+                cmc: Principal::from_slice(&[11]),
+            });
+            state.auction_format = AuctionFormat::SealedBid {
+                reveal_period: 1_000,
+            };
+        }
+
+        assert_eq!(
+            canister.retry_icp_bids().await,
+            Err(AuctionError::SealedBidAuction)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_refunds_are_retried() {
+        MockContext::new().inject();
+        let canister = TestAuction::init_instance();
+        let caller = ic_exports::ic_kit::ic::caller();
+        canister
+            .auction_state()
+            .borrow_mut()
+            .add_pending_refund(caller, 1_000_000);
+
+        register_failing_virtual_responder(
+            Principal::management_canister(),
+            "deposit_cycles",
+            "canister not found".into(),
+        );
+        assert!(matches!(
+            canister.retry_refund().await,
+            Err(AuctionError::RefundFailed(_))
+        ));
+        assert_eq!(canister.pending_refund(), 1_000_000);
+
+        register_virtual_responder(
+            Principal::management_canister(),
+            "deposit_cycles",
+            |_: (CanisterIDArg,)| (),
+        );
+        assert_eq!(canister.retry_refund().await, Ok(1_000_000));
+        assert_eq!(canister.pending_refund(), 0);
+        assert_eq!(
+            canister.retry_refund().await,
+            Err(AuctionError::NothingToRefund)
+        );
+    }
+
+    #[test]
+    fn sealed_bid_auction_without_reveals_is_skipped() {
+        let context = MockContext::new().inject();
+        let canister = TestAuction::init_instance();
+        let state = canister.auction_state();
+        state.borrow_mut().auction_format = AuctionFormat::SealedBid { reveal_period: 100 };
+        state.borrow_mut().bidding_state.auction_period = 1000;
+        state.borrow_mut().bidding_state.last_auction = ic_exports::ic_kit::ic::time();
+
+        context.add_time(1000);
+        assert_eq!(canister.run_auction(), Err(AuctionError::NoBids));
+        let bidding_state = state.borrow().bidding_state.clone();
+        assert_eq!(bidding_state.last_auction, ic_exports::ic_kit::ic::time());
+        assert!(!bidding_state.is_auction_due());
+    }
+}
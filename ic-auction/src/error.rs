@@ -17,6 +17,54 @@ pub enum AuctionError {
 
     #[error("the principal {0} is not an auction controller")]
     Unauthorized(String),
+
+    #[error("the auction rewards are not paid in tokens")]
+    ClaimsDisabled,
+
+    #[error("there are no rewards to claim")]
+    NothingToClaim,
+
+    #[error("failed to claim the rewards: {0}")]
+    ClaimFailed(String),
+
+    #[error("bidding with ICP is not enabled")]
+    IcpBiddingDisabled,
+
+    #[error("ledger error: {0}")]
+    LedgerError(String),
+
+    #[error("failed to mint cycles: {0}")]
+    TopUpFailed(String),
+
+    #[error("the auction accepts only sealed bids")]
+    SealedBidAuction,
+
+    #[error("the auction doesn't accept sealed bids")]
+    NotSealedBidAuction,
+
+    #[error("the commit phase of the sealed-bid auction is over")]
+    CommitPhaseOver,
+
+    #[error("the reveal phase of the sealed-bid auction is not started yet")]
+    RevealPhaseNotStarted,
+
+    #[error("the bidder has no committed bid")]
+    BidNotCommitted,
+
+    #[error("the bid doesn't match the commitment")]
+    InvalidCommitment,
+
+    #[error("the bid is recorded, but the rest of the deposit can't be returned: {0}")]
+    RefundFailed(String),
+
+    #[error("there are no deposit refunds to retry")]
+    NothingToRefund,
+
+    #[error("invalid fee ratio policy: {0}")]
+    InvalidFeeRatioPolicy(String),
+
+    #[error("{collected} cycles bid are less than the auction reserve of {reserve} cycles, the auction is skipped")]
+    ReserveNotMet { collected: u64, reserve: u64 },
 }
 
 pub type Result<T> = std::result::Result<T, AuctionError>;
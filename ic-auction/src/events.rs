@@ -0,0 +1,106 @@
+//! Structured events of the auction.
+//!
+//! The host canister can subscribe to the events with [`subscribe`]. The callbacks are kept in the
+//! heap memory, so they must be subscribed again after every upgrade.
+//!
+//! The events are queued in the auction state while it is borrowed, and are delivered to the
+//! callbacks at the end of the auction API calls, after the state borrow is released. So the
+//! callbacks can read the auction state.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_helpers::tokens::Tokens128;
+
+use crate::state::{AuctionInfo, AuctionState, Cycles};
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub enum AuctionEvent {
+    /// Cycles were added to the bid of the bidder.
+    BidPlaced { bidder: Principal, cycles: Cycles },
+    /// A sealed bid was committed with the deposit.
+    BidCommitted { bidder: Principal, deposit: Cycles },
+    /// The auction was held.
+    AuctionCompleted { info: AuctionInfo, bidders: u64 },
+    /// The auction was skipped because the bids didn't reach the reserve.
+    AuctionSkipped { collected: Cycles, reserve: Cycles },
+    /// The bidder claimed the rewards.
+    RewardsClaimed {
+        bidder: Principal,
+        amount: Tokens128,
+    },
+    /// Unclaimed rewards expired and were added to the reward pool.
+    RewardsExpired { amount: Tokens128 },
+}
+
+type Callback = Rc<dyn Fn(&AuctionEvent)>;
+
+/// Subscribes the callback to the auction events.
+pub fn subscribe(callback: impl Fn(&AuctionEvent) + 'static) {
+    CALLBACKS.with(|callbacks| callbacks.borrow_mut().push(Rc::new(callback)));
+}
+
+impl AuctionState {
+    /// Queues the event to be delivered to the subscribed callbacks by [`flush`].
+    pub(crate) fn emit(&mut self, event: AuctionEvent) {
+        self.pending_events.push(event);
+    }
+}
+
+/// Delivers the events queued in the auction state to the subscribed callbacks. The state must not
+/// be borrowed when this function is called.
+pub(crate) fn flush(state: &RefCell<AuctionState>) {
+    let events = std::mem::take(&mut state.borrow_mut().pending_events);
+    if events.is_empty() {
+        return;
+    }
+
+    let callbacks = CALLBACKS.with(|callbacks| callbacks.borrow().clone());
+    for event in &events {
+        for callback in &callbacks {
+            callback(event);
+        }
+    }
+}
+
+thread_local! {
+    static CALLBACKS: RefCell<Vec<Callback>> = RefCell::new(Vec::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_events() {
+        MockContext::new().inject();
+        let bidder = Principal::from_slice(&[1]);
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let state = Rc::new(RefCell::new(AuctionState::default()));
+        let sink = received.clone();
+        let subscribed_state = state.clone();
+        subscribe(move |event| {
+            // The state can be read by the callbacks.
+            let total = subscribed_state.borrow().bidding_state.cycles_since_auction;
+            sink.borrow_mut().push((event.clone(), total));
+        });
+        state.borrow_mut().record_bid(bidder, 1_000_000);
+        assert!(received.borrow().is_empty());
+
+        flush(&state);
+        assert_eq!(
+            *received.borrow(),
+            vec![(
+                AuctionEvent::BidPlaced {
+                    bidder,
+                    cycles: 1_000_000
+                },
+                1_000_000
+            )]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Policies computing the fee ratio for the period until the next auction.
+//!
+//! After every auction the fee ratio is recalculated by the [`FeeRatioPolicy`] returned by
+//! [`Auction::fee_ratio_policy`](crate::api::Auction::fee_ratio_policy). By default the policy
+//! configured in the auction state with [`FeeRatioConfig`] is used, but the canister can provide
+//! its own policy.
+
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize};
+
+use crate::error::{AuctionError, Result};
+use crate::state::Cycles;
+
+/// Canister state the fee ratio is computed from.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRatioInput {
+    /// Current cycles balance of the canister.
+    pub cycles_balance: Cycles,
+    pub min_cycles: Cycles,
+    /// Cycles bid in the auction that has just been held.
+    pub cycles_collected: Cycles,
+}
+
+pub trait FeeRatioPolicy {
+    /// Returns the fee ratio for the period until the next auction, between `0.0` and `1.0`.
+    fn fee_ratio(&self, input: &FeeRatioInput) -> f64;
+}
+
+/// The default policy.
+///
+/// If the cycles balance is not larger than `min_cycles`, all the fees go to the auction. If the
+/// balance is 10 times larger than `min_cycles`, half of the fees go to the auction, and if it is
+/// 1000 times larger, 12.5% of the fees go to the auction. The ratio is further decreased if the
+/// bidders bid a lot of cycles compared to `min_cycles`: bids of `min_cycles` halve the ratio,
+/// and bids of `3 * min_cycles` divide it by 3.
+///
+/// Setting `min_cycles` to zero effectively turns off the auction, as all the fees go to the
+/// owner.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultFeeRatio;
+
+impl FeeRatioPolicy for DefaultFeeRatio {
+    fn fee_ratio(&self, input: &FeeRatioInput) -> f64 {
+        if input.min_cycles == 0 {
+            return 0.0;
+        }
+
+        let min_cycles = input.min_cycles as f64;
+        let balance_ratio = if input.cycles_balance <= input.min_cycles {
+            1.0
+        } else {
+            2f64.powf((min_cycles / input.cycles_balance as f64).log10())
+        };
+
+        let volume = input.cycles_collected as f64 / min_cycles;
+        balance_ratio / (1.0 + (1.0 + volume).log2())
+    }
+}
+
+/// The fee ratio doesn't change.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedFeeRatio(pub f64);
+
+impl FeeRatioPolicy for FixedFeeRatio {
+    fn fee_ratio(&self, _input: &FeeRatioInput) -> f64 {
+        self.0
+    }
+}
+
+/// The fee ratio decreases linearly from `max_ratio` when the cycles balance is `min_cycles` or
+/// lower, to `min_ratio` when the balance is `min_cycles + ramp_cycles` or higher.
+#[derive(Debug, Clone, Copy)]
+pub struct LinearRampFeeRatio {
+    pub min_ratio: f64,
+    pub max_ratio: f64,
+    pub ramp_cycles: Cycles,
+}
+
+impl FeeRatioPolicy for LinearRampFeeRatio {
+    fn fee_ratio(&self, input: &FeeRatioInput) -> f64 {
+        let excess = input.cycles_balance.saturating_sub(input.min_cycles);
+        if excess >= self.ramp_cycles {
+            return self.min_ratio;
+        }
+
+        let progress = excess as f64 / self.ramp_cycles as f64;
+        self.max_ratio - (self.max_ratio - self.min_ratio) * progress
+    }
+}
+
+/// The fee ratio is `1.0` when the cycles balance is `min_cycles` or lower, and halves with every
+/// `half_life_cycles` cycles of the balance above `min_cycles`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialDecayFeeRatio {
+    pub half_life_cycles: Cycles,
+}
+
+impl FeeRatioPolicy for ExponentialDecayFeeRatio {
+    fn fee_ratio(&self, input: &FeeRatioInput) -> f64 {
+        if self.half_life_cycles == 0 {
+            return 0.0;
+        }
+
+        let excess = input.cycles_balance.saturating_sub(input.min_cycles);
+        0.5f64.powf(excess as f64 / self.half_life_cycles as f64)
+    }
+}
+
+/// Built-in fee ratio policies which can be configured by the auction owner.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub enum FeeRatioConfig {
+    #[default]
+    Default,
+    Fixed(f64),
+    LinearRamp {
+        min_ratio: f64,
+        max_ratio: f64,
+        ramp_cycles: Cycles,
+    },
+    ExponentialDecay {
+        half_life_cycles: Cycles,
+    },
+}
+
+impl FeeRatioConfig {
+    /// Checks that the ratios of the policy are numbers between `0.0` and `1.0`, and that the
+    /// minimum ratio of [`FeeRatioConfig::LinearRamp`] is not larger than the maximum one.
+    pub fn validate(&self) -> Result<()> {
+        let check_ratio = |name: &str, ratio: f64| {
+            if (0.0..=1.0).contains(&ratio) {
+                Ok(())
+            } else {
+                Err(AuctionError::InvalidFeeRatioPolicy(format!(
+                    "{name} must be between 0 and 1, but is {ratio}"
+                )))
+            }
+        };
+
+        match *self {
+            Self::Default | Self::ExponentialDecay { .. } => Ok(()),
+            Self::Fixed(ratio) => check_ratio("fee ratio", ratio),
+            Self::LinearRamp {
+                min_ratio,
+                max_ratio,
+                ..
+            } => {
+                check_ratio("min_ratio", min_ratio)?;
+                check_ratio("max_ratio", max_ratio)?;
+                if min_ratio > max_ratio {
+                    return Err(AuctionError::InvalidFeeRatioPolicy(
+                        "min_ratio is larger than max_ratio".into(),
+                    ));
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FeeRatioPolicy for FeeRatioConfig {
+    fn fee_ratio(&self, input: &FeeRatioInput) -> f64 {
+        let ratio = match *self {
+            Self::Default => DefaultFeeRatio.fee_ratio(input),
+            Self::Fixed(ratio) => FixedFeeRatio(ratio).fee_ratio(input),
+            Self::LinearRamp {
+                min_ratio,
+                max_ratio,
+                ramp_cycles,
+            } => LinearRampFeeRatio {
+                min_ratio,
+                max_ratio,
+                ramp_cycles,
+            }
+            .fee_ratio(input),
+            Self::ExponentialDecay { half_life_cycles } => {
+                ExponentialDecayFeeRatio { half_life_cycles }.fee_ratio(input)
+            }
+        };
+
+        ratio.clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(cycles_balance: Cycles, cycles_collected: Cycles) -> FeeRatioInput {
+        FeeRatioInput {
+            cycles_balance,
+            min_cycles: 1000,
+            cycles_collected,
+        }
+    }
+
+    #[test]
+    fn built_in_policies() {
+        let default = FeeRatioConfig::Default;
+        assert_eq!(default.fee_ratio(&input(500, 0)), 1.0);
+        assert!((default.fee_ratio(&input(10_000, 0)) - 0.5).abs() < 1e-9);
+        assert!((default.fee_ratio(&input(500, 1000)) - 0.5).abs() < 1e-9);
+
+        assert_eq!(FeeRatioConfig::Fixed(1.5).fee_ratio(&input(0, 0)), 1.0);
+
+        let ramp = FeeRatioConfig::LinearRamp {
+            min_ratio: 0.2,
+            max_ratio: 0.8,
+            ramp_cycles: 1000,
+        };
+        assert_eq!(ramp.fee_ratio(&input(1000, 0)), 0.8);
+        assert!((ramp.fee_ratio(&input(1500, 0)) - 0.5).abs() < 1e-9);
+        assert_eq!(ramp.fee_ratio(&input(5000, 0)), 0.2);
+
+        let decay = FeeRatioConfig::ExponentialDecay {
+            half_life_cycles: 1000,
+        };
+        assert_eq!(decay.fee_ratio(&input(1000, 0)), 1.0);
+        assert_eq!(decay.fee_ratio(&input(3000, 0)), 0.25);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(FeeRatioConfig::Fixed(0.3).validate().is_ok());
+        assert!(FeeRatioConfig::Fixed(f64::NAN).validate().is_err());
+        assert!(FeeRatioConfig::Fixed(1.5).validate().is_err());
+
+        let ramp = |min_ratio, max_ratio| FeeRatioConfig::LinearRamp {
+            min_ratio,
+            max_ratio,
+            ramp_cycles: 1000,
+        };
+        assert!(ramp(0.2, 0.8).validate().is_ok());
+        assert!(ramp(0.8, 0.2).validate().is_err());
+        assert!(ramp(-0.1, 0.2).validate().is_err());
+    }
+}
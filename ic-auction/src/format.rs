@@ -0,0 +1,90 @@
+//! Formats of the auction.
+//!
+//! The format is selected by the owner in the auction state and defines how the bids are made and
+//! how the reward pool is distributed:
+//!
+//! * [`AuctionFormat::Proportional`] - the bids are open and the reward pool is split between the
+//!   bidders in proportion to their cycles.
+//! * [`AuctionFormat::Capped`] - same as proportional, but the reward of one bidder can't exceed
+//!   the given share of the pool. The excess is split between the other bidders, and if all the
+//!   bidders reach the cap, the rest of the pool is rolled over to the next auction.
+//! * [`AuctionFormat::Reserve`] - same as proportional, but if the bidders bid less cycles than
+//!   the reserve, the auction is skipped, and the bids and the reward pool are rolled over to the
+//!   next auction.
+//! * [`AuctionFormat::SealedBid`] - the bidders commit to their bids without revealing the amount,
+//!   and reveal the bids in the last `reveal_period` before the auction. See below.
+//!
+//! # Sealed-bid auction
+//!
+//! In the commit phase, the bidder canister calls `commit_bid` with the [`bid_commitment`] of the
+//! bid and attaches a deposit of cycles, which must be not less than the bid. The deposit can be
+//! larger than the bid to hide the bid amount. The bid is committed for the caller, so only the
+//! caller can replace the commitment. In the reveal phase, the bidder calls `reveal_bid` with the
+//! bid amount and the salt of the commitment, and the revealed amount becomes the bid. The rest of
+//! the deposit is returned to the bidder canister with the `deposit_cycles` call of the management
+//! canister. If the refund fails, it is kept in the auction state, and the bidder can retry it
+//! with `retry_refund`. The deposits of the bids which are not revealed before the auction are not
+//! returned, and stay with the canister.
+//!
+//! The phases are computed from the time of the last auction. If no bids are revealed by the time
+//! the auction is due, the auction is skipped and the next auction period starts, so the bidders
+//! can commit their bids again. The bids which are not revealed are forfeited in this case too.
+
+use ic_canister::virtual_canister_call;
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_exports::ic_crypto_sha::Sha256;
+use ic_helpers::management::CanisterIDArg;
+
+use crate::error::{AuctionError, Result};
+use crate::state::{Cycles, Timestamp};
+
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub enum AuctionFormat {
+    #[default]
+    Proportional,
+    Capped {
+        /// Maximum share of the reward pool one bidder can get, between `0.0` and `1.0`.
+        max_share: f64,
+    },
+    Reserve {
+        /// Minimum amount of cycles the bidders must bid for the auction to be held.
+        reserve_cycles: Cycles,
+    },
+    SealedBid {
+        /// Period before the auction in nanoseconds, when the bids are revealed.
+        reveal_period: Timestamp,
+    },
+}
+
+/// Bid committed in a sealed-bid auction, which is not revealed yet.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct SealedBid {
+    pub commitment: Vec<u8>,
+    /// Cycles attached to the commitment.
+    pub deposit: Cycles,
+}
+
+/// Returns the commitment of a sealed bid: the SHA-256 hash of the bidder principal bytes, the bid
+/// cycles as 8 big endian bytes, and the salt.
+pub fn bid_commitment(bidder: Principal, cycles: Cycles, salt: &[u8]) -> Vec<u8> {
+    let mut hash = Sha256::new();
+    hash.write(bidder.as_slice());
+    hash.write(&cycles.to_be_bytes());
+    hash.write(salt);
+    hash.finish().to_vec()
+}
+
+/// Returns the unused part of the sealed bid deposit to the bidder canister.
+pub(crate) async fn refund_deposit(bidder: Principal, cycles: Cycles) -> Result<()> {
+    virtual_canister_call!(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIDArg {
+            canister_id: bidder
+        },),
+        (),
+        cycles
+    )
+    .await
+    .map_err(|(_, e)| AuctionError::RefundFailed(e))
+}
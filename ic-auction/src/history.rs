@@ -0,0 +1,260 @@
+//! Auction history in stable memory.
+//!
+//! The information about every held auction is appended to a stable log, and the bid and the
+//! reward of every bidder of the auction are stored in a stable multimap keyed by the bidder, so
+//! the history doesn't need to be serialized on upgrades and can be queried by pages.
+//!
+//! The memory ids of the history are configured with [`crate::memory`].
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{Decode, Encode};
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_helpers::tokens::Tokens128;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableLog, StableMultimap, Storable};
+
+use crate::memory;
+use crate::state::{AuctionInfo, Cycles};
+
+/// Maximum number of entries returned by one page query.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// Participation of a bidder in an auction.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct BidRecord {
+    pub auction_id: u64,
+    /// Cycles bid by the bidder.
+    pub cycles: Cycles,
+    /// Reward the bidder received in the auction.
+    pub reward: Tokens128,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct AuctionPage {
+    pub auctions: Vec<AuctionInfo>,
+    /// Total number of auctions held.
+    pub total: u64,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct BidderPage {
+    /// Records of the bidder's auctions in the order of the auctions.
+    pub records: Vec<BidRecord>,
+    /// Auction id to continue the query from. `None` if there are no more records.
+    pub next_auction_id: Option<u64>,
+}
+
+/// Appends the auction to the history. The auction id must be equal to [`len`].
+pub fn push(info: AuctionInfo) {
+    debug_assert_eq!(info.auction_id as u64, len());
+    HISTORY_LOG.with(|log| {
+        log.borrow_mut()
+            .append(StorableAuctionInfo(info))
+            .expect("failed to append auction info to stable memory")
+    });
+}
+
+/// Returns the information about the auction.
+pub fn get(auction_id: u64) -> Option<AuctionInfo> {
+    HISTORY_LOG.with(|log| log.borrow().get(auction_id).map(|info| info.0))
+}
+
+/// Number of auctions held.
+pub fn len() -> u64 {
+    HISTORY_LOG.with(|log| log.borrow().len())
+}
+
+/// Returns up to `limit` auctions starting from the auction with the `offset` id.
+pub fn page(offset: u64, limit: u64) -> AuctionPage {
+    let total = len();
+    let end = total.min(offset.saturating_add(limit.min(MAX_PAGE_SIZE)));
+    AuctionPage {
+        auctions: (offset..end).filter_map(get).collect(),
+        total,
+    }
+}
+
+/// Stores the bids and the rewards of the bidders of the auction.
+pub fn record_bids(
+    auction_id: u64,
+    bids: impl IntoIterator<Item = (Principal, Cycles, Tokens128)>,
+) {
+    BIDDER_HISTORY.with(|map| {
+        let mut map = map.borrow_mut();
+        for (bidder, cycles, reward) in bids {
+            let record = BidRecord {
+                auction_id,
+                cycles,
+                reward,
+            };
+            map.insert(&PrincipalKey(bidder), &AuctionId(auction_id), &record);
+        }
+    });
+}
+
+/// Returns up to `limit` records of the bidder's auctions starting from the auction with the
+/// `from_auction_id` id.
+pub fn bidder_page(bidder: Principal, from_auction_id: u64, limit: u64) -> BidderPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    BIDDER_HISTORY.with(|map| {
+        let mut records = map
+            .borrow()
+            .range(&PrincipalKey(bidder))
+            .map(|(_, record)| record)
+            .skip_while(|record| record.auction_id < from_auction_id)
+            .take(limit + 1)
+            .collect::<Vec<_>>();
+
+        let next_auction_id = if records.len() > limit {
+            records.pop().map(|record| record.auction_id)
+        } else {
+            None
+        };
+
+        BidderPage {
+            records,
+            next_auction_id,
+        }
+    })
+}
+
+struct StorableAuctionInfo(AuctionInfo);
+
+impl Storable for StorableAuctionInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(&self.0)
+            .expect("failed to serialize auction info")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(Decode!(&bytes, AuctionInfo).expect("failed to deserialize auction info"))
+    }
+}
+
+struct PrincipalKey(Principal);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.as_slice().into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    // max bytes count in Principal
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Auction id stored in big endian, so that the records of a bidder are ordered by the auction.
+struct AuctionId(u64);
+
+impl Storable for AuctionId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_be_bytes().to_vec().into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes);
+        Self(u64::from_be_bytes(buf))
+    }
+}
+
+impl BoundedStorable for AuctionId {
+    const MAX_SIZE: u32 = 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for BidRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE as usize);
+        bytes.extend_from_slice(&self.auction_id.to_le_bytes());
+        bytes.extend_from_slice(&self.cycles.to_le_bytes());
+        bytes.extend_from_slice(&self.reward.amount.to_le_bytes());
+        bytes.into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut auction_id = [0u8; 8];
+        let mut cycles = [0u8; 8];
+        let mut reward = [0u8; 16];
+        auction_id.copy_from_slice(&bytes[0..8]);
+        cycles.copy_from_slice(&bytes[8..16]);
+        reward.copy_from_slice(&bytes[16..32]);
+
+        Self {
+            auction_id: u64::from_le_bytes(auction_id),
+            cycles: u64::from_le_bytes(cycles),
+            reward: Tokens128::from(u128::from_le_bytes(reward)),
+        }
+    }
+}
+
+impl BoundedStorable for BidRecord {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static HISTORY_LOG: RefCell<StableLog<StorableAuctionInfo>> = {
+        let ids = memory::use_memory_ids();
+        let log = StableLog::new(MemoryId::new(ids.history_index), MemoryId::new(ids.history_data))
+            .expect("failed to initialize auction history log");
+        RefCell::new(log)
+    };
+
+    static BIDDER_HISTORY: RefCell<StableMultimap<PrincipalKey, AuctionId, BidRecord>> = {
+        let memory_id = MemoryId::new(memory::use_memory_ids().bidder_history);
+        RefCell::new(StableMultimap::new(memory_id))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn bidder_history_is_paged_by_auction() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+
+        for auction_id in 0..5 {
+            record_bids(
+                auction_id,
+                [
+                    (
+                        alice,
+                        1000 + auction_id,
+                        Tokens128::from(auction_id as u128),
+                    ),
+                    (bob, 1, Tokens128::ZERO),
+                ],
+            );
+        }
+
+        let page = bidder_page(alice, 1, 3);
+        assert_eq!(
+            page.records
+                .iter()
+                .map(|r| r.auction_id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(page.records[2].cycles, 1003);
+        assert_eq!(page.records[2].reward, Tokens128::from(3));
+        assert_eq!(page.next_auction_id, Some(4));
+
+        let page = bidder_page(alice, 4, 3);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.next_auction_id, None);
+    }
+}
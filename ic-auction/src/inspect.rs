@@ -1,5 +1,16 @@
+//! Validation of the ingress messages to the auction API.
+//!
+//! The [`Auction`](crate::api::Auction) trait registers an `inspect_message` hook using these
+//! rules, so the calls which can't succeed are rejected before the canister pays for their
+//! execution. A canister exporting the auction API can add its own rules with
+//! `ic_canister::register_inspect_message_hook`.
+
+use ic_exports::ic_cdk::export::candid::Principal;
+
+use crate::state::AuctionState;
+
 /// Reason why the method may be accepted.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AcceptReason {
     /// The call is a part of the auction API and can be performed.
     Valid,
@@ -7,26 +18,70 @@ pub enum AcceptReason {
     NotAuctionMethod,
 }
 
-pub fn inspect_message(method: &str, caller: Principal) -> Result<AcceptReason, &'static str> {
+/// Checks if the ingress message can be accepted by the auction.
+///
+/// * `run_auction` is accepted only if the auction is due and the caller is the auction
+///   controller or one of the current bidders.
+/// * `bid_cycles` and `commit_bid` are always rejected, as a call with cycles cannot be made
+///   through ingress.
+pub fn inspect_message(
+    state: &AuctionState,
+    method: &str,
+    caller: Principal,
+) -> Result<AcceptReason, &'static str> {
     match method {
-        "runAuction" => {
-            // We allow running auction only to the owner or any of the cycle bidders.
-            let state = CanisterState::get();
-            let state = state.borrow();
+        "run_auction" => {
             let bidding_state = &state.bidding_state;
             if bidding_state.is_auction_due()
-                && (bidding_state.bids.contains_key(&caller) || caller == state.stats.owner)
+                && (bidding_state.bids.contains_key(&caller) || caller == state.controller)
             {
                 Ok(AcceptReason::Valid)
             } else {
                 Err("auction is not due yet or auction run method is called not by owner or bidder, rejecting.")
             }
         }
-        "bidCycles" => {
+        "bid_cycles" | "commit_bid" => {
             // We reject this message, because a call with cycles cannot be made through ingress,
             // only from the wallet canister.
             Err("call with cycles cannot be made through ingress environment.")
         }
-        _ => Ok(AcceprReason::NotAuctionMethod),
+        _ => Ok(AcceptReason::NotAuctionMethod),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::{ic, MockContext};
+
+    use super::*;
+
+    #[test]
+    fn run_auction_is_accepted_only_when_due() {
+        MockContext::new().inject();
+        let controller = Principal::from_slice(&[1]);
+        let bidder = Principal::from_slice(&[2]);
+        let stranger = Principal::from_slice(&[3]);
+
+        let mut state = AuctionState::default();
+        state.controller = controller;
+        state.bidding_state.bids.insert(bidder, 1_000_000);
+        state.bidding_state.last_auction = ic::time() + 1000;
+
+        assert!(inspect_message(&state, "run_auction", controller).is_err());
+
+        state.bidding_state.last_auction = 0;
+        state.bidding_state.auction_period = 0;
+        for caller in [controller, bidder] {
+            assert_eq!(
+                inspect_message(&state, "run_auction", caller),
+                Ok(AcceptReason::Valid)
+            );
+        }
+        assert!(inspect_message(&state, "run_auction", stranger).is_err());
+        assert!(inspect_message(&state, "bid_cycles", bidder).is_err());
+        assert_eq!(
+            inspect_message(&state, "bidding_info", stranger),
+            Ok(AcceptReason::NotAuctionMethod)
+        );
     }
 }
@@ -1,3 +1,15 @@
 pub mod api;
 pub mod error;
+pub mod events;
+pub mod fee_ratio;
+pub mod format;
+pub mod history;
+pub mod inspect;
+pub mod memory;
+pub mod rewards;
+pub mod scheduler;
 pub mod state;
+pub mod top_up;
+
+#[cfg(test)]
+mod test_utils;
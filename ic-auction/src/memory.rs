@@ -0,0 +1,134 @@
+//! Stable memory layout of the auction.
+//!
+//! The auction keeps its history and the recovery list of the reward transfers in the stable
+//! structures of `ic-stable-structures`, in the virtual memories with the ids given by
+//! [`AuctionMemoryIds`]. The ids 251 to 254 are used by default. A canister which uses these ids
+//! for its own stable structures must move the auction to other ids with [`set_memory_ids`] in
+//! its `init` and `post_upgrade` methods, before any auction method is called.
+//!
+//! The memory manager of the stable structures owns the whole stable memory starting from offset
+//! 0. [`ic_storage::stable::write`] and [`ic_storage::stable::read`] use the stable memory from
+//! offset 0 as well, so a canister with the auction must not use them. This includes the upgrade
+//! methods generated by the `Canister` derive macro for a `#[state]` field: the canister must be
+//! declared with `#[canister_no_upgrade_methods]` and keep its state, including the
+//! [`AuctionState`](crate::state::AuctionState), in stable structures, e.g. serialized to a
+//! `StableCell`.
+
+use std::cell::Cell;
+
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize};
+
+/// Ids of the virtual stable memories used by the auction. All the ids must be different.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct AuctionMemoryIds {
+    /// Index of the auction history log.
+    pub history_index: u8,
+    /// Data of the auction history log.
+    pub history_data: u8,
+    /// Bids and rewards of the bidders.
+    pub bidder_history: u8,
+    /// Recovery list of the reward transfers.
+    pub rewards_recovery_list: u8,
+}
+
+impl AuctionMemoryIds {
+    pub const DEFAULT: Self = Self {
+        history_index: 251,
+        history_data: 252,
+        bidder_history: 253,
+        rewards_recovery_list: 254,
+    };
+
+    fn all(&self) -> [u8; 4] {
+        [
+            self.history_index,
+            self.history_data,
+            self.bidder_history,
+            self.rewards_recovery_list,
+        ]
+    }
+}
+
+impl Default for AuctionMemoryIds {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+thread_local! {
+    static MEMORY_IDS: Cell<AuctionMemoryIds> = Cell::new(AuctionMemoryIds::DEFAULT);
+    static MEMORY_IDS_IN_USE: Cell<bool> = Cell::new(false);
+}
+
+/// Sets the ids of the stable memories used by the auction.
+///
+/// The ids are kept in the heap memory, so they must be set in both `init` and `post_upgrade`
+/// methods of the canister, before any auction method is called.
+///
+/// # Panics
+///
+/// Panics if the ids are not different, or if the auction stable structures are already
+/// initialized with other ids.
+pub fn set_memory_ids(ids: AuctionMemoryIds) {
+    let all = ids.all();
+    assert!(
+        all.iter()
+            .enumerate()
+            .all(|(i, id)| !all[i + 1..].contains(id)),
+        "auction memory ids must be different"
+    );
+    assert!(
+        !MEMORY_IDS_IN_USE.with(Cell::get) || memory_ids() == ids,
+        "auction stable structures are already initialized with other memory ids"
+    );
+
+    MEMORY_IDS.with(|cell| cell.set(ids));
+}
+
+/// Returns the ids of the stable memories used by the auction.
+pub fn memory_ids() -> AuctionMemoryIds {
+    MEMORY_IDS.with(Cell::get)
+}
+
+/// Returns the memory ids to initialize an auction stable structure with. The ids can't be changed
+/// after that.
+pub(crate) fn use_memory_ids() -> AuctionMemoryIds {
+    MEMORY_IDS_IN_USE.with(|cell| cell.set(true));
+    memory_ids()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDS: AuctionMemoryIds = AuctionMemoryIds {
+        history_index: 1,
+        history_data: 2,
+        bidder_history: 3,
+        rewards_recovery_list: 4,
+    };
+
+    #[test]
+    fn same_memory_ids_can_be_set_after_use() {
+        set_memory_ids(IDS);
+        assert_eq!(use_memory_ids(), IDS);
+        set_memory_ids(IDS);
+        assert_eq!(memory_ids(), IDS);
+    }
+
+    #[test]
+    #[should_panic(expected = "already initialized with other memory ids")]
+    fn memory_ids_cant_be_changed_after_use() {
+        use_memory_ids();
+        set_memory_ids(IDS);
+    }
+
+    #[test]
+    #[should_panic(expected = "auction memory ids must be different")]
+    fn memory_ids_must_be_different() {
+        set_memory_ids(AuctionMemoryIds {
+            bidder_history: 1,
+            ..IDS
+        });
+    }
+}
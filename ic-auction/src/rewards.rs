@@ -0,0 +1,355 @@
+//! Payout of the auction rewards through the ICRC-1 token terminal.
+//!
+//! The rewards are always credited to the internal reward balances of the bidders first. If the
+//! auction is configured with [`RewardPayout::Transfer`](crate::state::RewardPayout), the rewards
+//! are then withdrawn from the internal balances to the bidders' token accounts. If a transfer
+//! is rejected, the reward stays on the internal balance of the bidder. If the outcome of a
+//! transfer is unknown because of an IC error, the transfer is saved to the recovery list of the
+//! terminal.
+//!
+//! With [`RewardPayout::Claim`](crate::state::RewardPayout) the auction doesn't make any
+//! transfers, and the bidders withdraw their rewards by [`claim`]. The unclaimed rewards can
+//! expire, in which case they are added to the reward pool of the next auction. The bidders can
+//! also [`claim`] the rewards left on their balances by the failed transfers.
+//!
+//! The failed transfers are recorded to
+//! [`AuctionState::failed_payouts`](crate::state::AuctionState) until they are completed by
+//! [`retry_payouts`] or the rewards are claimed.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use ic_exports::ic_cdk::export::candid::Principal;
+use ic_exports::ic_kit::ic;
+use ic_helpers::tokens::Tokens128;
+use ic_payments::error::{PaymentError, RecoveryDetails};
+use ic_payments::recovery_list::DynamicStableRecoveryList;
+use ic_payments::{BalanceError, Balances, TokenConfiguration, TokenTerminal};
+
+use crate::api::Auction;
+use crate::error::{self, AuctionError};
+use crate::events::AuctionEvent;
+use crate::memory;
+use crate::state::{AuctionState, FailedPayout};
+
+/// Token terminal of the reward transfers. Its recovery list is stored in the stable memory with
+/// the id configured with [`crate::memory`].
+pub type RewardsTerminal<A> = TokenTerminal<RewardBalances<A>, DynamicStableRecoveryList>;
+
+fn terminal<A: Auction>(
+    config: TokenConfiguration,
+    balances: RewardBalances<A>,
+) -> RewardsTerminal<A> {
+    let recovery_list =
+        DynamicStableRecoveryList::new(memory::use_memory_ids().rewards_recovery_list);
+    TokenTerminal::new_with_recovery_list(config, balances, recovery_list)
+}
+
+/// Internal reward balances of the bidders, stored in the state returned by
+/// [`Auction::auction_state`] of the canister `A`.
+///
+/// [`Balances`] must be `Send` and `Sync`, so the state `Rc` can't be stored in this object, and
+/// the state is requested from the canister on every access instead.
+pub struct RewardBalances<A>(PhantomData<fn() -> A>);
+
+impl<A: Auction> RewardBalances<A> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+
+    fn state(&self) -> Rc<RefCell<AuctionState>> {
+        A::from_principal(ic::id()).auction_state()
+    }
+
+    /// Returns the reward balance of the account owner.
+    pub fn balance_of(&self, account_owner: Principal) -> Tokens128 {
+        self.state().borrow().reward_balance(account_owner)
+    }
+}
+
+impl<A: Auction> Default for RewardBalances<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Auction> Balances for RewardBalances<A> {
+    fn credit(
+        &mut self,
+        account_owner: Principal,
+        amount: Tokens128,
+    ) -> Result<Tokens128, BalanceError> {
+        let balance = (self.balance_of(account_owner) + amount)
+            .ok_or_else(|| BalanceError::Fatal("balance overflow".into()))?;
+        self.state()
+            .borrow_mut()
+            .set_reward_balance(account_owner, balance);
+        Ok(balance)
+    }
+
+    fn debit(
+        &mut self,
+        account_owner: Principal,
+        amount: Tokens128,
+    ) -> Result<Tokens128, BalanceError> {
+        let balance =
+            (self.balance_of(account_owner) - amount).ok_or(BalanceError::InsufficientFunds)?;
+        self.state()
+            .borrow_mut()
+            .set_reward_balance(account_owner, balance);
+        Ok(balance)
+    }
+}
+
+/// Transfers the rewards from the internal reward balances to the bidders' token accounts. The
+/// failed transfers are added to the `failed_payouts` of the auction state, and are also returned.
+pub async fn pay_out<A: Auction>(
+    config: TokenConfiguration,
+    rewards: Vec<(Principal, Tokens128)>,
+) -> Vec<FailedPayout> {
+    let balances = RewardBalances::<A>::new();
+    let state = balances.state();
+    let mut terminal = terminal(config, balances);
+    let mut failed = vec![];
+    for (bidder, amount) in rewards {
+        if let Err(e) = terminal.withdraw(bidder, amount).await {
+            failed.push(FailedPayout {
+                bidder,
+                amount,
+                error: e.to_string(),
+            });
+        }
+    }
+
+    state.borrow_mut().add_failed_payouts(failed.clone());
+    failed
+}
+
+/// Retries the failed reward transfers.
+///
+/// The transfers saved to the recovery list are recovered first, and then the remaining reward
+/// balances of the bidders with failed payouts are transferred to their token accounts. The
+/// transfers which fail again, or are still waiting for recovery, replace the recorded failed
+/// payouts, and are also returned.
+pub async fn retry_payouts<A: Auction>(config: TokenConfiguration) -> Vec<FailedPayout> {
+    let balances = RewardBalances::<A>::new();
+    let state = balances.state();
+    let bidders: Vec<_> = std::mem::take(&mut state.borrow_mut().failed_payouts)
+        .into_iter()
+        .map(|payout| payout.bidder)
+        .collect();
+
+    let mut terminal = terminal(config, balances);
+    terminal.recover_all().await;
+
+    let mut failed = vec![];
+    for bidder in bidders {
+        let amount = terminal.balances().balance_of(bidder);
+        if amount.is_zero() {
+            continue;
+        }
+
+        if let Err(e) = terminal.withdraw(bidder, amount).await {
+            failed.push(FailedPayout {
+                bidder,
+                amount,
+                error: e.to_string(),
+            });
+        }
+    }
+
+    failed.extend(
+        terminal
+            .list_for_recovery()
+            .into_iter()
+            .map(|transfer| FailedPayout {
+                bidder: transfer.caller(),
+                amount: transfer.amount(),
+                error: PaymentError::Recoverable(RecoveryDetails::IcError).to_string(),
+            }),
+    );
+
+    let mut state = state.borrow_mut();
+    state.add_failed_payouts(failed);
+    state.failed_payouts.clone()
+}
+
+/// Withdraws all the rewards of the claimer to the claimer's token account. Returns the amount
+/// received by the claimer, which is the reward minus the transfer fees.
+///
+/// The failed payout of the claimer, if any, is removed after a successful claim.
+pub async fn claim<A: Auction>(
+    config: TokenConfiguration,
+    claimer: Principal,
+) -> error::Result<Tokens128> {
+    let balances = RewardBalances::<A>::new();
+    let amount = balances.balance_of(claimer);
+    if amount.is_zero() {
+        return Err(AuctionError::NothingToClaim);
+    }
+
+    let state = balances.state();
+    let mut terminal = terminal(config, balances);
+    let (_, received) = terminal
+        .withdraw(claimer, amount)
+        .await
+        .map_err(|e| AuctionError::ClaimFailed(e.to_string()))?;
+
+    let mut state = state.borrow_mut();
+    state
+        .failed_payouts
+        .retain(|payout| payout.bidder != claimer);
+    state.emit(AuctionEvent::RewardsClaimed {
+        bidder: claimer,
+        amount,
+    });
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Nat;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_icrc1::endpoints::{TransferArg, TransferError};
+    use ic_exports::ic_icrc1::Account;
+    use ic_exports::ic_kit::MockContext;
+    use ic_storage::IcStorage;
+
+    use super::*;
+    use crate::test_utils::TestAuction;
+
+    fn token_config() -> TokenConfiguration {
+        TokenConfiguration {
+            principal: Principal::from_slice(&[10]),
+            fee: 10.into(),
+            minting_account: Account {
+                owner: Principal::from_slice(&[11]).into(),
+                subaccount: None,
+            },
+        }
+    }
+
+    /// Makes the token reject the transfers of more than `max_amount` tokens.
+    fn setup_token(max_amount: u128) {
+        register_virtual_responder(
+            token_config().principal,
+            "icrc1_transfer",
+            move |(arg,): (TransferArg,)| {
+                if arg.amount > Nat::from(max_amount) {
+                    Err(TransferError::InsufficientFunds { balance: 0.into() })
+                } else {
+                    Ok::<Nat, TransferError>(1.into())
+                }
+            },
+        );
+    }
+
+    fn set_balance(bidder: Principal, amount: u128) {
+        AuctionState::get()
+            .borrow_mut()
+            .set_reward_balance(bidder, amount.into());
+    }
+
+    fn balance(bidder: Principal) -> Tokens128 {
+        AuctionState::get().borrow().reward_balance(bidder)
+    }
+
+    #[tokio::test]
+    async fn failed_payouts_are_accumulated_and_retried() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        set_balance(alice, 1000);
+        set_balance(bob, 4000);
+        setup_token(1500);
+
+        let rewards = vec![(alice, 1000.into()), (bob, 2000.into())];
+        let failed = pay_out::<TestAuction>(token_config(), rewards).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].bidder, bob);
+        assert_eq!(balance(alice), Tokens128::ZERO);
+        assert_eq!(balance(bob), 4000.into());
+
+        pay_out::<TestAuction>(token_config(), vec![(bob, 2000.into())]).await;
+        let failed_payouts = AuctionState::get().borrow().failed_payouts.clone();
+        assert_eq!(failed_payouts.len(), 1);
+        assert_eq!(failed_payouts[0].amount, 4000.into());
+
+        setup_token(u128::MAX);
+        assert!(retry_payouts::<TestAuction>(token_config())
+            .await
+            .is_empty());
+        assert_eq!(balance(bob), Tokens128::ZERO);
+        assert!(AuctionState::get().borrow().failed_payouts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn interrupted_payouts_are_recovered_by_retry() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        set_balance(alice, 1000);
+        register_failing_virtual_responder(
+            token_config().principal,
+            "icrc1_transfer",
+            "IC error".into(),
+        );
+
+        let failed = pay_out::<TestAuction>(token_config(), vec![(alice, 1000.into())]).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(balance(alice), Tokens128::ZERO);
+
+        let failed = retry_payouts::<TestAuction>(token_config()).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].bidder, alice);
+
+        setup_token(u128::MAX);
+        assert!(retry_payouts::<TestAuction>(token_config())
+            .await
+            .is_empty());
+        assert_eq!(balance(alice), Tokens128::ZERO);
+    }
+
+    #[tokio::test]
+    async fn claim_withdraws_whole_balance() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        assert_eq!(
+            claim::<TestAuction>(token_config(), alice).await,
+            Err(AuctionError::NothingToClaim)
+        );
+
+        set_balance(alice, 1000);
+        AuctionState::get()
+            .borrow_mut()
+            .add_failed_payouts(vec![FailedPayout {
+                bidder: alice,
+                amount: 1000.into(),
+                error: "rejected".into(),
+            }]);
+        setup_token(500);
+        assert!(matches!(
+            claim::<TestAuction>(token_config(), alice).await,
+            Err(AuctionError::ClaimFailed(_))
+        ));
+        assert_eq!(balance(alice), 1000.into());
+
+        setup_token(u128::MAX);
+        assert_eq!(
+            claim::<TestAuction>(token_config(), alice).await,
+            Ok(980.into())
+        );
+        assert_eq!(balance(alice), Tokens128::ZERO);
+
+        let state = AuctionState::get();
+        assert!(state.borrow().failed_payouts.is_empty());
+        assert_eq!(
+            state.borrow().pending_events,
+            vec![AuctionEvent::RewardsClaimed {
+                bidder: alice,
+                amount: 1000.into()
+            }]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Opt-in timer driven auction scheduling.
+//!
+//! By default the auction is started by the first update call made after the auction is due (see
+//! [`Auction::canister_pre_update`]). If the scheduler is enabled, the auction is run by a timer
+//! exactly at `last_auction + auction_period` instead, and other update calls don't try to run the
+//! auction. If the auction fails because there were no bids, the next attempt is made after the
+//! auction period.
+//!
+//! Timers are not persisted across canister upgrades, so the canister should call
+//! [`Auction::start_auction_timer`] in its `post_upgrade` method to re-arm the scheduler. Until the
+//! timer is re-armed, the auction is run by the update calls, and the first update call re-arms the
+//! timer.
+
+use std::cell::Cell;
+
+use ic_exports::ic_kit::ic;
+
+use crate::api::Auction;
+use crate::state::Timestamp;
+
+/// Returns the time the next auction is scheduled at, or `None` if the scheduler is not running.
+pub fn scheduled_time() -> Option<Timestamp> {
+    SCHEDULED_AT.with(Cell::get)
+}
+
+/// Returns `true` if the auction must be run by the update calls, i.e. the scheduler is disabled
+/// or its timer is not armed.
+pub(crate) fn runs_on_update(scheduler_enabled: bool) -> bool {
+    !scheduler_enabled || scheduled_time().is_none()
+}
+
+/// Arms the auction timer for the next auction, or stops it if the scheduler is disabled.
+/// `after_failure` tells that the last scheduled auction failed, so it must not be retried
+/// immediately.
+pub(crate) fn schedule<A: Auction>(canister: &A, after_failure: bool) {
+    let now = ic::time();
+    let scheduled_at = {
+        let state = canister.auction_state();
+        let state = state.borrow();
+        let next_auction = state.bidding_state.next_auction_time();
+        state.scheduler_enabled.then(|| {
+            if next_auction > now {
+                next_auction
+            } else if after_failure {
+                now + state.bidding_state.auction_period
+            } else {
+                now
+            }
+        })
+    };
+
+    SCHEDULED_AT.with(|cell| cell.set(scheduled_at));
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        use std::time::Duration;
+
+        use ic_exports::ic_cdk_timers;
+
+        AUCTION_TIMER.with(|timer| {
+            if let Some(timer_id) = timer.take() {
+                ic_cdk_timers::clear_timer(timer_id);
+            }
+
+            if let Some(scheduled_at) = scheduled_at {
+                let timer_id = ic_cdk_timers::set_timer(
+                    Duration::from_nanos(scheduled_at.saturating_sub(now)),
+                    || {
+                        let canister = A::from_principal(ic::id());
+                        let result = canister.run_auction();
+                        schedule(&canister, result.is_err());
+                    },
+                );
+                timer.set(Some(timer_id));
+            }
+        });
+    }
+}
+
+thread_local! {
+    static SCHEDULED_AT: Cell<Option<Timestamp>> = Cell::new(None);
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    static AUCTION_TIMER: Cell<Option<ic_exports::ic_cdk_timers::TimerId>> = Cell::new(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_calls_run_auction_until_timer_is_armed() {
+        assert!(runs_on_update(false));
+        assert!(runs_on_update(true));
+
+        SCHEDULED_AT.with(|cell| cell.set(Some(100)));
+        assert!(!runs_on_update(true));
+        assert!(runs_on_update(false));
+    }
+}
@@ -2,11 +2,18 @@ use std::collections::HashMap;
 
 use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 use ic_exports::ic_kit::ic;
+use ic_exports::BlockHeight;
 use ic_helpers::tokens::Tokens128;
-use ic_metrics::Interval;
+use ic_metrics::{Interval, MetricsMap};
+use ic_payments::TokenConfiguration;
 use ic_storage::IcStorage;
 
 use crate::error::{AuctionError, Result};
+use crate::events::AuctionEvent;
+use crate::fee_ratio::{FeeRatioConfig, FeeRatioInput, FeeRatioPolicy};
+use crate::format::{bid_commitment, AuctionFormat, SealedBid};
+use crate::history;
+use crate::top_up::IcpBiddingConfig;
 
 // Minimum bidding amount is required, for every update call costs cycles, and we want bidding
 // to add cycles rather then to decrease them. 1M is chosen as one ingress call costs 590K cycles.
@@ -25,6 +32,24 @@ pub struct AuctionInfo {
     pub fee_ratio: f64,
     pub first_transaction_id: TxId,
     pub last_transaction_id: TxId,
+    /// Fee ratio set by the auction for the period until the next auction.
+    pub next_fee_ratio: Option<f64>,
+}
+
+/// Metrics of a held auction.
+///
+/// The metrics are stored in a [`MetricsMap`], which keeps one entry per metrics interval, so if
+/// several auctions are held in one interval, only the last one is kept.
+#[derive(CandidType, Deserialize, IcStorage, Default, Clone, Debug, PartialEq)]
+pub struct AuctionMetrics {
+    pub auction_id: u64,
+    /// Total cycles bid in the auction.
+    pub cycles_collected: Cycles,
+    /// Number of the bidders of the auction.
+    pub bidders: u64,
+    pub tokens_distributed: Tokens128,
+    /// Fee ratio used in the period before the auction.
+    pub fee_ratio: f64,
 }
 
 /// Current information about upcoming auction and current cycle bids.
@@ -48,6 +73,177 @@ pub struct BiddingInfo {
 
     /// The amount of cycles the caller bid for the upcoming auction.
     pub caller_cycles: Cycles,
+
+    /// Fees to be distributed to the bidders in the upcoming auction.
+    pub reward_pool: RewardPool,
+
+    /// Fees collected for the canister owner which are not taken by the canister yet.
+    pub owner_fees: Tokens128,
+
+    pub auction_format: AuctionFormat,
+
+    /// Time the next auction is scheduled at, if the auction scheduler is running. See
+    /// [`crate::scheduler`].
+    pub next_scheduled_auction: Option<Timestamp>,
+}
+
+/// Fees accumulated since the last auction to be distributed between the bidders.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct RewardPool {
+    pub amount: Tokens128,
+    /// Id of the first transaction the fees were collected from.
+    pub first_transaction_id: Option<TxId>,
+    /// Id of the last transaction the fees were collected from.
+    pub last_transaction_id: Option<TxId>,
+}
+
+impl RewardPool {
+    pub fn add(&mut self, amount: Tokens128, tx_id: TxId) {
+        self.amount = self.amount.saturating_add(amount);
+        self.first_transaction_id.get_or_insert(tx_id);
+        self.last_transaction_id = Some(tx_id);
+    }
+}
+
+/// How the auction rewards are paid to the bidders. See [`crate::rewards`] for the details.
+#[derive(CandidType, Debug, Clone, Default, Deserialize)]
+pub enum RewardPayout {
+    /// Rewards are credited to the internal reward balances of the bidders.
+    #[default]
+    InternalBalance,
+    /// Rewards are transferred to the bidders' accounts of the given token.
+    Transfer(TokenConfiguration),
+    /// Rewards are kept on the internal reward balances until the bidders claim them with
+    /// `claim_rewards`.
+    Claim(ClaimConfig),
+}
+
+impl RewardPayout {
+    /// Returns the token the rewards are paid in, or `None` if the rewards are kept on the
+    /// internal reward balances.
+    pub fn token(&self) -> Option<&TokenConfiguration> {
+        match self {
+            Self::InternalBalance => None,
+            Self::Transfer(token) => Some(token),
+            Self::Claim(config) => Some(&config.token),
+        }
+    }
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct ClaimConfig {
+    /// Token the rewards are withdrawn to.
+    pub token: TokenConfiguration,
+    /// Period in nanoseconds after the last reward of a bidder, after which the unclaimed rewards
+    /// of the bidder expire and are rolled over to the reward pool of the next auction. `None` if
+    /// the rewards never expire.
+    pub expiry_period: Option<Timestamp>,
+}
+
+/// Reward transfer of a bidder which failed and is not retried successfully yet.
+///
+/// If the transfer was rejected, the reward stays on the internal reward balance of the bidder. If
+/// the outcome of the transfer is unknown because of an IC error, the transfer is kept in the
+/// recovery list of the [`RewardsTerminal`](crate::rewards::RewardsTerminal) instead. Both are
+/// completed by [`rewards::retry_payouts`](crate::rewards::retry_payouts).
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct FailedPayout {
+    pub bidder: Principal,
+    pub amount: Tokens128,
+    pub error: String,
+}
+
+/// Rewards of a bidder which are not claimed yet.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq)]
+pub struct UnclaimedRewards {
+    pub amount: Tokens128,
+    /// Time the rewards expire at, if the claimed rewards expire.
+    pub expires_at: Option<Timestamp>,
+}
+
+/// Returns the `ratio` part of the `amount`, rounded down. The ratio is clamped to `[0.0, 1.0]`.
+fn fee_share(amount: Tokens128, ratio: f64) -> Tokens128 {
+    const PRECISION: u64 = 1_000_000_000;
+
+    let ratio = (ratio.clamp(0.0, 1.0) * PRECISION as f64).round() as u64;
+    ((amount * ratio) / PRECISION)
+        .and_then(|share| share.to_tokens128())
+        .unwrap_or_default()
+}
+
+/// Splits the `pool` between the bidders in proportion to their cycles.
+///
+/// The rounding dust is given out one token unit at a time to the largest bidders, with ties
+/// broken by the principal order, so the whole pool is distributed and the result doesn't depend
+/// on the order of the bids. Bidders with zero reward are omitted.
+pub fn split_rewards(
+    pool: Tokens128,
+    bids: &HashMap<Principal, Cycles>,
+) -> Vec<(Principal, Tokens128)> {
+    let total_cycles: u128 = bids.values().map(|cycles| *cycles as u128).sum();
+    if total_cycles == 0 {
+        return vec![];
+    }
+
+    let mut bidders: Vec<_> = bids.iter().map(|(p, c)| (*p, *c)).collect();
+    bidders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut rewards: Vec<_> = bidders
+        .into_iter()
+        .map(|(bidder, cycles)| {
+            let reward = ((pool * cycles) / total_cycles)
+                .and_then(|reward| reward.to_tokens128())
+                .unwrap_or_default();
+            (bidder, reward)
+        })
+        .collect();
+
+    let distributed: u128 = rewards.iter().map(|(_, reward)| reward.amount).sum();
+    let dust = pool.amount - distributed;
+    for (_, reward) in rewards.iter_mut().take(dust as usize) {
+        reward.amount += 1;
+    }
+
+    rewards.retain(|(_, reward)| !reward.is_zero());
+    rewards
+}
+
+/// Splits the `pool` between the bidders in proportion to their cycles, so that no bidder gets
+/// more than `max_share` of the pool. The excess of the capped bidders is split between the other
+/// bidders. Returns the rewards and the part of the pool which is not distributed because all the
+/// bidders reached the cap.
+pub fn split_capped_rewards(
+    pool: Tokens128,
+    bids: &HashMap<Principal, Cycles>,
+    max_share: f64,
+) -> (Vec<(Principal, Tokens128)>, Tokens128) {
+    let cap = fee_share(pool, max_share);
+    let mut uncapped = bids.clone();
+    let mut capped = vec![];
+    let mut remaining = pool;
+
+    loop {
+        let rewards = split_rewards(remaining, &uncapped);
+        let over_cap: Vec<_> = rewards
+            .iter()
+            .filter(|(_, reward)| *reward > cap)
+            .map(|(bidder, _)| *bidder)
+            .collect();
+
+        if over_cap.is_empty() {
+            let distributed = rewards.iter().fold(Tokens128::ZERO, |sum, (_, reward)| {
+                sum.saturating_add(*reward)
+            });
+            capped.extend(rewards);
+            return (capped, (remaining - distributed).unwrap_or_default());
+        }
+
+        for bidder in over_cap {
+            uncapped.remove(&bidder);
+            capped.push((bidder, cap));
+            remaining = (remaining - cap).unwrap_or_default();
+        }
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -61,13 +257,20 @@ pub struct BiddingState {
     pub auction_period: Timestamp,
     pub cycles_since_auction: Cycles,
     pub bids: HashMap<Principal, Cycles>,
+    /// Committed bids of a sealed-bid auction, which are not revealed yet.
+    #[serde(default)]
+    pub sealed_bids: HashMap<Principal, SealedBid>,
 }
 
 impl BiddingState {
     pub fn is_auction_due(&self) -> bool {
         let curr_time = ic::time();
-        let next_auction = self.last_auction + self.auction_period;
-        curr_time >= next_auction
+        curr_time >= self.next_auction_time()
+    }
+
+    /// Returns the earliest time the next auction can be started at.
+    pub fn next_auction_time(&self) -> Timestamp {
+        self.last_auction + self.auction_period
     }
 
     pub fn cooldown_secs_remaining(&self) -> u64 {
@@ -85,6 +288,7 @@ impl Default for BiddingState {
             auction_period: 10u64.pow(9) * 60 * 60 * 24, // 1 day
             cycles_since_auction: 0,
             bids: HashMap::new(),
+            sealed_bids: HashMap::new(),
         }
     }
 }
@@ -96,9 +300,49 @@ impl Default for BiddingState {
 #[derive(CandidType, Deserialize, IcStorage, Debug)]
 pub struct AuctionState {
     pub bidding_state: BiddingState,
-    pub history: Vec<AuctionInfo>,
+    /// Auction history stored in the heap by the previous versions. It is moved to the stable
+    /// [`history`] by [`migrate_history`](Self::migrate_history) on the first update call.
+    #[serde(default, rename = "history")]
+    legacy_history: Vec<AuctionInfo>,
     pub controller: Principal,
     min_cycles: Cycles,
+    #[serde(default)]
+    pub reward_pool: RewardPool,
+    #[serde(default)]
+    pub reward_payout: RewardPayout,
+    #[serde(default)]
+    reward_balances: HashMap<Principal, Tokens128>,
+    /// Time the unclaimed rewards of the bidders expire at, if the rewards are paid by claims.
+    #[serde(default)]
+    reward_expiry: HashMap<Principal, Timestamp>,
+    #[serde(default)]
+    owner_fees: Tokens128,
+    #[serde(default)]
+    pub fee_ratio_policy: FeeRatioConfig,
+    /// If `true`, the auctions are run by a timer instead of the update calls. See
+    /// [`crate::scheduler`].
+    #[serde(default)]
+    pub scheduler_enabled: bool,
+    /// If set, the users can bid with ICP. See [`crate::top_up`].
+    #[serde(default)]
+    pub icp_bidding: Option<IcpBiddingConfig>,
+    #[serde(default)]
+    pub auction_format: AuctionFormat,
+    /// Metrics of the held auctions. See [`AuctionMetrics`].
+    #[serde(default)]
+    pub metrics: MetricsMap<AuctionMetrics>,
+    /// Block heights of the ICP transfers to the CMC which are not converted to bids yet.
+    #[serde(default)]
+    pending_icp_bids: HashMap<Principal, Vec<BlockHeight>>,
+    /// Sealed bid deposit refunds which failed and are not retried successfully yet.
+    #[serde(default)]
+    pending_refunds: HashMap<Principal, Cycles>,
+    /// Reward transfers which failed and are not retried successfully yet, one per bidder.
+    #[serde(default)]
+    pub failed_payouts: Vec<FailedPayout>,
+    /// Events which are not delivered to the subscribers yet. See [`crate::events`].
+    #[serde(default)]
+    pub(crate) pending_events: Vec<AuctionEvent>,
 }
 
 impl Default for AuctionState {
@@ -106,8 +350,22 @@ impl Default for AuctionState {
         AuctionState {
             controller: Principal::anonymous(),
             bidding_state: BiddingState::default(),
-            history: Vec::new(),
+            legacy_history: Vec::new(),
             min_cycles: MIN_BIDDING_AMOUNT,
+            reward_pool: RewardPool::default(),
+            reward_payout: RewardPayout::default(),
+            reward_balances: HashMap::new(),
+            reward_expiry: HashMap::new(),
+            owner_fees: Tokens128::ZERO,
+            fee_ratio_policy: FeeRatioConfig::default(),
+            scheduler_enabled: false,
+            icp_bidding: None,
+            auction_format: AuctionFormat::default(),
+            metrics: MetricsMap::default(),
+            pending_icp_bids: HashMap::new(),
+            pending_refunds: HashMap::new(),
+            failed_payouts: Vec::new(),
+            pending_events: Vec::new(),
         }
     }
 }
@@ -120,8 +378,22 @@ impl AuctionState {
                 auction_period: auction_period.nanos(),
                 ..Default::default()
             },
-            history: Vec::new(),
+            legacy_history: Vec::new(),
             min_cycles: MIN_BIDDING_AMOUNT,
+            reward_pool: RewardPool::default(),
+            reward_payout: RewardPayout::default(),
+            reward_balances: HashMap::new(),
+            reward_expiry: HashMap::new(),
+            owner_fees: Tokens128::ZERO,
+            fee_ratio_policy: FeeRatioConfig::default(),
+            scheduler_enabled: false,
+            icp_bidding: None,
+            auction_format: AuctionFormat::default(),
+            metrics: MetricsMap::default(),
+            pending_icp_bids: HashMap::new(),
+            pending_refunds: HashMap::new(),
+            failed_payouts: Vec::new(),
+            pending_events: Vec::new(),
         }
     }
 
@@ -136,44 +408,237 @@ impl AuctionState {
         }
     }
 
-    pub fn reset_bidding_state(&mut self) {
+    /// Resets the bidding state for the next auction with the fee ratio computed by the
+    /// configured fee ratio policy. Returns the new fee ratio.
+    pub fn reset_bidding_state(&mut self) -> f64 {
+        let policy = self.fee_ratio_policy.clone();
+        self.reset_bidding_state_with(&policy)
+    }
+
+    /// Same as [`reset_bidding_state`](Self::reset_bidding_state), but with the given fee ratio
+    /// policy.
+    pub fn reset_bidding_state_with(&mut self, policy: &dyn FeeRatioPolicy) -> f64 {
+        let input = FeeRatioInput {
+            cycles_balance: ic::balance(),
+            min_cycles: self.min_cycles,
+            cycles_collected: self.bidding_state.cycles_since_auction,
+        };
+        let fee_ratio = match policy.fee_ratio(&input) {
+            ratio if ratio.is_nan() => self.bidding_state.fee_ratio,
+            ratio => ratio.clamp(0.0, 1.0),
+        };
+
         self.bidding_state = BiddingState {
-            fee_ratio: self.get_fee_ratio(),
+            fee_ratio,
             auction_period: self.bidding_state.auction_period,
             last_auction: ic::time(),
             ..Default::default()
         };
+
+        fee_ratio
     }
 
-    fn get_fee_ratio(&self) -> f64 {
-        let min_cycles = self.min_cycles as f64;
-        let current_cycles = ic::balance() as f64;
-        if min_cycles == 0.0 {
-            // Setting min_cycles to zero effectively turns off the auction functionality, as all the
-            // fees will go to the owner.
-            0.0
-        } else if current_cycles <= min_cycles {
-            1.0
-        } else {
-            // If current cycles are 10 times larger, then min_cycles, half of the fees go to the auction.
-            // If current cycles are 1000 times larger, 17% of the fees go to the auction.
-            2f64.powf((min_cycles / current_cycles).log10())
+    pub fn bid_cycles(&mut self, bidder: Principal) -> Result<Cycles> {
+        self.check_open_bidding()?;
+        let amount = ic::msg_cycles_available();
+        if amount < MIN_BIDDING_AMOUNT {
+            return Err(AuctionError::BiddingTooSmall);
+        }
+
+        let amount_accepted = ic::msg_cycles_accept(amount);
+        Ok(self.record_bid(bidder, amount_accepted))
+    }
+
+    /// Returns an error if the auction format doesn't accept open bids.
+    pub fn check_open_bidding(&self) -> Result<()> {
+        match self.auction_format {
+            AuctionFormat::SealedBid { .. } => Err(AuctionError::SealedBidAuction),
+            _ => Ok(()),
         }
     }
 
-    pub fn bid_cycles(&mut self, bidder: Principal) -> Result<Cycles> {
+    /// Commits a sealed bid with the cycles attached to the call as the deposit. If the bidder
+    /// has already committed a bid, the commitment is replaced, and the deposit is added to the
+    /// previous one. Returns the accepted deposit.
+    pub fn commit_bid(&mut self, bidder: Principal, commitment: Vec<u8>) -> Result<Cycles> {
+        let reveal_period = self.reveal_period()?;
+        let reveal_start = self
+            .bidding_state
+            .next_auction_time()
+            .saturating_sub(reveal_period);
+        if ic::time() >= reveal_start {
+            return Err(AuctionError::CommitPhaseOver);
+        }
+
+        if commitment.len() != 32 {
+            return Err(AuctionError::InvalidCommitment);
+        }
+
         let amount = ic::msg_cycles_available();
         if amount < MIN_BIDDING_AMOUNT {
             return Err(AuctionError::BiddingTooSmall);
         }
 
         let amount_accepted = ic::msg_cycles_accept(amount);
-        self.bidding_state.cycles_since_auction += amount_accepted;
-        *self.bidding_state.bids.entry(bidder).or_insert(0) += amount_accepted;
+        let sealed_bid = self
+            .bidding_state
+            .sealed_bids
+            .entry(bidder)
+            .or_insert(SealedBid {
+                commitment: vec![],
+                deposit: 0,
+            });
+        sealed_bid.commitment = commitment;
+        sealed_bid.deposit += amount_accepted;
+        self.emit(AuctionEvent::BidCommitted {
+            bidder,
+            deposit: amount_accepted,
+        });
 
         Ok(amount_accepted)
     }
 
+    /// Reveals the sealed bid of the bidder, making the revealed cycles the bid of the bidder.
+    /// Returns the revealed cycles and the rest of the deposit, which must be refunded to the
+    /// bidder.
+    pub fn reveal_bid(
+        &mut self,
+        bidder: Principal,
+        cycles: Cycles,
+        salt: &[u8],
+    ) -> Result<(Cycles, Cycles)> {
+        let reveal_period = self.reveal_period()?;
+        let reveal_start = self
+            .bidding_state
+            .next_auction_time()
+            .saturating_sub(reveal_period);
+        if ic::time() < reveal_start {
+            return Err(AuctionError::RevealPhaseNotStarted);
+        }
+
+        let sealed_bid = self
+            .bidding_state
+            .sealed_bids
+            .get(&bidder)
+            .ok_or(AuctionError::BidNotCommitted)?;
+        if cycles < MIN_BIDDING_AMOUNT {
+            return Err(AuctionError::BiddingTooSmall);
+        }
+
+        if cycles > sealed_bid.deposit
+            || bid_commitment(bidder, cycles, salt) != sealed_bid.commitment
+        {
+            return Err(AuctionError::InvalidCommitment);
+        }
+
+        let refund = sealed_bid.deposit - cycles;
+        self.bidding_state.sealed_bids.remove(&bidder);
+        Ok((self.record_bid(bidder, cycles), refund))
+    }
+
+    fn reveal_period(&self) -> Result<Timestamp> {
+        match self.auction_format {
+            AuctionFormat::SealedBid { reveal_period } => Ok(reveal_period),
+            _ => Err(AuctionError::NotSealedBidAuction),
+        }
+    }
+
+    /// Starts the next auction period of a sealed-bid auction which is due, but has no revealed
+    /// bids. The bids which are not revealed are forfeited, like at a held auction, so the bidders
+    /// can commit the bids for the next auction. Otherwise the commit phase would never start
+    /// again, as the phases are computed from the time of the last auction.
+    pub fn skip_sealed_bid_auction(&mut self) {
+        if matches!(self.auction_format, AuctionFormat::SealedBid { .. })
+            && self.bidding_state.bids.is_empty()
+            && self.bidding_state.is_auction_due()
+        {
+            self.bidding_state.last_auction = ic::time();
+            self.bidding_state.sealed_bids.clear();
+        }
+    }
+
+    /// Checks that the bidders bid enough cycles for the auction to be held in the reserve
+    /// auction format. If they didn't, the auction is skipped: the bids and the reward pool are
+    /// kept for the next auction, which is held after the auction period.
+    pub fn check_reserve(&mut self) -> Result<()> {
+        let reserve = match self.auction_format {
+            AuctionFormat::Reserve { reserve_cycles } => reserve_cycles,
+            _ => return Ok(()),
+        };
+
+        let collected = self.bidding_state.cycles_since_auction;
+        if collected < reserve {
+            self.bidding_state.last_auction = ic::time();
+            self.emit(AuctionEvent::AuctionSkipped { collected, reserve });
+            return Err(AuctionError::ReserveNotMet { collected, reserve });
+        }
+
+        Ok(())
+    }
+
+    /// Adds the cycles received by the canister to the bid of the bidder. Returns the amount of
+    /// the cycles.
+    pub(crate) fn record_bid(&mut self, bidder: Principal, cycles: Cycles) -> Cycles {
+        self.bidding_state.cycles_since_auction += cycles;
+        *self.bidding_state.bids.entry(bidder).or_insert(0) += cycles;
+        self.emit(AuctionEvent::BidPlaced { bidder, cycles });
+
+        cycles
+    }
+
+    /// Returns the block heights of the ICP transfers of the bidder which are not converted to
+    /// bids yet.
+    pub fn pending_icp_bids(&self, bidder: Principal) -> Vec<BlockHeight> {
+        self.pending_icp_bids
+            .get(&bidder)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn add_pending_icp_bid(&mut self, bidder: Principal, block_height: BlockHeight) {
+        self.pending_icp_bids
+            .entry(bidder)
+            .or_default()
+            .push(block_height);
+    }
+
+    /// Returns the cycles of the failed deposit refunds of the bidder.
+    pub fn pending_refund(&self, bidder: Principal) -> Cycles {
+        self.pending_refunds.get(&bidder).copied().unwrap_or(0)
+    }
+
+    /// Adds the cycles of a failed deposit refund to the pending refund of the bidder.
+    pub(crate) fn add_pending_refund(&mut self, bidder: Principal, cycles: Cycles) {
+        let pending = self.pending_refunds.entry(bidder).or_insert(0);
+        *pending = pending.saturating_add(cycles);
+    }
+
+    /// Removes the pending refund of the bidder. Returns the cycles of the refund.
+    pub(crate) fn take_pending_refund(&mut self, bidder: Principal) -> Cycles {
+        self.pending_refunds.remove(&bidder).unwrap_or(0)
+    }
+
+    /// Removes the pending ICP bid. Returns `false` if the bid was not pending.
+    pub(crate) fn remove_pending_icp_bid(
+        &mut self,
+        bidder: Principal,
+        block_height: BlockHeight,
+    ) -> bool {
+        let pending = match self.pending_icp_bids.get_mut(&bidder) {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        let len = pending.len();
+        pending.retain(|block| *block != block_height);
+        let removed = pending.len() != len;
+        if pending.is_empty() {
+            self.pending_icp_bids.remove(&bidder);
+        }
+
+        removed
+    }
+
     pub fn bidding_info(&self) -> BiddingInfo {
         BiddingInfo {
             fee_ratio: self.bidding_state.fee_ratio,
@@ -186,19 +651,242 @@ impl AuctionState {
                 .get(&ic::caller())
                 .cloned()
                 .unwrap_or(0),
+            reward_pool: self.reward_pool.clone(),
+            owner_fees: self.owner_fees,
+            auction_format: self.auction_format.clone(),
+            next_scheduled_auction: crate::scheduler::scheduled_time(),
         }
     }
 
     pub fn auction_info(&self, id: usize) -> Result<AuctionInfo> {
-        self.history
-            .get(id)
-            .cloned()
+        history::get(id as u64)
+            .or_else(|| {
+                self.legacy_history
+                    .iter()
+                    .find(|info| info.auction_id == id)
+                    .cloned()
+            })
             .ok_or(AuctionError::AuctionNotFound)
     }
 
+    /// Records the metrics of the held auction.
+    pub fn record_auction_metrics(&mut self, info: &AuctionInfo, bidders: u64) {
+        self.metrics.insert(AuctionMetrics {
+            auction_id: info.auction_id as u64,
+            cycles_collected: info.cycles_collected,
+            bidders,
+            tokens_distributed: info.tokens_distributed,
+            fee_ratio: info.fee_ratio,
+        });
+    }
+
+    /// Moves the auction history kept in the heap by the previous versions to the stable memory.
+    ///
+    /// The method is called before every update call of the auction API, so the canister doesn't
+    /// need to call it. The auctions which are already in the stable history are skipped. If there
+    /// is a gap in the legacy history, the auctions after the gap are renumbered to follow the
+    /// last auction of the stable history, so the migration never stops the auctions.
+    pub fn migrate_history(&mut self) {
+        if self.legacy_history.is_empty() {
+            return;
+        }
+
+        let mut legacy = std::mem::take(&mut self.legacy_history);
+        legacy.sort_by_key(|info| info.auction_id);
+
+        for mut info in legacy {
+            let expected = history::len();
+            if (info.auction_id as u64) < expected {
+                continue;
+            }
+
+            info.auction_id = expected as usize;
+            history::push(info);
+        }
+    }
+
     pub fn min_cycles(&self) -> Cycles {
         self.min_cycles
     }
+
+    /// Records the fee collected by the canister in the transaction `tx_id`. The fee is split
+    /// between the reward pool of the next auction and the canister owner by the current fee
+    /// ratio. Returns the amount added to the reward pool.
+    ///
+    /// The transaction ids must be recorded in increasing order, as they define the transaction
+    /// range of the auction.
+    pub fn record_fee(&mut self, amount: Tokens128, tx_id: TxId) -> Tokens128 {
+        let auction_part = fee_share(amount, self.bidding_state.fee_ratio);
+        let owner_part = (amount - auction_part).unwrap_or_default();
+
+        self.reward_pool.add(auction_part, tx_id);
+        self.owner_fees = self.owner_fees.saturating_add(owner_part);
+
+        auction_part
+    }
+
+    /// Fees collected for the canister owner which are not taken yet.
+    pub fn owner_fees(&self) -> Tokens128 {
+        self.owner_fees
+    }
+
+    /// Takes the fees collected for the canister owner, so that the canister can transfer them to
+    /// the owner. The owner fees are kept over the auctions until they are taken.
+    pub fn take_owner_fees(&mut self) -> Tokens128 {
+        std::mem::take(&mut self.owner_fees)
+    }
+
+    /// Distributes the reward pool between the current bidders and credits the rewards to their
+    /// reward balances. Returns the information about the auction and the rewards of the bidders.
+    ///
+    /// The bids and the rewards of the bidders are recorded to the bidders' [`history`]. The
+    /// bidding state is not reset by this method.
+    pub fn disburse_rewards(&mut self) -> (AuctionInfo, Vec<(Principal, Tokens128)>) {
+        let expired = self.expire_rewards();
+        let mut pool = std::mem::take(&mut self.reward_pool);
+        pool.amount = pool.amount.saturating_add(expired);
+
+        let (rewards, undistributed) = match self.auction_format {
+            AuctionFormat::Capped { max_share } => {
+                split_capped_rewards(pool.amount, &self.bidding_state.bids, max_share)
+            }
+            _ => (
+                split_rewards(pool.amount, &self.bidding_state.bids),
+                Tokens128::ZERO,
+            ),
+        };
+        if !undistributed.is_zero() {
+            self.reward_pool = RewardPool {
+                amount: undistributed,
+                ..pool.clone()
+            };
+        }
+
+        let expires_at = match &self.reward_payout {
+            RewardPayout::Claim(config) => config
+                .expiry_period
+                .map(|period| ic::time().saturating_add(period)),
+            _ => None,
+        };
+        for (bidder, reward) in &rewards {
+            let balance = self.reward_balance(*bidder).saturating_add(*reward);
+            self.set_reward_balance(*bidder, balance);
+            if let Some(expires_at) = expires_at {
+                self.reward_expiry.insert(*bidder, expires_at);
+            }
+        }
+
+        let auction_id = history::len();
+        let rewards_by_bidder = rewards.iter().copied().collect::<HashMap<_, _>>();
+        let bids = self.bidding_state.bids.iter().map(|(bidder, cycles)| {
+            let reward = rewards_by_bidder.get(bidder).copied().unwrap_or_default();
+            (*bidder, *cycles, reward)
+        });
+        history::record_bids(auction_id, bids);
+
+        let info = AuctionInfo {
+            auction_id: auction_id as usize,
+            auction_time: ic::time(),
+            tokens_distributed: rewards.iter().fold(Tokens128::ZERO, |sum, (_, reward)| {
+                sum.saturating_add(*reward)
+            }),
+            cycles_collected: self.bidding_state.cycles_since_auction,
+            fee_ratio: self.bidding_state.fee_ratio,
+            first_transaction_id: pool.first_transaction_id.unwrap_or_default(),
+            last_transaction_id: pool.last_transaction_id.unwrap_or_default(),
+            next_fee_ratio: None,
+        };
+
+        (info, rewards)
+    }
+
+    /// Removes the expired unclaimed rewards from the reward balances, if the rewards are paid by
+    /// claims with expiry. Returns the total amount of the expired rewards.
+    fn expire_rewards(&mut self) -> Tokens128 {
+        if !matches!(
+            &self.reward_payout,
+            RewardPayout::Claim(ClaimConfig {
+                expiry_period: Some(_),
+                ..
+            })
+        ) {
+            return Tokens128::ZERO;
+        }
+
+        let now = ic::time();
+        let expired = self
+            .reward_expiry
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(bidder, _)| *bidder)
+            .collect::<Vec<_>>();
+
+        let mut total = Tokens128::ZERO;
+        for bidder in expired {
+            self.reward_expiry.remove(&bidder);
+            total = total.saturating_add(self.reward_balance(bidder));
+            self.set_reward_balance(bidder, Tokens128::ZERO);
+        }
+
+        if !total.is_zero() {
+            self.emit(AuctionEvent::RewardsExpired { amount: total });
+        }
+
+        total
+    }
+
+    /// Returns the rewards of the principal which are not claimed yet.
+    pub fn unclaimed_rewards(&self, principal: Principal) -> UnclaimedRewards {
+        let expires_at = match &self.reward_payout {
+            RewardPayout::Claim(ClaimConfig {
+                expiry_period: Some(_),
+                ..
+            }) => self.reward_expiry.get(&principal).copied(),
+            _ => None,
+        };
+
+        UnclaimedRewards {
+            amount: self.reward_balance(principal),
+            expires_at,
+        }
+    }
+
+    /// Returns the reward balance of the principal.
+    pub fn reward_balance(&self, principal: Principal) -> Tokens128 {
+        self.reward_balances
+            .get(&principal)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Records the failed reward transfers. A failure of a bidder who already has a failed payout
+    /// is merged into the recorded one.
+    pub(crate) fn add_failed_payouts(&mut self, failed: impl IntoIterator<Item = FailedPayout>) {
+        for payout in failed {
+            match self
+                .failed_payouts
+                .iter_mut()
+                .find(|recorded| recorded.bidder == payout.bidder)
+            {
+                Some(recorded) => {
+                    recorded.amount = recorded.amount.saturating_add(payout.amount);
+                    recorded.error = payout.error;
+                }
+                None => self.failed_payouts.push(payout),
+            }
+        }
+    }
+
+    /// Sets the reward balance of the principal. The expiry of the rewards is removed if the
+    /// balance is zero.
+    pub(crate) fn set_reward_balance(&mut self, principal: Principal, balance: Tokens128) {
+        if balance.is_zero() {
+            self.reward_balances.remove(&principal);
+            self.reward_expiry.remove(&principal);
+        } else {
+            self.reward_balances.insert(principal, balance);
+        }
+    }
 }
 
 /// A wrapper that helps us separate owner/caller methods with a
@@ -223,4 +911,349 @@ impl<'a> Authorized<Controller<'a>> {
     pub fn set_controller(&mut self, controller: Principal) {
         self.auth.state.controller = controller;
     }
+
+    /// Sets the reward payout. If the new payout is [`RewardPayout::Claim`] with an expiry period,
+    /// all the unclaimed rewards expire after the period from now, otherwise the unclaimed rewards
+    /// don't expire.
+    pub fn set_reward_payout(&mut self, payout: RewardPayout) {
+        let state = &mut self.auth.state;
+        state.reward_expiry.clear();
+        if let RewardPayout::Claim(ClaimConfig {
+            expiry_period: Some(period),
+            ..
+        }) = &payout
+        {
+            let expires_at = ic::time().saturating_add(*period);
+            state.reward_expiry = state
+                .reward_balances
+                .keys()
+                .map(|bidder| (*bidder, expires_at))
+                .collect();
+        }
+
+        state.reward_payout = payout;
+    }
+
+    pub fn set_fee_ratio_policy(&mut self, policy: FeeRatioConfig) -> Result<()> {
+        policy.validate()?;
+        self.auth.state.fee_ratio_policy = policy;
+        Ok(())
+    }
+
+    pub fn set_scheduler_enabled(&mut self, enabled: bool) {
+        self.auth.state.scheduler_enabled = enabled;
+    }
+
+    pub fn set_icp_bidding(&mut self, config: Option<IcpBiddingConfig>) {
+        self.auth.state.icp_bidding = config;
+    }
+
+    /// Sets the auction format. The sealed bids which are not revealed when the format is
+    /// changed are forfeited at the next auction.
+    pub fn set_auction_format(&mut self, format: AuctionFormat) {
+        self.auth.state.auction_format = format;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_icrc1::Account;
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn rewards_split_distributes_dust_deterministically() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let bids = HashMap::from([(alice, 1_000_000), (bob, 1_000_000), (carol, 2_000_000)]);
+
+        let rewards = split_rewards(Tokens128::from(11), &bids);
+        assert_eq!(
+            rewards,
+            vec![
+                (carol, Tokens128::from(6)),
+                (alice, Tokens128::from(3)),
+                (bob, Tokens128::from(2)),
+            ]
+        );
+
+        assert!(split_rewards(Tokens128::from(11), &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn capped_split_redistributes_excess() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let bids = HashMap::from([(alice, 8_000_000), (bob, 1_000_000), (carol, 1_000_000)]);
+
+        let (rewards, rest) = split_capped_rewards(Tokens128::from(100), &bids, 0.5);
+        assert_eq!(
+            rewards,
+            vec![
+                (alice, Tokens128::from(50)),
+                (bob, Tokens128::from(25)),
+                (carol, Tokens128::from(25)),
+            ]
+        );
+        assert_eq!(rest, Tokens128::ZERO);
+
+        let bids = HashMap::from([(alice, 1_000_000)]);
+        let (rewards, rest) = split_capped_rewards(Tokens128::from(100), &bids, 0.3);
+        assert_eq!(rewards, vec![(alice, Tokens128::from(30))]);
+        assert_eq!(rest, Tokens128::from(70));
+    }
+
+    #[test]
+    fn capped_rollover_keeps_transaction_range() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+
+        let mut state = AuctionState::default();
+        state.auction_format = AuctionFormat::Capped { max_share: 0.3 };
+        state.bidding_state.bids.insert(alice, 1_000_000);
+        state.reward_pool.add(Tokens128::from(100), 5);
+        state.disburse_rewards();
+        assert_eq!(
+            state.reward_pool,
+            RewardPool {
+                amount: Tokens128::from(70),
+                first_transaction_id: Some(5),
+                last_transaction_id: Some(5),
+            }
+        );
+
+        state.reward_pool.add(Tokens128::from(10), 9);
+        assert_eq!(state.reward_pool.first_transaction_id, Some(5));
+        assert_eq!(state.reward_pool.last_transaction_id, Some(9));
+    }
+
+    #[test]
+    fn revealed_bid_returns_rest_of_deposit() {
+        MockContext::new().with_msg_cycles(3_000_000).inject();
+        let alice = Principal::from_slice(&[1]);
+        let salt = [7u8; 16];
+
+        let mut state = AuctionState::default();
+        state.auction_format = AuctionFormat::SealedBid { reveal_period: 100 };
+        state.bidding_state.last_auction = ic::time();
+        state.bidding_state.auction_period = 1000;
+        let commitment = bid_commitment(alice, 2_000_000, &salt);
+        assert_eq!(state.commit_bid(alice, commitment), Ok(3_000_000));
+
+        state.bidding_state.auction_period = 50;
+        assert_eq!(
+            state.reveal_bid(alice, 2_000_000, &salt),
+            Ok((2_000_000, 1_000_000))
+        );
+        assert_eq!(state.bidding_state.bids[&alice], 2_000_000);
+    }
+
+    #[test]
+    fn sealed_bid_auction_without_reveals_starts_next_period() {
+        let context = MockContext::new().with_msg_cycles(3_000_000).inject();
+        let alice = Principal::from_slice(&[1]);
+        let salt = [7u8; 16];
+
+        let mut state = AuctionState::default();
+        state.auction_format = AuctionFormat::SealedBid { reveal_period: 100 };
+        state.bidding_state.last_auction = ic::time();
+        state.bidding_state.auction_period = 1000;
+        let commitment = bid_commitment(alice, 2_000_000, &salt);
+        assert_eq!(state.commit_bid(alice, commitment.clone()), Ok(3_000_000));
+
+        state.skip_sealed_bid_auction();
+        assert_eq!(state.bidding_state.sealed_bids.len(), 1);
+
+        context.add_time(1000);
+        assert_eq!(
+            state.commit_bid(alice, commitment),
+            Err(AuctionError::CommitPhaseOver)
+        );
+
+        state.skip_sealed_bid_auction();
+        assert_eq!(state.bidding_state.last_auction, ic::time());
+        assert!(state.bidding_state.sealed_bids.is_empty());
+        let reveal_start = state.bidding_state.next_auction_time() - 100;
+        assert!(ic::time() < reveal_start);
+    }
+
+    #[test]
+    fn failed_refunds_are_accumulated() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let mut state = AuctionState::default();
+        state.add_pending_refund(alice, 1_000_000);
+        state.add_pending_refund(alice, 2_000_000);
+        assert_eq!(state.pending_refund(alice), 3_000_000);
+
+        assert_eq!(state.take_pending_refund(alice), 3_000_000);
+        assert_eq!(state.pending_refund(alice), 0);
+    }
+
+    #[test]
+    fn fees_are_split_by_fee_ratio() {
+        MockContext::new().inject();
+        let mut state = AuctionState::default();
+        state.bidding_state.fee_ratio = 0.25;
+
+        assert_eq!(
+            state.record_fee(Tokens128::from(100), 7),
+            Tokens128::from(25)
+        );
+        assert_eq!(state.record_fee(Tokens128::from(3), 9), Tokens128::from(0));
+
+        assert_eq!(state.reward_pool.amount, Tokens128::from(25));
+        assert_eq!(state.reward_pool.first_transaction_id, Some(7));
+        assert_eq!(state.reward_pool.last_transaction_id, Some(9));
+        assert_eq!(state.owner_fees(), Tokens128::from(78));
+
+        state.disburse_rewards();
+        assert_eq!(state.owner_fees(), Tokens128::from(78));
+        assert_eq!(state.take_owner_fees(), Tokens128::from(78));
+        assert_eq!(state.owner_fees(), Tokens128::ZERO);
+    }
+
+    #[test]
+    fn auction_metrics_keep_last_auction_of_interval() {
+        MockContext::new().inject();
+        let auction = |auction_id, cycles_collected| AuctionInfo {
+            auction_id,
+            auction_time: ic::time(),
+            tokens_distributed: Tokens128::from(10),
+            cycles_collected,
+            fee_ratio: 0.5,
+            first_transaction_id: 0,
+            last_transaction_id: 0,
+            next_fee_ratio: None,
+        };
+
+        let mut state = AuctionState::default();
+        state.record_auction_metrics(&auction(0, 1_000_000), 1);
+        state.record_auction_metrics(&auction(1, 3_000_000), 2);
+
+        assert_eq!(
+            state.metrics.map.values().collect::<Vec<_>>(),
+            vec![&AuctionMetrics {
+                auction_id: 1,
+                cycles_collected: 3_000_000,
+                bidders: 2,
+                tokens_distributed: Tokens128::from(10),
+                fee_ratio: 0.5,
+            }]
+        );
+    }
+
+    #[test]
+    fn legacy_history_gaps_are_renumbered() {
+        MockContext::new().inject();
+        let auction = |auction_id| AuctionInfo {
+            auction_id,
+            auction_time: auction_id as u64,
+            tokens_distributed: Tokens128::ZERO,
+            cycles_collected: 0,
+            fee_ratio: 1.0,
+            first_transaction_id: 0,
+            last_transaction_id: 0,
+            next_fee_ratio: None,
+        };
+
+        history::push(auction(0));
+        let mut state = AuctionState::default();
+        state.legacy_history = vec![auction(4), auction(1), auction(0)];
+        state.migrate_history();
+        assert!(state.legacy_history.is_empty());
+        assert_eq!(history::len(), 3);
+        assert_eq!(state.auction_info(1), Ok(auction(1)));
+        assert_eq!(
+            state.auction_info(2),
+            Ok(AuctionInfo {
+                auction_id: 2,
+                ..auction(4)
+            })
+        );
+    }
+
+    #[test]
+    fn expired_claims_roll_over_to_next_auction() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let token = TokenConfiguration {
+            principal: Principal::management_canister(),
+            fee: Tokens128::ZERO,
+            minting_account: Account {
+                owner: Principal::management_canister().into(),
+                subaccount: None,
+            },
+        };
+
+        let mut state = AuctionState::default();
+        state.reward_payout = RewardPayout::Claim(ClaimConfig {
+            token,
+            expiry_period: Some(0),
+        });
+        state.bidding_state.bids.insert(alice, 1_000_000);
+        state.reward_pool.add(Tokens128::from(100), 1);
+        state.disburse_rewards();
+        assert_eq!(
+            state.unclaimed_rewards(alice),
+            UnclaimedRewards {
+                amount: Tokens128::from(100),
+                expires_at: Some(ic::time()),
+            }
+        );
+
+        state.bidding_state.bids = HashMap::from([(bob, 1_000_000)]);
+        state.reward_pool.add(Tokens128::from(50), 2);
+        let (info, _) = state.disburse_rewards();
+        assert_eq!(info.tokens_distributed, Tokens128::from(150));
+        assert_eq!(state.unclaimed_rewards(alice).amount, Tokens128::ZERO);
+        assert_eq!(state.unclaimed_rewards(bob).amount, Tokens128::from(150));
+    }
+
+    #[test]
+    fn reward_expiry_is_cleared_with_balance_and_payout_change() {
+        MockContext::new()
+            .with_caller(Principal::anonymous())
+            .inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let claim = |expiry_period| {
+            RewardPayout::Claim(ClaimConfig {
+                token: TokenConfiguration {
+                    principal: Principal::management_canister(),
+                    fee: Tokens128::ZERO,
+                    minting_account: Account {
+                        owner: Principal::management_canister().into(),
+                        subaccount: None,
+                    },
+                },
+                expiry_period,
+            })
+        };
+
+        let mut state = AuctionState::default();
+        state.set_reward_balance(alice, Tokens128::from(10));
+        state.set_reward_balance(bob, Tokens128::from(20));
+        state
+            .authorize_owner()
+            .unwrap()
+            .set_reward_payout(claim(Some(5)));
+        assert_eq!(
+            state.unclaimed_rewards(bob).expires_at,
+            Some(ic::time() + 5)
+        );
+
+        state.set_reward_balance(alice, Tokens128::ZERO);
+        assert!(!state.reward_expiry.contains_key(&alice));
+
+        state
+            .authorize_owner()
+            .unwrap()
+            .set_reward_payout(claim(None));
+        assert!(state.reward_expiry.is_empty());
+    }
 }
@@ -0,0 +1,26 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ic_canister::{Canister, PreUpdate};
+use ic_exports::ic_cdk::export::candid::Principal;
+
+use crate::api::Auction;
+use crate::state::AuctionState;
+
+/// Canister with the auction API and the state stored in the [`AuctionState`] storage.
+#[derive(Clone, Canister)]
+#[canister_no_upgrade_methods]
+pub struct TestAuction {
+    #[id]
+    principal: Principal,
+    #[state]
+    state: Rc<RefCell<AuctionState>>,
+}
+
+impl PreUpdate for TestAuction {}
+
+impl Auction for TestAuction {
+    fn auction_state(&self) -> Rc<RefCell<AuctionState>> {
+        self.state.clone()
+    }
+}
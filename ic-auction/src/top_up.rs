@@ -0,0 +1,195 @@
+//! Bidding with ICP.
+//!
+//! Canisters bid by attaching cycles to the `bid_cycles` call, but users can't attach cycles to
+//! their calls. Instead, a user transfers ICP to the user's [`deposit_account`] of the auction and
+//! calls `bid_icp`. The auction transfers the ICP to the cycles minting canister (CMC) and notifies
+//! it with `notify_top_up`, so that the cycles are minted to the auction canister. The minted
+//! cycles are recorded as the user's bid.
+//!
+//! The CMC can only mint cycles for ICP, so other tokens can't be used for bidding.
+//!
+//! The bid is rejected before the transfer if the cycles expected to be minted for the ICP at the
+//! current conversion rate are less than [`MIN_BIDDING_AMOUNT`].
+//!
+//! If the notification fails after the ICP is transferred, the transfer is kept as a pending bid
+//! of the user. The user can retry the notification with `retry_icp_bids`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ic_exports::ic_base_types::PrincipalId;
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_exports::ic_kit::ic;
+use ic_exports::ledger::{AccountIdentifier, Subaccount, DEFAULT_TRANSFER_FEE};
+use ic_exports::BlockHeight;
+use ic_helpers::cmc;
+
+use crate::error::{AuctionError, Result};
+use crate::state::{AuctionState, Cycles, MIN_BIDDING_AMOUNT};
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct IcpBiddingConfig {
+    /// Principal of the ICP ledger canister.
+    pub ledger: Principal,
+    /// Principal of the cycles minting canister.
+    pub cmc: Principal,
+}
+
+/// Result of retrying the pending ICP bids of a bidder.
+#[derive(CandidType, Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+pub struct IcpBidsRetry {
+    /// Cycles bid by the completed pending bids.
+    pub cycles: Cycles,
+    /// Block heights of the ICP transfers which are still pending, with the errors of the retries.
+    pub failed: Vec<(BlockHeight, AuctionError)>,
+}
+
+/// Returns the account of the auction canister the bidder must transfer ICP to before bidding.
+pub fn deposit_account(bidder: Principal) -> AccountIdentifier {
+    AccountIdentifier::new(PrincipalId(ic::id()), Some(deposit_subaccount(bidder)))
+}
+
+fn deposit_subaccount(bidder: Principal) -> Subaccount {
+    Subaccount::from(&PrincipalId(bidder))
+}
+
+/// Converts `amount` of ICP e8s from the deposit account of the bidder to cycles and records them
+/// as the bid of the bidder. The ledger fee is deducted from the amount. Returns the cycles bid.
+pub(crate) async fn bid_icp(
+    state: Rc<RefCell<AuctionState>>,
+    config: IcpBiddingConfig,
+    bidder: Principal,
+    amount: u64,
+) -> Result<Cycles> {
+    if amount <= DEFAULT_TRANSFER_FEE.get_e8s() {
+        return Err(AuctionError::BiddingTooSmall);
+    }
+
+    let rate = cmc::conversion_rate(config.cmc)
+        .await
+        .map_err(AuctionError::TopUpFailed)?;
+    let expected_cycles = cmc::icp_to_cycles(
+        amount - DEFAULT_TRANSFER_FEE.get_e8s(),
+        rate.xdr_permyriad_per_icp,
+    );
+    if expected_cycles < MIN_BIDDING_AMOUNT as u128 {
+        return Err(AuctionError::BiddingTooSmall);
+    }
+
+    let block_height = cmc::transfer_icp_to_cmc(
+        config.ledger,
+        config.cmc,
+        amount,
+        Some(deposit_subaccount(bidder)),
+        ic::id(),
+    )
+    .await
+    .map_err(AuctionError::LedgerError)?;
+    state.borrow_mut().add_pending_icp_bid(bidder, block_height);
+
+    complete_bid(&state, &config, bidder, block_height).await
+}
+
+/// Retries to mint cycles for all the pending ICP bids of the bidder. A failed retry doesn't stop
+/// the retries of the other bids.
+pub(crate) async fn retry_pending(
+    state: Rc<RefCell<AuctionState>>,
+    config: IcpBiddingConfig,
+    bidder: Principal,
+) -> IcpBidsRetry {
+    let pending = state.borrow().pending_icp_bids(bidder);
+    let mut result = IcpBidsRetry::default();
+    for block_height in pending {
+        match complete_bid(&state, &config, bidder, block_height).await {
+            Ok(cycles) => result.cycles = result.cycles.saturating_add(cycles),
+            Err(e) => result.failed.push((block_height, e)),
+        }
+    }
+
+    result
+}
+
+async fn complete_bid(
+    state: &Rc<RefCell<AuctionState>>,
+    config: &IcpBiddingConfig,
+    bidder: Principal,
+    block_height: BlockHeight,
+) -> Result<Cycles> {
+    let cycles = cmc::notify_top_up(config.cmc, block_height, ic::id())
+        .await
+        .map_err(AuctionError::TopUpFailed)?;
+    let cycles = Cycles::try_from(cycles).unwrap_or(Cycles::MAX);
+
+    // The CMC returns the same result for repeated notifications, so the pending bid must be
+    // recorded only once if the notifications are retried concurrently.
+    let mut state = state.borrow_mut();
+    if state.remove_pending_icp_bid(bidder, block_height) {
+        Ok(state.record_bid(bidder, cycles))
+    } else {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_canister::register_virtual_responder;
+    use ic_exports::cycles_minting_canister::{
+        IcpXdrConversionRate, IcpXdrConversionRateCertifiedResponse, NotifyError, NotifyTopUp,
+    };
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    fn config() -> IcpBiddingConfig {
+        IcpBiddingConfig {
+            ledger: Principal::from_slice(&[10]),
+            cmc: Principal::from_slice(&[11]),
+        }
+    }
+
+    #[tokio::test]
+    async fn bids_below_minimum_are_rejected_before_transfer() {
+        MockContext::new().inject();
+        register_virtual_responder(config().cmc, "get_icp_xdr_conversion_rate", |()| {
+            IcpXdrConversionRateCertifiedResponse {
+                data: IcpXdrConversionRate {
+                    xdr_permyriad_per_icp: 1,
+                    timestamp_seconds: 1663144200,
+                },
+                hash_tree: vec![],
+                certificate: vec![],
+            }
+        });
+
+        let state = Rc::new(RefCell::new(AuctionState::default()));
+        let bidder = Principal::from_slice(&[1]);
+        assert_eq!(
+            bid_icp(state, config(), bidder, 100_000).await,
+            Err(AuctionError::BiddingTooSmall)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_retries_keep_completed_bids() {
+        MockContext::new().inject();
+        register_virtual_responder(config().cmc, "notify_top_up", |(notify,): (NotifyTopUp,)| {
+            if notify.block_index == 1 {
+                Ok::<u128, NotifyError>(2_000_000)
+            } else {
+                Err(NotifyError::Processing)
+            }
+        });
+
+        let state = Rc::new(RefCell::new(AuctionState::default()));
+        let bidder = Principal::from_slice(&[1]);
+        state.borrow_mut().add_pending_icp_bid(bidder, 1);
+        state.borrow_mut().add_pending_icp_bid(bidder, 2);
+
+        let result = retry_pending(state.clone(), config(), bidder).await;
+        assert_eq!(result.cycles, 2_000_000);
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].0, 2);
+        assert_eq!(state.borrow().pending_icp_bids(bidder), vec![2]);
+        assert_eq!(state.borrow().bidding_state.bids[&bidder], 2_000_000);
+    }
+}
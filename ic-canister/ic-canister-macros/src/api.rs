@@ -244,6 +244,66 @@ pub(crate) fn api_method(
     TokenStream::from(expanded)
 }
 
+lazy_static! {
+    static ref INSPECT_MESSAGE_HOOKS: Mutex<Vec<String>> = Mutex::new(Default::default());
+}
+
+pub(crate) fn inspect_message(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ImplItemMethod);
+    let parameters =
+        serde_tokenstream::from_tokenstream::<ApiAttrParameters>(&attr.into()).unwrap();
+
+    let has_self = matches!(input.sig.inputs.first(), Some(FnArg::Receiver(_)));
+    if !has_self || input.sig.inputs.len() != 3 {
+        return syn::Error::new(
+            input.span(),
+            "inspect_message hook must have `&self`, method name and caller arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let method = &input.sig.ident;
+    let export_function = if parameters.is_trait {
+        INSPECT_MESSAGE_HOOKS
+            .lock()
+            .unwrap()
+            .push(method.to_string());
+        quote! {}
+    } else {
+        inspect_message_export(quote! {
+            Self::init_instance()
+                .#method(&method, caller)
+                .and_then(|_| ::ic_canister::run_inspect_message_hooks(&method, caller))
+        })
+    };
+
+    TokenStream::from(quote! {
+        #[allow(dead_code)]
+        #input
+
+        #export_function
+    })
+}
+
+/// Generates the `canister_inspect_message` export, which accepts the message if the `check`
+/// expression returns `Ok` for the `method` and `caller` of the message.
+fn inspect_message_export(check: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    quote! {
+        #[cfg(all(target_arch = "wasm32", feature = "export-api"))]
+        #[export_name = "canister_inspect_message"]
+        fn __canister_inspect_message() {
+            ::ic_exports::ic_cdk::setup();
+            let method = ::ic_exports::ic_cdk::api::call::method_name();
+            let caller = ::ic_exports::ic_cdk::api::caller();
+            if let Err(reason) = #check {
+                ::ic_exports::ic_cdk::trap(&reason);
+            }
+            ::ic_exports::ic_cdk::api::call::accept_message();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct StateGetter {
     pub method_name: String,
@@ -464,6 +524,37 @@ pub(crate) fn generate_exports(input: TokenStream) -> TokenStream {
         quote! {}
     };
 
+    let inspect_message_hooks = INSPECT_MESSAGE_HOOKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|hook| Ident::new(hook, Span::call_site()))
+        .collect::<Vec<_>>();
+    let inspect_message_impl = if inspect_message_hooks.is_empty() {
+        quote! {}
+    } else {
+        let export = inspect_message_export(quote! {
+            #struct_name ::init_instance().run_inspect_message_hooks(&method, caller)
+        });
+        quote! {
+            impl #struct_name {
+                /// Runs the `inspect_message` hooks of the trait, and then the hooks registered
+                /// with `ic_canister::register_inspect_message_hook`. Returns the error of the
+                /// first failing hook.
+                pub fn run_inspect_message_hooks(
+                    &self,
+                    method: &str,
+                    caller: ::ic_exports::ic_cdk::export::Principal,
+                ) -> ::std::result::Result<(), ::std::string::String> {
+                    #(self. #inspect_message_hooks(method, caller)?;)*
+                    ::ic_canister::run_inspect_message_hooks(method, caller)
+                }
+            }
+
+            #export
+        }
+    };
+
     let expanded = quote! {
         #[derive(::std::clone::Clone, ::std::fmt::Debug, Canister)]
         #[allow(non_camel_case_types)]
@@ -479,6 +570,8 @@ pub(crate) fn generate_exports(input: TokenStream) -> TokenStream {
         impl PreUpdate for #struct_name {}
 
         #(#methods)*
+
+        #inspect_message_impl
     };
     expanded.into()
 }
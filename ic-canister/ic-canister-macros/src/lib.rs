@@ -136,6 +136,35 @@ pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
     api::api_method("post_upgrade", attr, item, true, false)
 }
 
+/// Marks the canister method as an `inspect_message` hook.
+///
+/// The hook must take the method name and the caller of the ingress message, and return
+/// `Result<(), String>`:
+///
+/// ```ignore
+/// #[inspect_message(trait = true)]
+/// fn inspect_my_message(&self, method: &str, caller: Principal) -> Result<(), String> {
+///     Ok(())
+/// }
+/// ```
+///
+/// An ingress message is accepted only if all the hooks of the canister return `Ok`. Otherwise,
+/// the message is rejected with the error of the first failing hook.
+///
+/// Hooks defined in traits with `trait = true` are composed into one `canister_inspect_message`
+/// export by [`generate_exports!`], which also adds the `run_inspect_message_hooks` method running
+/// the hooks to the generated struct. A hook defined in the canister struct implementation
+/// generates the export itself.
+///
+/// Both exports run the hooks registered with `ic_canister::register_inspect_message_hook` after
+/// their own hooks. A canister exporting the API of a trait with hooks can't have a hook in its
+/// struct implementation, as there can be only one `canister_inspect_message` export, so it must
+/// register its hooks instead.
+#[proc_macro_attribute]
+pub fn inspect_message(attr: TokenStream, item: TokenStream) -> TokenStream {
+    api::inspect_message(attr, item)
+}
+
 /// Generates IDL (Candid) definition of the canister.
 ///
 /// ```ignore
@@ -559,6 +559,36 @@ pub trait PreUpdate {
     fn pre_update(&self, _method_name: &str, _method_type: MethodType) {}
 }
 
+/// Hook checking an ingress message with the given method name and caller before the message is
+/// accepted. See [`register_inspect_message_hook`].
+pub type InspectMessageHook = fn(&str, Principal) -> Result<(), String>;
+
+thread_local! {
+    static INSPECT_MESSAGE_HOOKS: RefCell<Vec<InspectMessageHook>> = RefCell::new(vec![]);
+}
+
+/// Registers an `inspect_message` hook of the canister, which is run after the hooks of the
+/// `canister_inspect_message` export generated by the [`inspect_message`] attribute.
+///
+/// A canister which exports the API of a canister trait with `inspect_message` hooks can't have its
+/// own `canister_inspect_message` export, so it must register its hooks with this function to
+/// compose them with the hooks of the trait. The hooks are stored in the heap memory, so they must
+/// be registered in both `init` and `post_upgrade` methods of the canister.
+pub fn register_inspect_message_hook(hook: InspectMessageHook) {
+    INSPECT_MESSAGE_HOOKS.with(|hooks| hooks.borrow_mut().push(hook));
+}
+
+/// Runs the hooks registered with [`register_inspect_message_hook`] in the order of registration.
+/// Returns the error of the first failing hook.
+pub fn run_inspect_message_hooks(method: &str, caller: Principal) -> Result<(), String> {
+    INSPECT_MESSAGE_HOOKS.with(|hooks| {
+        hooks
+            .borrow()
+            .iter()
+            .try_for_each(|hook| hook(method, caller))
+    })
+}
+
 /// Main trait for a testable canister. Do not implement this trait manually, use the derive macro.
 pub trait Canister: PreUpdate {
     /// Creates a new instance of the canister with the default state. Call this method to initialize
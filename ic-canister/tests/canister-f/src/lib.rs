@@ -0,0 +1,132 @@
+//! Canister composing the `inspect_message` hooks of a canister trait with the hooks registered by
+//! the canister itself.
+
+use ic_canister::{
+    generate_exports, generate_idl, init, inspect_message, post_upgrade, update, Canister, Idl,
+    PreUpdate,
+};
+use ic_exports::ic_cdk::export::candid::Principal;
+
+/// The only principal allowed to call the `owner_` methods.
+pub fn owner() -> Principal {
+    Principal::from_slice(&[1])
+}
+
+pub trait CanisterF: Canister {
+    #[inspect_message(trait = true)]
+    fn inspect_owner_methods(&self, method: &str, caller: Principal) -> Result<(), String> {
+        if method.starts_with("owner_") && caller != owner() {
+            return Err("only the owner can call this method".into());
+        }
+
+        Ok(())
+    }
+
+    #[inspect_message(trait = true)]
+    fn inspect_empty_method(&self, method: &str, _caller: Principal) -> Result<(), String> {
+        if method.is_empty() {
+            return Err("method name is empty".into());
+        }
+
+        Ok(())
+    }
+
+    #[update(trait = true)]
+    fn owner_reset(&self) {}
+
+    #[update(trait = true)]
+    fn ping(&self) {}
+
+    // Important: This function *must* be defined to be the
+    // last one in the trait because it depends on the order
+    // of expansion of update/query(trait = true) methods.
+    fn get_idl() -> Idl {
+        generate_idl!()
+    }
+}
+
+generate_exports!(CanisterF, CanisterFImpl);
+
+/// Canister exporting the API of [`CanisterF`], which adds its own `inspect_message` hook.
+#[derive(Clone, Canister)]
+#[canister_no_upgrade_methods]
+pub struct CanisterFHost {
+    #[id]
+    principal: Principal,
+}
+
+impl PreUpdate for CanisterFHost {}
+
+impl CanisterFHost {
+    #[init]
+    pub fn init(&self) {
+        register_hooks();
+    }
+
+    #[post_upgrade]
+    pub fn post_upgrade(&self) {
+        register_hooks();
+    }
+}
+
+/// Hook of the host canister, which is run after the hooks of the trait.
+pub fn reject_anonymous(_method: &str, caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        return Err("anonymous calls are not accepted".into());
+    }
+
+    Ok(())
+}
+
+fn register_hooks() {
+    ic_canister::register_inspect_message_hook(reject_anonymous);
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn trait_hooks_are_composed() {
+        MockContext::new().inject();
+        let canister = CanisterFImpl::init_instance();
+        let user = Principal::from_slice(&[2]);
+
+        assert_eq!(canister.run_inspect_message_hooks("ping", user), Ok(()));
+        assert_eq!(
+            canister.run_inspect_message_hooks("owner_reset", owner()),
+            Ok(())
+        );
+        assert_eq!(
+            canister.run_inspect_message_hooks("owner_reset", user),
+            Err("only the owner can call this method".into())
+        );
+        assert_eq!(
+            canister.run_inspect_message_hooks("", user),
+            Err("method name is empty".into())
+        );
+    }
+
+    #[test]
+    fn registered_hooks_run_after_trait_hooks() {
+        MockContext::new().inject();
+        let canister = CanisterFImpl::init_instance();
+        let anonymous = Principal::anonymous();
+        assert_eq!(
+            canister.run_inspect_message_hooks("ping", anonymous),
+            Ok(())
+        );
+
+        CanisterFHost::init_instance().init();
+        assert_eq!(
+            canister.run_inspect_message_hooks("ping", anonymous),
+            Err("anonymous calls are not accepted".into())
+        );
+        assert_eq!(
+            canister.run_inspect_message_hooks("owner_reset", anonymous),
+            Err("only the owner can call this method".into())
+        );
+    }
+}
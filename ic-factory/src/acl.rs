@@ -0,0 +1,285 @@
+//! Role based access control for the factory methods.
+//!
+//! The factory controller always has the [`Role::Owner`] role. Owners can grant and revoke roles
+//! to other principals, and all the role changes are recorded in the role changes log. Some roles
+//! imply others, see [`Role::includes`].
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableLog, Storable};
+
+use crate::error::FactoryError;
+use crate::state::PrincipalKey;
+
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ROLE_CHANGES_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ROLE_CHANGES_DATA_MEMORY_ID: MemoryId = MemoryId::new(7);
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Can call any factory method, including granting and revoking roles.
+    Owner,
+    /// Can set the canister wasm and upgrade the factory canisters.
+    Upgrader,
+    /// Can manage the factory canisters: upgrade and drop them, change their settings and top them
+    /// up.
+    Operator,
+    /// Can change the canister creation fees.
+    Billing,
+    /// Can read the factory logs.
+    Viewer,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Owner,
+        Role::Upgrader,
+        Role::Operator,
+        Role::Billing,
+        Role::Viewer,
+    ];
+
+    /// Returns `true` if a principal with this role can act as a principal with the `other` role.
+    /// Owners can act in every role, and operators can also act as upgraders.
+    pub fn includes(self, other: Role) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Operator => matches!(other, Role::Operator | Role::Upgrader),
+            _ => self == other,
+        }
+    }
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RoleChangeKind {
+    Granted,
+    Revoked,
+}
+
+/// Record in the role changes log.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct RoleChange {
+    /// Time of the change in nanoseconds.
+    pub timestamp: u64,
+    /// The principal that made the change.
+    pub changed_by: Principal,
+    /// The principal whose role was changed.
+    pub principal: Principal,
+    pub role: Role,
+    pub kind: RoleChangeKind,
+}
+
+impl Storable for RoleChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize role change")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize role change")
+    }
+}
+
+/// Set of roles stored as a bit mask.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct RoleSet(u8);
+
+impl RoleSet {
+    fn contains(self, role: Role) -> bool {
+        self.0 & role.mask() != 0
+    }
+
+    fn roles(self) -> Vec<Role> {
+        Role::ALL
+            .into_iter()
+            .filter(|role| self.contains(*role))
+            .collect()
+    }
+}
+
+impl Storable for RoleSet {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        vec![self.0].into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes[0])
+    }
+}
+
+impl BoundedStorable for RoleSet {
+    const MAX_SIZE: u32 = 1;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Returns `true` if the role was explicitly granted to the principal.
+pub fn has_role(principal: Principal, role: Role) -> bool {
+    roles_of(principal).contains(role)
+}
+
+/// Returns the roles explicitly granted to the principal.
+pub fn roles(principal: Principal) -> Vec<Role> {
+    roles_of(principal).roles()
+}
+
+/// Returns all the principals with explicitly granted roles.
+pub fn all_roles() -> Vec<(Principal, Vec<Role>)> {
+    ROLES_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(principal, roles)| (principal.0, roles.roles()))
+            .collect()
+    })
+}
+
+/// Returns up to `limit` role changes starting from the `offset` record.
+pub fn role_changes(offset: u64, limit: u64) -> Vec<RoleChange> {
+    ROLE_CHANGES_LOG.with(|log| {
+        let log = log.borrow();
+        (offset..log.len().min(offset.saturating_add(limit)))
+            .filter_map(|idx| log.get(idx))
+            .collect()
+    })
+}
+
+/// Grants the role to the principal and records the change to the log.
+///
+/// # Errors
+///
+/// Returns `FactoryError::GenericError` if the principal already has the role.
+pub(crate) fn grant(
+    changed_by: Principal,
+    principal: Principal,
+    role: Role,
+) -> Result<(), FactoryError> {
+    let roles = roles_of(principal);
+    if roles.contains(role) {
+        return Err(FactoryError::GenericError(format!(
+            "principal {principal} already has the {role:?} role"
+        )));
+    }
+
+    set_roles(principal, RoleSet(roles.0 | role.mask()));
+    record_change(changed_by, principal, role, RoleChangeKind::Granted);
+
+    Ok(())
+}
+
+/// Revokes the role from the principal and records the change to the log.
+///
+/// # Errors
+///
+/// Returns `FactoryError::RoleNotGranted` if the principal doesn't have the role.
+pub(crate) fn revoke(
+    changed_by: Principal,
+    principal: Principal,
+    role: Role,
+) -> Result<(), FactoryError> {
+    let roles = roles_of(principal);
+    if !roles.contains(role) {
+        return Err(FactoryError::RoleNotGranted);
+    }
+
+    set_roles(principal, RoleSet(roles.0 & !role.mask()));
+    record_change(changed_by, principal, role, RoleChangeKind::Revoked);
+
+    Ok(())
+}
+
+pub(crate) fn clear() {
+    ROLES_MAP.with(|map| map.borrow_mut().clear());
+    ROLE_CHANGES_LOG.with(|log| log.borrow_mut().clear());
+}
+
+fn roles_of(principal: Principal) -> RoleSet {
+    ROLES_MAP
+        .with(|map| map.borrow().get(&PrincipalKey(principal)))
+        .unwrap_or_default()
+}
+
+fn set_roles(principal: Principal, roles: RoleSet) {
+    ROLES_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        if roles == RoleSet::default() {
+            map.remove(&PrincipalKey(principal));
+        } else {
+            map.insert(PrincipalKey(principal), roles);
+        }
+    });
+}
+
+fn record_change(changed_by: Principal, principal: Principal, role: Role, kind: RoleChangeKind) {
+    let change = RoleChange {
+        timestamp: ic::time(),
+        changed_by,
+        principal,
+        role,
+        kind,
+    };
+
+    ROLE_CHANGES_LOG.with(|log| {
+        log.borrow_mut()
+            .append(change)
+            .expect("failed to append role change to stable memory")
+    });
+}
+
+thread_local! {
+    static ROLES_MAP: RefCell<StableBTreeMap<PrincipalKey, RoleSet>> =
+        RefCell::new(StableBTreeMap::new(ROLES_MEMORY_ID));
+
+    static ROLE_CHANGES_LOG: RefCell<StableLog<RoleChange>> = {
+        RefCell::new(StableLog::new(ROLE_CHANGES_INDEX_MEMORY_ID, ROLE_CHANGES_DATA_MEMORY_ID)
+            .expect("failed to initialize role changes log"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn grant_and_revoke_roles() {
+        MockContext::new().inject();
+        let owner = Principal::from_slice(&[1]);
+        let user = Principal::from_slice(&[2]);
+
+        grant(owner, user, Role::Upgrader).unwrap();
+        grant(owner, user, Role::Billing).unwrap();
+        assert!(grant(owner, user, Role::Billing).is_err());
+
+        assert!(has_role(user, Role::Upgrader));
+        assert!(!has_role(user, Role::Operator));
+        assert!(Role::Operator.includes(Role::Upgrader));
+        assert!(!Role::Operator.includes(Role::Billing));
+        assert!(Role::Owner.includes(Role::Billing));
+        assert_eq!(roles(user), vec![Role::Upgrader, Role::Billing]);
+
+        revoke(owner, user, Role::Upgrader).unwrap();
+        assert!(!has_role(user, Role::Upgrader));
+        assert!(matches!(
+            revoke(owner, user, Role::Upgrader),
+            Err(FactoryError::RoleNotGranted)
+        ));
+
+        revoke(owner, user, Role::Billing).unwrap();
+        assert!(all_roles().is_empty());
+
+        let changes = role_changes(0, 10);
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[0].kind, RoleChangeKind::Granted);
+        assert_eq!(changes[3].role, Role::Billing);
+        assert_eq!(changes[3].kind, RoleChangeKind::Revoked);
+        assert_eq!(role_changes(3, 10).len(), 1);
+    }
+}
@@ -8,15 +8,35 @@ use ic_canister::{
 };
 use ic_exports::candid::{CandidType, Nat, Principal};
 use ic_exports::ic_base_types::PrincipalId;
-use ic_exports::ic_cdk::export::candid::utils::ArgumentEncoder;
+use ic_exports::ic_cdk::export::candid::utils::{encode_args, ArgumentEncoder};
+use ic_exports::ic_icrc1::Account;
 use ic_exports::ic_kit::ic;
 use ic_exports::ledger::{AccountIdentifier, Subaccount, DEFAULT_TRANSFER_FEE};
 use ic_helpers::ledger::LedgerPrincipalExt;
-use ic_helpers::management::ManagementPrincipalExt;
+use ic_helpers::management::{CanisterSettings, ManagementPrincipalExt};
+use ic_helpers::tokens::Tokens128;
+use ic_payments::icrc1::get_icrc1_configuration;
+use ic_payments::{get_deposit_interim_account, PaymentError};
 use ic_storage::IcStorage;
 
 use super::error::FactoryError;
-use crate::{state, CmcConfig, INITIAL_CANISTER_CYCLES};
+use crate::acl::{self, Role, RoleChange};
+use crate::audit::{self, AuditFilter, AuditPage, Operation};
+use crate::backup::{self, BackupInfo, ExportStatus};
+use crate::controllers::{self, ControllerReport, ReconcileConfig};
+use crate::creation::{self, CreationRecord, CreationStatus};
+use crate::dropping::{self, DropOptions, DropRecord, DropStep};
+use crate::monitor::{self, CanisterSnapshot, MonitorConfig};
+use crate::operations::{OperationKind, OperationRecord};
+use crate::payments::{self, TokenBalances, TokenPrice};
+use crate::registry::{
+    self, AdoptedCanister, CanisterListPage, CanisterListRequest, CanisterMetadata,
+};
+use crate::v1::{self, V1Migration};
+use crate::{
+    state, top_up, Billing, CanisterHash, CanisterModule, CmcConfig, CreationQuote, Operator,
+    PricingTier, Upgrader, Viewer,
+};
 
 pub trait FactoryCanister: Canister + Sized + PreUpdate {
     fn cmc_config(&self) -> Rc<RefCell<CmcConfig>> {
@@ -37,9 +57,20 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     /// This method can only be called by the factory owner.
     #[update(trait = true)]
     fn set_cmc_principal(&mut self, cmc_principal: Principal) -> Result<(), FactoryError> {
-        state::factory_state().check_is_owner()?;
-        self.cmc_config().borrow_mut().cmc_principal = Some(cmc_principal);
-        Ok(())
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_is_owner_internal(caller)
+            .map(|_| self.cmc_config().borrow_mut().cmc_principal = Some(cmc_principal));
+
+        audit::record(
+            caller,
+            Operation::SetCmcPrincipal,
+            vec![],
+            None,
+            Some(cmc_principal.to_text()),
+            &result,
+        );
+        result
     }
 
     /// Returns the checksum of a wasm module in hex representation.
@@ -72,51 +103,508 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
         <Principal as ManagementPrincipalExt>::accept_cycles()
     }
 
-    fn set_canister_code(&self, wasm: Vec<u8>) -> Result<u32, FactoryError> {
+    /// Returns the configuration of the canisters cycles monitor.
+    #[query(trait = true)]
+    fn get_monitor_config(&self) -> MonitorConfig {
+        monitor::monitor_config()
+    }
+
+    /// Sets the configuration of the canisters cycles monitor and restarts the monitor timer.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn set_monitor_config(&self, config: MonitorConfig) -> Result<(), FactoryError> {
         state::factory_state()
-            .check_is_owner()?
-            .set_canister_wasm(wasm)
+            .check_role::<Operator>()?
+            .set_monitor_config(config)
+    }
+
+    /// Returns the latest status snapshot of the canister made by the cycles monitor.
+    #[query(trait = true)]
+    fn get_canister_snapshot(&self, canister_id: Principal) -> Option<CanisterSnapshot> {
+        monitor::snapshot(canister_id)
+    }
+
+    /// Returns the latest status snapshots of all the canisters checked by the cycles monitor.
+    #[query(trait = true)]
+    fn get_canister_snapshots(&self) -> Vec<(Principal, CanisterSnapshot)> {
+        monitor::snapshots()
+    }
+
+    /// Runs the cycles monitor for the next batch of canisters without waiting for the timer.
+    /// Returns the snapshots of the checked canisters.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn run_cycles_monitor(
+        &self,
+    ) -> AsyncReturn<Result<Vec<(Principal, CanisterSnapshot)>, FactoryError>> {
+        let cmc = self.cmc_principal();
+        Box::pin(async move {
+            state::factory_state().check_role::<Operator>()?;
+            Ok(monitor::run_monitor_batch(cmc).await)
+        })
+    }
+
+    /// Returns the last controllers reports of the factory canisters. If `flagged_only` is `true`,
+    /// returns only the canisters with missing controllers. See [`crate::controllers`] for the
+    /// details.
+    #[query(trait = true)]
+    fn get_controller_reports(&self, flagged_only: bool) -> Vec<(Principal, ControllerReport)> {
+        controllers::reports(flagged_only)
+    }
+
+    /// Returns the configuration of the periodic controllers reconciliation.
+    #[query(trait = true)]
+    fn get_reconcile_config(&self) -> ReconcileConfig {
+        controllers::reconcile_config()
+    }
+
+    /// Sets the configuration of the periodic controllers reconciliation and restarts its timer.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn set_reconcile_config(&self, config: ReconcileConfig) -> Result<(), FactoryError> {
+        state::factory_state()
+            .check_role::<Operator>()?
+            .set_reconcile_config(config)
+    }
+
+    /// Checks the controllers of the given factory canisters, or of all the factory canisters if
+    /// `canisters` is `None`. If `restore` is `true`, the missing intended controllers are added
+    /// back to the canisters. Returns the reports of the checked canisters.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn reconcile_controllers(
+        &self,
+        canisters: Option<Vec<Principal>>,
+        restore: bool,
+    ) -> AsyncReturn<Result<Vec<(Principal, ControllerReport)>, FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                let mut state = state::factory_state();
+                state.check_role_internal::<Operator>(caller)?;
+                let canisters = canisters.unwrap_or_else(|| state.canister_list());
+                if !canisters.iter().all(|id| state.is_registered(*id)) {
+                    return Err(FactoryError::NotFound);
+                }
+
+                // The canister list can be too large for the operation record, so it's not stored.
+                let _state_lock = match restore {
+                    true => Some(state.lock_for(OperationRecord::new(
+                        OperationKind::UpdateCanisterSettings,
+                        caller,
+                        vec![],
+                    ))?),
+                    false => None,
+                };
+
+                Ok(controllers::reconcile(canisters, restore).await)
+            }
+            .await;
+
+            if restore {
+                let restored = result
+                    .as_ref()
+                    .map(|reports| controllers::restored(reports))
+                    .unwrap_or_default();
+                audit::record(
+                    caller,
+                    Operation::ReconcileControllers,
+                    restored,
+                    None,
+                    None,
+                    &result,
+                );
+            }
+            result
+        })
+    }
+
+    fn set_canister_code(&self, wasm: Vec<u8>) -> Result<u32, FactoryError> {
+        let caller = ic::caller();
+        let mut state = state::factory_state();
+        let result = state
+            .check_role_internal::<Upgrader>(caller)
+            .and_then(|mut upgrader| upgrader.set_canister_wasm(wasm));
+
+        let module = state.module().ok();
+        audit::record(
+            caller,
+            Operation::SetCanisterWasm,
+            vec![],
+            module.as_ref().map(CanisterModule::hash),
+            result
+                .as_ref()
+                .ok()
+                .map(|version| format!("version: {version}")),
+            &result,
+        );
+        result
     }
 
-    #[allow(unused_variables)]
-    #[allow(clippy::await_holding_refcell_ref)]
     fn create_canister<'a, T: ArgumentEncoder + Send + 'a>(
         &'a self,
         init_args: T,
         controller: Option<Principal>,
         caller: Option<Principal>,
+    ) -> AsyncReturn<'a, Result<Principal, FactoryError>> {
+        let settings = CanisterSettings {
+            controllers: controller.map(|p| vec![p]),
+            ..Default::default()
+        };
+
+        self.create_canister_with_settings(init_args, settings, caller)
+    }
+
+    /// Creates a new canister with the given canister settings. The factory is always added to the
+    /// controllers of the new canister.
+    fn create_canister_with_settings<'a, T: ArgumentEncoder + Send + 'a>(
+        &'a self,
+        init_args: T,
+        settings: CanisterSettings,
+        caller: Option<Principal>,
+    ) -> AsyncReturn<'a, Result<Principal, FactoryError>> {
+        self.create_canister_with_tier(init_args, settings, None, caller)
+    }
+
+    /// Creates a new canister with the given canister settings and the cycles and fees of the
+    /// given pricing tier. If `tier` is `None`, the default factory pricing is used.
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn create_canister_with_tier<'a, T: ArgumentEncoder + Send + 'a>(
+        &'a self,
+        init_args: T,
+        settings: CanisterSettings,
+        tier: Option<String>,
+        caller: Option<Principal>,
     ) -> AsyncReturn<'a, Result<Principal, FactoryError>> {
         Box::pin(async move {
-            let state_lock = state::factory_state().lock()?;
+            let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            let module = state::factory_state().module().ok();
+            let owner = settings_owner(&settings);
 
-            let cycles_minted = {
-                #[cfg(target_arch = "wasm32")]
-                {
-                    let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            let mut paid = false;
+            let result = async {
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::CreateCanister,
+                    caller,
+                    vec![],
+                ))?;
+                let pricing = state::factory_state().pricing(tier.as_deref())?;
 
+                state_lock.set_step("paying".into());
+                let cycles_minted = {
+                    #[cfg(target_arch = "wasm32")]
+                    {
+                        state::factory_state()
+                            .consume_creation_fee(caller, self.cmc_principal(), pricing)
+                            .await?
+                    }
+
+                    #[cfg(not(target_arch = "wasm32"))]
+                    {
+                        0
+                    }
+                };
+
+                paid = true;
+                let cycles_to_canister = cycles_minted.min(pricing.initial_cycles);
+
+                state_lock.set_step("creating".into());
+                let principal = state::factory_state()
+                    .create_canister_with_settings(
+                        init_args,
+                        cycles_to_canister,
+                        &state_lock,
+                        settings,
+                    )?
+                    .await
+                    .map_err(|e| FactoryError::CanisterCreateFailed(e.1))?;
+
+                state::factory_state()
+                    .register_created(principal, caller, owner, &state_lock)
+                    .expect("correct state lock");
+                state::factory_state().accept_creation_cycles(pricing);
+
+                Ok::<_, FactoryError>(principal)
+            }
+            .await;
+
+            // Failures before the payment don't change anything, and anyone can cause them, so
+            // they are not recorded.
+            if paid {
+                audit::record(
+                    caller,
+                    Operation::CreateCanister,
+                    result.iter().copied().collect(),
+                    module.as_ref().map(CanisterModule::hash),
+                    tier.map(|tier| format!("pricing tier {tier}")),
+                    &result,
+                );
+            }
+            result
+        })
+    }
+
+    /// Creates a new canister in the same way as [`create_canister_with_tier`], but idempotently.
+    ///
+    /// Repeated calls with the same `request_id` from the same caller don't create new canisters
+    /// and don't charge the caller again. If the canister was created by a previous call, its
+    /// principal is returned. If a previous call was interrupted before the canister was created
+    /// and installed, the creation is continued from the step it was interrupted at.
+    ///
+    /// The candid encoded `init_args` must not be larger than
+    /// [`creation::MAX_INIT_ARGS_SIZE`], as they are stored to resume the creation.
+    fn create_canister_idempotent<'a, T: ArgumentEncoder + Send + 'a>(
+        &'a self,
+        init_args: T,
+        settings: CanisterSettings,
+        tier: Option<String>,
+        request_id: u64,
+        caller: Option<Principal>,
+    ) -> AsyncReturn<'a, Result<Principal, FactoryError>> {
+        Box::pin(async move {
+            let init_args =
+                encode_args(init_args).map_err(|e| FactoryError::GenericError(e.to_string()))?;
+            self.create_canister_idempotent_encoded(init_args, settings, tier, request_id, caller)
+                .await
+        })
+    }
+
+    /// Same as [`create_canister_idempotent`], but with already candid encoded `init_args`. Used
+    /// to resume an interrupted creation with the arguments stored in its creation record.
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn create_canister_idempotent_encoded(
+        &self,
+        init_args: Vec<u8>,
+        settings: CanisterSettings,
+        tier: Option<String>,
+        request_id: u64,
+        caller: Option<Principal>,
+    ) -> AsyncReturn<Result<Principal, FactoryError>> {
+        Box::pin(async move {
+            let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            if let Some(record) = creation::get(caller, request_id) {
+                record.check_retry(&settings, tier.as_deref(), &init_args)?;
+                if let CreationStatus::Completed(canister_id) = record.status {
+                    return Ok(canister_id);
+                }
+            }
+
+            let module = state::factory_state().module().ok();
+            let mut paid = false;
+            let result = async {
+                let mut operation =
+                    OperationRecord::new(OperationKind::CreateCanister, caller, vec![]);
+                operation.request_id = Some(request_id);
+                let state_lock = state::factory_state().lock_for(operation)?;
+
+                let record = match creation::get(caller, request_id) {
+                    Some(record) => {
+                        record.check_retry(&settings, tier.as_deref(), &init_args)?;
+                        record
+                    }
+                    None => {
+                        if init_args.len() > creation::MAX_INIT_ARGS_SIZE {
+                            return Err(FactoryError::GenericError(format!(
+                                "init arguments are larger than {} bytes",
+                                creation::MAX_INIT_ARGS_SIZE
+                            )));
+                        }
+
+                        let pricing = state::factory_state().pricing(tier.as_deref())?;
+                        state_lock.set_step("paying".into());
+                        let cycles_minted = {
+                            #[cfg(target_arch = "wasm32")]
+                            {
+                                state::factory_state()
+                                    .consume_creation_fee(caller, self.cmc_principal(), pricing)
+                                    .await?
+                            }
+
+                            #[cfg(not(target_arch = "wasm32"))]
+                            {
+                                0
+                            }
+                        };
+
+                        // The creation record keeps the payment, so the attached cycles are
+                        // accepted right away, even if this call doesn't finish the creation.
+                        state::factory_state().accept_creation_cycles(pricing);
+                        let record = CreationRecord::new(
+                            cycles_minted.min(pricing.initial_cycles),
+                            settings,
+                            tier,
+                            init_args,
+                        );
+                        creation::prune(caller);
+                        creation::insert(caller, request_id, &record);
+                        record
+                    }
+                };
+                paid = true;
+
+                let canister_id = match record.status {
+                    CreationStatus::Completed(canister_id) => return Ok(canister_id),
+                    CreationStatus::Created(canister_id) => canister_id,
+                    CreationStatus::Paid => {
+                        state_lock.set_step("creating".into());
+                        let canister_id = state::factory_state()
+                            .create_empty_canister(
+                                record.cycles,
+                                &state_lock,
+                                record.settings.clone(),
+                            )
+                            .await
+                            .map_err(|e| FactoryError::CanisterCreateFailed(e.1))?;
+                        creation::set_status(
+                            caller,
+                            request_id,
+                            CreationStatus::Created(canister_id),
+                        );
+                        canister_id
+                    }
+                };
+
+                // The module could be installed by the interrupted call already.
+                state_lock.set_step("installing".into());
+                let installed = canister_id
+                    .status()
+                    .await
+                    .map_err(|e| FactoryError::ManagementError(e.1))?
+                    .module_hash
+                    .is_some();
+                if !installed {
                     state::factory_state()
-                        .consume_provided_cycles_or_icp(caller, self.cmc_principal())
-                        .await?
+                        .install_created(canister_id, record.init_args, &state_lock)?
+                        .await
+                        .map_err(|e| FactoryError::CanisterCreateFailed(e.1))?;
                 }
 
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    0
+                state::factory_state()
+                    .register_created(
+                        canister_id,
+                        caller,
+                        settings_owner(&record.settings),
+                        &state_lock,
+                    )
+                    .expect("correct state lock");
+                creation::set_status(caller, request_id, CreationStatus::Completed(canister_id));
+
+                Ok(canister_id)
+            }
+            .await;
+
+            // Same as in `create_canister_with_tier`, failures before the payment are not
+            // recorded.
+            if paid {
+                audit::record(
+                    caller,
+                    Operation::CreateCanister,
+                    result.iter().copied().collect(),
+                    module.as_ref().map(CanisterModule::hash),
+                    Some(format!("request id {request_id}")),
+                    &result,
+                );
+            }
+            result
+        })
+    }
+
+    /// Returns the caller's canister creation request with the given id.
+    #[query(trait = true)]
+    fn get_creation_request(&self, request_id: u64) -> Option<CreationRecord> {
+        creation::get(ic::caller(), request_id)
+    }
+
+    /// Returns all the caller's canister creation requests with their ids.
+    #[query(trait = true)]
+    fn get_creation_requests(&self) -> Vec<(u64, CreationRecord)> {
+        creation::list(ic::caller())
+    }
+
+    /// Creates a new canister paid with the ICRC-1 `token`. The caller must transfer the tokens to
+    /// their deposit interim account in the token before calling this method (see
+    /// [`get_token_deposit_account`]), or have enough tokens on their balance in the factory.
+    ///
+    /// The cycles for the new canister are taken from the factory balance.
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn create_canister_with_token<'a, T: ArgumentEncoder + Send + 'a>(
+        &'a self,
+        init_args: T,
+        token: Principal,
+        settings: CanisterSettings,
+        caller: Option<Principal>,
+    ) -> AsyncReturn<'a, Result<Principal, FactoryError>> {
+        Box::pin(async move {
+            let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            let module = state::factory_state().module().ok();
+            let owner = settings_owner(&settings);
+
+            let mut paid = false;
+            let result = async {
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::CreateCanister,
+                    caller,
+                    vec![],
+                ))?;
+
+                let initial_cycles = state::factory_state().initial_canister_cycles();
+                let required_cycles = state::factory_state().creation_cycles_required()?;
+                if ic::balance() < required_cycles {
+                    return Err(FactoryError::GenericError(
+                        "factory doesn't have enough cycles to create a canister".into(),
+                    ));
                 }
-            };
 
-            let cycles_to_canister = cycles_minted.min(INITIAL_CANISTER_CYCLES);
+                state_lock.set_step("paying".into());
+                let price = payments::charge(caller, token).await?;
+                paid = true;
 
-            let principal = state::factory_state()
-                .create_canister(init_args, cycles_to_canister, &state_lock, controller)?
-                .await
-                .map_err(|e| FactoryError::CanisterCreateFailed(e.1))?;
+                state_lock.set_step("creating".into());
+                let created = match state::factory_state().create_canister_with_settings(
+                    init_args,
+                    initial_cycles,
+                    &state_lock,
+                    settings,
+                ) {
+                    Ok(creation) => creation
+                        .await
+                        .map_err(|e| FactoryError::CanisterCreateFailed(e.1)),
+                    Err(e) => Err(e),
+                };
+
+                let principal = match created {
+                    Ok(principal) => principal,
+                    Err(e) => {
+                        payments::refund(caller, token, price);
+                        return Err(e);
+                    }
+                };
 
-            state::factory_state()
-                .register_created(principal, &state_lock)
-                .expect("correct state lock");
+                state::factory_state()
+                    .register_created(principal, caller, owner, &state_lock)
+                    .expect("correct state lock");
 
-            Ok(principal)
+                Ok(principal)
+            }
+            .await;
+
+            // Same as in `create_canister_with_tier`, failures before the payment are not
+            // recorded.
+            if paid {
+                audit::record(
+                    caller,
+                    Operation::CreateCanister,
+                    result.iter().copied().collect(),
+                    module.as_ref().map(CanisterModule::hash),
+                    Some(format!("paid with token {token}")),
+                    &result,
+                );
+            }
+            result
         })
     }
 
@@ -125,35 +613,53 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     ) -> AsyncReturn<Result<HashMap<Principal, UpgradeResult>, FactoryError>> {
         Box::pin(async move {
             let mut state = state::factory_state();
-            let state_lock = state.lock()?;
             let caller = ic_exports::ic_kit::ic::caller();
+            let state_lock = state.lock_for(OperationRecord::new(
+                OperationKind::UpgradeCanisters,
+                caller,
+                vec![],
+            ))?;
 
             let canisters = state.canister_list();
-            let module_hash = state.module()?.hash().0.clone();
+            let module_hash = state.module()?.hash().clone();
 
             let mut results = HashMap::new();
             for canister in canisters {
-                if state.canisters()[&canister].0 == module_hash {
+                if state.canisters()[&canister].0 == module_hash.0 {
                     results.insert(canister, UpgradeResult::Noop);
                     continue;
                 }
 
+                state_lock.set_step(format!("upgrading {canister}"));
                 let upgrader = state
-                    .check_is_owner_internal(caller)?
+                    .check_role_internal::<Upgrader>(caller)?
                     .upgrade(canister, &state_lock)?;
 
-                let upgrade_result = match upgrader.await {
+                let result = upgrader
+                    .await
+                    .map_err(|e| FactoryError::ManagementError(e.1));
+                audit::record(
+                    caller,
+                    Operation::UpgradeCanister,
+                    vec![canister],
+                    Some(&module_hash),
+                    None,
+                    &result,
+                );
+
+                let upgrade_result = match result {
                     Ok(()) => UpgradeResult::Upgraded,
-                    Err(e) => UpgradeResult::Error(e.1),
+                    Err(FactoryError::ManagementError(e)) => UpgradeResult::Error(e),
+                    Err(e) => UpgradeResult::Error(e.to_string()),
                 };
 
                 results.insert(canister, upgrade_result);
             }
 
-            let mut owner = state.check_is_owner_internal(caller)?;
+            let mut upgrader = state.check_role_internal::<Upgrader>(caller)?;
             for (canister, upgrade_result) in results.iter() {
                 if matches!(upgrade_result, UpgradeResult::Upgraded) {
-                    owner
+                    upgrader
                         .register_upgraded(*canister, &state_lock)
                         .expect("correct lock");
                 }
@@ -163,12 +669,365 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
         })
     }
 
+    /// Updates the settings (compute and memory allocation, freezing threshold and controllers) of
+    /// the canister created by the factory. The factory always stays in the controllers list of the
+    /// canister.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn update_canister_settings(
+        &self,
+        canister_id: Principal,
+        settings: CanisterSettings,
+    ) -> AsyncReturn<Result<(), FactoryError>> {
+        Box::pin(async move {
+            let caller = ic_exports::ic_kit::ic::caller();
+            let result = async {
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::UpdateCanisterSettings,
+                    caller,
+                    vec![canister_id],
+                ))?;
+                state::factory_state()
+                    .check_role_internal::<Operator>(caller)?
+                    .update_canister_settings(canister_id, settings, &state_lock)?
+                    .await
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::UpdateCanisterSettings,
+                vec![canister_id],
+                None,
+                None,
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Updates the settings of all the canisters created by the factory. Returns the result of the
+    /// update for every canister.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn update_all_canister_settings(
+        &self,
+        settings: CanisterSettings,
+    ) -> AsyncReturn<Result<HashMap<Principal, Result<(), FactoryError>>, FactoryError>> {
+        Box::pin(async move {
+            let mut state = state::factory_state();
+            let caller = ic_exports::ic_kit::ic::caller();
+            // Check the role before anything else, so that the call fails for unauthorized
+            // callers even if there are no canisters to update.
+            state.check_role_internal::<Operator>(caller)?;
+            let state_lock = state.lock_for(OperationRecord::new(
+                OperationKind::UpdateCanisterSettings,
+                caller,
+                vec![],
+            ))?;
+
+            let mut results = HashMap::new();
+            for canister in state.canister_list() {
+                state_lock.set_step(format!("updating settings of {canister}"));
+                let result = state
+                    .check_role_internal::<Operator>(caller)?
+                    .update_canister_settings(canister, settings.clone(), &state_lock)?
+                    .await;
+                audit::record(
+                    caller,
+                    Operation::UpdateCanisterSettings,
+                    vec![canister],
+                    None,
+                    None,
+                    &result,
+                );
+                results.insert(canister, result);
+            }
+
+            Ok(results)
+        })
+    }
+
     #[update(trait = true)]
     fn reset_update_lock(&self) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_is_owner_internal(caller)
+            .map(|mut owner| owner.release_update_lock());
+
+        audit::record(
+            caller,
+            Operation::ResetUpdateLock,
+            vec![],
+            None,
+            None,
+            &result,
+        );
+        result
+    }
+
+    /// Returns the records of the operations that were interrupted by a trap or which state lock
+    /// has expired. See [`crate::operations`] for the details.
+    ///
+    /// This method can only be called by principals with the `Viewer` role.
+    #[query(trait = true)]
+    fn get_interrupted_operations(&self) -> Result<Vec<(u64, OperationRecord)>, FactoryError> {
+        let mut state = state::factory_state();
+        state.check_role::<Viewer>()?;
+        Ok(state.interrupted_operations())
+    }
+
+    /// Resumes the interrupted operation. The operation is run again from the step it was
+    /// interrupted at, with the arguments stored in its creation or drop record, and the
+    /// operation record is removed if the resumed operation succeeds. Canister creation with a
+    /// request id, resumable canister dropping and canister upgrades can be resumed.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn resume_operation(&mut self, operation_id: u64) -> AsyncReturn<Result<(), FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                state::factory_state().check_is_owner_internal(caller)?;
+                let record = state::factory_state()
+                    .interrupted_operations()
+                    .into_iter()
+                    .find(|(id, _)| *id == operation_id)
+                    .map(|(_, record)| record)
+                    .ok_or(FactoryError::NotFound)?;
+
+                let canister_id = record.canisters.first().copied();
+                match (record.kind, record.request_id, canister_id) {
+                    (OperationKind::CreateCanister, Some(request_id), _)
+                        if creation::get(record.caller, request_id).is_some() =>
+                    {
+                        let creation = creation::get(record.caller, request_id)
+                            .ok_or(FactoryError::NotFound)?;
+                        self.create_canister_idempotent_encoded(
+                            creation.init_args,
+                            creation.settings,
+                            creation.tier,
+                            request_id,
+                            Some(record.caller),
+                        )
+                        .await?;
+                    }
+                    (OperationKind::DropCanister, _, Some(canister_id))
+                        if dropping::get(canister_id).is_some() =>
+                    {
+                        let drop = dropping::get(canister_id).ok_or(FactoryError::NotFound)?;
+                        self.drop_canister_safely(canister_id, drop.options, Some(record.caller))
+                            .await?;
+                    }
+                    (OperationKind::UpgradeCanisters, _, _) => {
+                        self.upgrade_canister().await?;
+                    }
+                    (kind, _, _) => {
+                        return Err(FactoryError::GenericError(format!(
+                            "{kind:?} operation cannot be resumed, roll it back instead"
+                        )))
+                    }
+                }
+
+                // The record is kept if the operation fails again, so it can be resumed or rolled
+                // back later.
+                state::factory_state()
+                    .check_is_owner_internal(caller)?
+                    .discard_operation(operation_id)?;
+
+                Ok(())
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::ResumeOperation,
+                vec![],
+                None,
+                Some(format!("operation {operation_id}")),
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Rolls back the interrupted operation and returns its record. If the operation holds the
+    /// expired state lock, the lock is released.
+    ///
+    /// If canister dropping is rolled back before the canister code is uninstalled, the canister
+    /// is started again and its dropping record is removed. For other operations only the
+    /// operation record is removed. Creation request records are kept, so an interrupted creation
+    /// can still be completed by repeating the request.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn rollback_operation(
+        &self,
+        operation_id: u64,
+    ) -> AsyncReturn<Result<OperationRecord, FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                let record = state::factory_state()
+                    .check_is_owner_internal(caller)?
+                    .discard_operation(operation_id)?;
+
+                if record.kind == OperationKind::DropCanister {
+                    for canister_id in &record.canisters {
+                        let restartable = dropping::get(*canister_id)
+                            .map_or(false, |drop| drop.next_step != DropStep::Delete);
+                        if restartable {
+                            canister_id
+                                .start()
+                                .await
+                                .map_err(|e| FactoryError::ManagementError(e.1))?;
+                            dropping::remove(*canister_id);
+                        }
+                    }
+                }
+
+                Ok(record)
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::RollbackOperation,
+                result
+                    .as_ref()
+                    .map(|record| record.canisters.clone())
+                    .unwrap_or_default(),
+                None,
+                Some(format!("operation {operation_id}")),
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Dry run of the V1 state migration: returns the configuration and the canister entries of
+    /// the V1 state that would be written to the factory state by `apply_v1_migration`, or `None`
+    /// if there is no pending migration. See [`crate::v1`] for the details.
+    ///
+    /// This method can only be called by principals with the `Viewer` role and by the controller
+    /// of the V1 state.
+    #[query(trait = true)]
+    fn get_v1_migration_plan(&self) -> Result<Option<V1Migration>, FactoryError> {
+        if v1::check_migration_owner(ic::caller()).is_err() {
+            state::factory_state().check_role::<Viewer>()?;
+        }
+
+        Ok(v1::pending_migration())
+    }
+
+    /// Writes the pending configuration and canister entries of the V1 state to the factory
+    /// state.
+    ///
+    /// This method can only be called by principals with the `Owner` role and by the controller
+    /// of the V1 state.
+    #[update(trait = true)]
+    fn apply_v1_migration(&self) -> Result<V1Migration, FactoryError> {
+        let caller = ic::caller();
+        let result =
+            v1::check_migration_owner(caller).and_then(|mut owner| owner.apply_v1_migration());
+
+        audit::record(
+            caller,
+            Operation::ApplyV1Migration,
+            result
+                .as_ref()
+                .map(|migration| {
+                    migration
+                        .canisters
+                        .iter()
+                        .map(|canister| canister.canister_id)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None,
+            None,
+            &result,
+        );
+        result
+    }
+
+    /// Starts a backup of the factory state. Call `continue_backup_export` until the export is
+    /// completed, and then read the backup with `get_backup_chunk`. See [`crate::backup`] for the
+    /// details.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn export_backup(&self) -> Result<ExportStatus, FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_is_owner_internal(caller)
+            .and_then(|mut owner| owner.export_backup());
+
+        audit::record(caller, Operation::ExportBackup, vec![], None, None, &result);
+        result
+    }
+
+    /// Encodes the next part of the backup started by `export_backup`.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn continue_backup_export(&self) -> Result<ExportStatus, FactoryError> {
+        state::factory_state().check_is_owner()?.continue_export()
+    }
+
+    /// Returns the chunk of the backup completed after the last `export_backup` call.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[query(trait = true)]
+    fn get_backup_chunk(&self, index: u32) -> Result<Vec<u8>, FactoryError> {
+        state::factory_state().check_is_owner()?;
+        backup::export_chunk(index).ok_or(FactoryError::NotFound)
+    }
+
+    /// Starts the import of a backup into the factory without canisters. The backup chunks are
+    /// uploaded with `import_backup_chunk`, and the import is completed with
+    /// `finish_backup_import`.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn begin_backup_import(&self, info: BackupInfo) -> Result<(), FactoryError> {
+        state::factory_state().check_is_owner()?.begin_import(info)
+    }
+
+    /// Uploads the chunk of the backup being imported.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn import_backup_chunk(&self, index: u32, chunk: Vec<u8>) -> Result<(), FactoryError> {
         state::factory_state()
             .check_is_owner()?
-            .release_update_lock();
-        Ok(())
+            .import_chunk(index, chunk)
+    }
+
+    /// Validates the uploaded backup and imports it into the factory. The backup is either
+    /// imported completely, or not imported at all. Returns the ids of the imported canisters.
+    ///
+    /// This method can only be called by principals with the `Owner` role.
+    #[update(trait = true)]
+    fn finish_backup_import(&self) -> Result<Vec<Principal>, FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_is_owner_internal(caller)
+            .and_then(|mut owner| owner.finish_import(caller));
+
+        audit::record(
+            caller,
+            Operation::ImportBackup,
+            result.as_ref().cloned().unwrap_or_default(),
+            None,
+            None,
+            &result,
+        );
+        result
     }
 
     /// Returns the current version of canister.
@@ -184,11 +1043,26 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     }
 
     /// Returns a vector of all canisters created by the factory.
+    ///
+    /// For large number of canisters use [`list_canisters`] method instead.
     #[query(trait = true)]
     fn get_all(&self) -> Vec<Principal> {
         state::factory_state().canister_list()
     }
 
+    /// Returns a page of the factory canisters with their metadata, matching the request filters.
+    /// See [`registry::list`] for details.
+    #[query(trait = true)]
+    fn list_canisters(&self, request: CanisterListRequest) -> CanisterListPage {
+        registry::list(&request)
+    }
+
+    /// Returns the metadata of the factory canister.
+    #[query(trait = true)]
+    fn get_canister_metadata(&self, canister_id: Principal) -> Option<CanisterMetadata> {
+        registry::metadata(canister_id)
+    }
+
     /// Returns the ICP fee amount for canister creation.
     #[query(trait = true)]
     fn get_icp_fee(&self) -> u64 {
@@ -196,10 +1070,127 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     }
 
     /// Sets the ICP fee amount for canister creation. This method can only be called
-    /// by the factory controller.
+    /// by principals with the `Billing` role.
     #[update(trait = true)]
     fn set_icp_fee(&self, e8s: u64) -> Result<(), FactoryError> {
-        state::factory_state().check_is_owner()?.set_icp_fee(e8s)
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.set_icp_fee(e8s));
+
+        audit::record(
+            caller,
+            Operation::SetIcpFee,
+            vec![],
+            None,
+            Some(e8s.to_string()),
+            &result,
+        );
+        result
+    }
+
+    /// Returns the amount of cycles to attach to a canister creation call. The attached cycles
+    /// above this amount are refunded to the caller.
+    #[query(trait = true)]
+    fn get_creation_cycles_required(&self) -> Result<u64, FactoryError> {
+        state::factory_state().creation_cycles_required()
+    }
+
+    /// Sets the amount of cycles transferred to the new canisters and the amount of cycles charged
+    /// by the factory to cover the canister creation expenses. This method can only be called by
+    /// principals with the `Billing` role.
+    #[update(trait = true)]
+    fn set_creation_cycles(
+        &self,
+        initial_cycles: u64,
+        creation_cost: u64,
+    ) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.set_creation_cycles(initial_cycles, creation_cost));
+
+        audit::record(
+            caller,
+            Operation::SetCreationCycles,
+            vec![],
+            None,
+            Some(format!(
+                "initial cycles: {initial_cycles}, creation cost: {creation_cost}"
+            )),
+            &result,
+        );
+        result
+    }
+
+    /// Returns the pricing tiers the canisters can be created with.
+    #[query(trait = true)]
+    fn get_pricing_tiers(&self) -> Vec<PricingTier> {
+        state::factory_state().pricing_tiers()
+    }
+
+    /// Adds a new pricing tier or replaces the existing tier with the same name. This method can
+    /// only be called by principals with the `Billing` role.
+    #[update(trait = true)]
+    fn set_pricing_tier(&self, tier: PricingTier) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        let details = format!("{tier:?}");
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.set_pricing_tier(tier));
+
+        audit::record(
+            caller,
+            Operation::SetPricingTier,
+            vec![],
+            None,
+            Some(details),
+            &result,
+        );
+        result
+    }
+
+    /// Removes the pricing tier. This method can only be called by principals with the `Billing`
+    /// role.
+    #[update(trait = true)]
+    fn remove_pricing_tier(&self, name: String) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.remove_pricing_tier(&name));
+
+        audit::record(
+            caller,
+            Operation::RemovePricingTier,
+            vec![],
+            None,
+            Some(name),
+            &result,
+        );
+        result
+    }
+
+    /// Returns the cycles and fees of canister creation with the pricing tier, and the amount of
+    /// ICP that the CMC mints the required cycles for. If `tier` is `None`, the default factory
+    /// pricing is quoted.
+    ///
+    /// This is an update method, as the conversion rate is requested from the CMC.
+    #[update(trait = true)]
+    fn quote_creation_price(
+        &self,
+        tier: Option<String>,
+    ) -> AsyncReturn<Result<CreationQuote, FactoryError>> {
+        Box::pin(async move {
+            let pricing = state::factory_state().pricing(tier.as_deref())?;
+            let icp_amount =
+                top_up::icp_amount_from_cycles(self.cmc_principal(), pricing.cycles_required()?)
+                    .await?;
+
+            Ok(CreationQuote {
+                pricing,
+                icp_amount,
+            })
+        })
     }
 
     /// Returns the principal that will receive the ICP fees.
@@ -209,10 +1200,176 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     }
 
     /// Sets the principal that will receive the ICP fees. This method can only be called
-    /// by the factory controller.
+    /// by principals with the `Billing` role.
     #[update(trait = true)]
     fn set_icp_to(&self, to: Principal) -> Result<(), FactoryError> {
-        state::factory_state().check_is_owner()?.set_fee_to(to)
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.set_fee_to(to));
+
+        audit::record(
+            caller,
+            Operation::SetIcpTo,
+            vec![],
+            None,
+            Some(to.to_text()),
+            &result,
+        );
+        result
+    }
+
+    /// Returns the prices of canister creation in all the ICRC-1 tokens accepted by the factory.
+    #[query(trait = true)]
+    fn get_token_prices(&self) -> Vec<TokenPrice> {
+        payments::token_prices()
+    }
+
+    /// Returns the price of canister creation in the ICRC-1 token, or `None` if the token is not
+    /// accepted by the factory.
+    #[query(trait = true)]
+    fn get_token_price(&self, token: Principal) -> Option<TokenPrice> {
+        payments::token_price(token)
+    }
+
+    /// Sets the price of canister creation in the ICRC-1 token. The token configuration is
+    /// requested from the token canister.
+    ///
+    /// This method can only be called by principals with the `Billing` role.
+    #[update(trait = true)]
+    fn set_token_price(
+        &self,
+        token: Principal,
+        price: Tokens128,
+    ) -> AsyncReturn<Result<(), FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                state::factory_state().check_role_internal::<Billing>(caller)?;
+                let config = get_icrc1_configuration(token)
+                    .await
+                    .map_err(|e| FactoryError::PaymentError(e.to_string()))?;
+
+                state::factory_state()
+                    .check_role_internal::<Billing>(caller)?
+                    .set_token_price(config, price)
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::SetTokenPrice,
+                vec![],
+                None,
+                Some(format!("{token}: {price}")),
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Removes the ICRC-1 token from the list of tokens accepted by the factory.
+    ///
+    /// This method can only be called by principals with the `Billing` role.
+    #[update(trait = true)]
+    fn remove_token_price(&self, token: Principal) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_role_internal::<Billing>(caller)
+            .and_then(|mut billing| billing.remove_token_price(token));
+
+        audit::record(
+            caller,
+            Operation::RemoveTokenPrice,
+            vec![],
+            None,
+            Some(token.to_text()),
+            &result,
+        );
+        result
+    }
+
+    /// Returns the account the caller should transfer ICRC-1 tokens to, to pay for a canister.
+    #[query(trait = true)]
+    fn get_token_deposit_account(&self) -> Account {
+        get_deposit_interim_account(ic::caller())
+    }
+
+    /// Returns the caller's balance in the ICRC-1 token.
+    #[query(trait = true)]
+    fn get_token_balance(&self, token: Principal) -> Tokens128 {
+        TokenBalances::new(token).balance_of(ic::caller())
+    }
+
+    /// Transfers `amount` of the caller's balance in the ICRC-1 token to the caller's account.
+    /// Returns the transaction id and the amount the caller receives after the transfer fees.
+    #[update(trait = true)]
+    fn withdraw_tokens(
+        &self,
+        token: Principal,
+        amount: Tokens128,
+    ) -> AsyncReturn<Result<(Nat, Tokens128), FactoryError>> {
+        Box::pin(async move {
+            payments::terminal(token)?
+                .withdraw(ic::caller(), amount)
+                .await
+                .map_err(|e| FactoryError::PaymentError(e.to_string()))
+        })
+    }
+
+    /// Transfers `amount` of the canister creation payments collected in the ICRC-1 token to the
+    /// caller's account.
+    ///
+    /// This method can only be called by principals with the `Billing` role.
+    #[update(trait = true)]
+    fn withdraw_token_revenue(
+        &self,
+        token: Principal,
+        amount: Tokens128,
+    ) -> AsyncReturn<Result<(Nat, Tokens128), FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                let mut terminal = payments::terminal(token)?;
+                state::factory_state()
+                    .check_role_internal::<Billing>(caller)?
+                    .take_revenue(token, caller, amount)?;
+
+                terminal
+                    .withdraw(caller, amount)
+                    .await
+                    .map_err(|e| FactoryError::PaymentError(e.to_string()))
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::WithdrawTokenRevenue,
+                vec![],
+                None,
+                Some(format!("{token}: {amount}")),
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Retries the ICRC-1 token transfers that failed with unknown result.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn recover_token_transfers(
+        &self,
+        token: Principal,
+    ) -> AsyncReturn<Result<Vec<Result<Nat, PaymentError>>, FactoryError>> {
+        Box::pin(async move {
+            state::factory_state().check_role::<Operator>()?;
+            let results = payments::terminal(token)?.recover_all().await;
+            Ok(results
+                .into_iter()
+                .map(|result| result.map(|(tx_id, _)| tx_id))
+                .collect())
+        })
     }
 
     /// Returns the ICPs transferred to the factory by the caller. This method returns all
@@ -250,9 +1407,20 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
     /// Sets the factory controller principal.
     #[update(trait = true)]
     fn set_controller(&self, controller: Principal) -> Result<(), FactoryError> {
-        state::factory_state()
-            .check_is_owner()?
-            .set_controller(controller)
+        let caller = ic::caller();
+        let result = state::factory_state()
+            .check_is_owner_internal(caller)
+            .and_then(|mut owner| owner.set_controller(controller));
+
+        audit::record(
+            caller,
+            Operation::SetController,
+            vec![],
+            None,
+            Some(controller.to_text()),
+            &result,
+        );
+        result
     }
 
     /// Returns the factory controller principal.
@@ -261,6 +1429,76 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
         state::factory_state().controller()
     }
 
+    /// Grants the role to the principal.
+    ///
+    /// This method can only be called by the factory owner.
+    #[update(trait = true)]
+    fn grant_role(&self, principal: Principal, role: Role) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        state::factory_state()
+            .check_is_owner_internal(caller)?
+            .grant_role(caller, principal, role)
+    }
+
+    /// Revokes the role from the principal.
+    ///
+    /// This method can only be called by the factory owner.
+    #[update(trait = true)]
+    fn revoke_role(&self, principal: Principal, role: Role) -> Result<(), FactoryError> {
+        let caller = ic::caller();
+        state::factory_state()
+            .check_is_owner_internal(caller)?
+            .revoke_role(caller, principal, role)
+    }
+
+    /// Returns the roles of the principal. The factory controller has all the roles.
+    #[query(trait = true)]
+    fn get_roles(&self, principal: Principal) -> Vec<Role> {
+        state::factory_state().roles(principal)
+    }
+
+    /// Returns all the principals with granted roles.
+    ///
+    /// This method can only be called by principals with the `Viewer` role.
+    #[query(trait = true)]
+    fn list_roles(&self) -> Result<Vec<(Principal, Vec<Role>)>, FactoryError> {
+        state::factory_state().check_role::<Viewer>()?;
+        Ok(acl::all_roles())
+    }
+
+    /// Returns up to `limit` records of the role changes log starting from `offset`.
+    ///
+    /// This method can only be called by principals with the `Viewer` role.
+    #[query(trait = true)]
+    fn get_role_changes(&self, offset: u64, limit: u64) -> Result<Vec<RoleChange>, FactoryError> {
+        state::factory_state().check_role::<Viewer>()?;
+        Ok(acl::role_changes(offset, limit))
+    }
+
+    /// Returns up to `limit` audit log records matching the filter, starting from the `offset`
+    /// record. See [`audit::query`] for details.
+    ///
+    /// This method can only be called by principals with the `Viewer` role.
+    #[query(trait = true)]
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<AuditPage, FactoryError> {
+        state::factory_state().check_role::<Viewer>()?;
+        Ok(audit::query(&filter, offset, limit))
+    }
+
+    /// Returns the number of records in the audit log.
+    ///
+    /// This method can only be called by principals with the `Viewer` role.
+    #[query(trait = true)]
+    fn audit_log_length(&self) -> Result<u64, FactoryError> {
+        state::factory_state().check_role::<Viewer>()?;
+        Ok(audit::len())
+    }
+
     /// Returns the AccountIdentifier for the caller subaccount in the factory account.
     #[query(trait = true)]
     fn get_ledger_account_id(&self) -> String {
@@ -281,17 +1519,238 @@ pub trait FactoryCanister: Canister + Sized + PreUpdate {
         caller: Option<Principal>,
     ) -> AsyncReturn<Result<(), FactoryError>> {
         Box::pin(async move {
-            let state_lock = state::factory_state().lock()?;
             let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            let result = async {
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::DropCanister,
+                    caller,
+                    vec![canister_id],
+                ))?;
+
+                state::factory_state()
+                    .check_role_internal::<Operator>(caller)?
+                    .drop_canister(canister_id, &state_lock)
+                    .await?;
+
+                state::factory_state()
+                    .check_role_internal::<Operator>(caller)?
+                    .register_dropped(canister_id, &state_lock)
+            }
+            .await;
+
+            audit::record(
+                caller,
+                Operation::DropCanister,
+                vec![canister_id],
+                None,
+                None,
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Drops the canister with the resumable dropping workflow: calls the canister finalization
+    /// hook if `options.finalize` is set, stops the canister, uninstalls its code, withdraws its
+    /// remaining cycles and deletes it. See [`crate::dropping`] for the details.
+    ///
+    /// If a step fails, the error is returned and the workflow can be continued by calling this
+    /// method again. The new `options` are used if the finalization step wasn't completed yet,
+    /// otherwise the `options` of the first call are used.
+    ///
+    /// Returns the amount of cycles the cycles receiver got during the finalization and the
+    /// withdrawal.
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn drop_canister_safely(
+        &self,
+        canister_id: Principal,
+        options: DropOptions,
+        caller: Option<Principal>,
+    ) -> AsyncReturn<Result<u128, FactoryError>> {
+        Box::pin(async move {
+            let caller = caller.unwrap_or_else(ic_exports::ic_kit::ic::caller);
+            let result = async {
+                let mut operator =
+                    state::factory_state().check_role_internal::<Operator>(caller)?;
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::DropCanister,
+                    caller,
+                    vec![canister_id],
+                ))?;
+                if !state::factory_state().is_registered(canister_id) {
+                    return Err(FactoryError::NotFound);
+                }
+
+                // Until the canister is finalized, a retry can change the drop options, e.g. to
+                // drop the canister without finalization if its finalization hook fails.
+                let record = match dropping::get(canister_id) {
+                    Some(record) if record.next_step != DropStep::Finalize => record,
+                    _ => {
+                        let record = DropRecord::new(options);
+                        dropping::insert(canister_id, record.clone());
+                        record
+                    }
+                };
+
+                let mut step = Some(record.next_step);
+                while let Some(current) = step {
+                    state_lock.set_step(format!("{current:?}"));
+                    let reclaimed = match operator
+                        .run_drop_step(canister_id, current, &record.options, &state_lock)
+                        .await
+                    {
+                        Ok(reclaimed) => reclaimed,
+                        Err(e) => {
+                            dropping::update(canister_id, |record| {
+                                record.last_error = Some(e.to_string())
+                            });
+                            return Err(e);
+                        }
+                    };
+
+                    step = current.next();
+                    dropping::update(canister_id, |record| {
+                        if let Some(next_step) = step {
+                            record.next_step = next_step;
+                        }
+                        if let Some(cycles) = reclaimed {
+                            let total = record.reclaimed_cycles.unwrap_or_default();
+                            record.reclaimed_cycles = Some(total.saturating_add(cycles));
+                        }
+                        record.last_error = None;
+                    });
+                }
+
+                let reclaimed_cycles = dropping::get(canister_id)
+                    .and_then(|record| record.reclaimed_cycles)
+                    .unwrap_or_default();
+                operator.register_dropped(canister_id, &state_lock)?;
+
+                Ok(reclaimed_cycles)
+            }
+            .await;
 
-            state::factory_state()
-                .check_is_owner_internal(caller)?
-                .drop_canister(canister_id, &state_lock)
-                .await?;
+            audit::record(
+                caller,
+                Operation::DropCanister,
+                vec![canister_id],
+                None,
+                result
+                    .as_ref()
+                    .ok()
+                    .map(|cycles| format!("reclaimed cycles: {cycles}")),
+                &result,
+            );
+            result
+        })
+    }
+
+    /// Returns the canisters which dropping was started but not completed, with the state of
+    /// their dropping workflows.
+    #[query(trait = true)]
+    fn get_pending_drops(&self) -> Vec<(Principal, DropRecord)> {
+        dropping::list()
+    }
+
+    /// Adds an existing canister to the factory canisters. The factory must be a controller of the
+    /// canister, and the canister must have a module installed. The actual module hash of the
+    /// canister is recorded.
+    ///
+    /// If `upgrade` is `true` and the canister runs a module different from the current factory
+    /// module, the canister is upgraded to the factory module. This requires the `Upgrader` role.
+    /// The canister is registered only after a successful upgrade, so if the upgrade fails, the
+    /// canister is not adopted.
+    ///
+    /// This method can only be called by principals with the `Operator` role.
+    #[update(trait = true)]
+    fn adopt_canister(
+        &self,
+        canister_id: Principal,
+        upgrade: bool,
+    ) -> AsyncReturn<Result<AdoptedCanister, FactoryError>> {
+        Box::pin(async move {
+            let caller = ic::caller();
+            let result = async {
+                state::factory_state().check_role_internal::<Operator>(caller)?;
+                if upgrade {
+                    state::factory_state().check_role_internal::<Upgrader>(caller)?;
+                }
+
+                let state_lock = state::factory_state().lock_for(OperationRecord::new(
+                    OperationKind::AdoptCanister,
+                    caller,
+                    vec![canister_id],
+                ))?;
 
-            state::factory_state()
-                .check_is_owner_internal(caller)?
-                .register_dropped(canister_id, &state_lock)
+                let status = canister_id
+                    .status()
+                    .await
+                    .map_err(|e| FactoryError::ManagementError(e.1))?;
+                if !status.settings.controllers.contains(&ic::id()) {
+                    return Err(FactoryError::GenericError(format!(
+                        "the factory is not a controller of canister {canister_id}"
+                    )));
+                }
+
+                let mut module_hash = status.module_hash.ok_or_else(|| {
+                    FactoryError::GenericError(format!(
+                        "canister {canister_id} has no module installed"
+                    ))
+                })?;
+
+                if state::factory_state().is_registered(canister_id) {
+                    return Err(FactoryError::GenericError(format!(
+                        "canister {canister_id} is already registered in the factory"
+                    )));
+                }
+
+                let runs_factory_module = state::factory_state()
+                    .module()
+                    .map_or(false, |module| module.hash().0 == module_hash);
+                let upgraded = upgrade && !runs_factory_module;
+                if upgraded {
+                    state::factory_state()
+                        .check_role_internal::<Upgrader>(caller)?
+                        .upgrade(canister_id, &state_lock)?
+                        .await
+                        .map_err(|e| FactoryError::ManagementError(e.1))?;
+                    module_hash = state::factory_state().module()?.hash().0.clone();
+                }
+
+                let module_version = state::factory_state()
+                    .check_role_internal::<Operator>(caller)?
+                    .register_adopted(canister_id, module_hash.clone(), &state_lock)?;
+                if upgraded {
+                    state::factory_state()
+                        .check_role_internal::<Upgrader>(caller)?
+                        .register_upgraded(canister_id, &state_lock)?;
+                }
+
+                Ok(AdoptedCanister {
+                    canister_id,
+                    module_hash,
+                    module_version,
+                    upgraded,
+                })
+            }
+            .await;
+
+            let module_hash = result
+                .as_ref()
+                .ok()
+                .map(|adopted| CanisterHash(adopted.module_hash.clone()));
+            audit::record(
+                caller,
+                Operation::AdoptCanister,
+                vec![canister_id],
+                module_hash.as_ref(),
+                result
+                    .as_ref()
+                    .ok()
+                    .map(|adopted| format!("upgraded: {}", adopted.upgraded)),
+                &result,
+            );
+            result
         })
     }
 
@@ -310,4 +1769,136 @@ pub enum UpgradeResult {
     Error(String),
 }
 
+/// Returns the additional controller of a new canister, set besides the factory.
+fn settings_owner(settings: &CanisterSettings) -> Option<Principal> {
+    let factory_id = ic::id();
+    settings
+        .controllers
+        .iter()
+        .flatten()
+        .find(|controller| **controller != factory_id)
+        .copied()
+}
+
 generate_exports!(FactoryCanister);
+
+#[cfg(test)]
+mod tests {
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_kit::MockContext;
+    use ic_helpers::management::{
+        CanisterIDArg, CanisterStatus, CanisterStatusKind, InstallCodeInput,
+    };
+
+    use super::*;
+    use crate::operations;
+    use crate::FactoryConfiguration;
+
+    #[derive(Clone, Canister)]
+    #[canister_no_upgrade_methods]
+    struct TestFactory {
+        #[id]
+        principal: Principal,
+    }
+
+    impl PreUpdate for TestFactory {}
+
+    impl FactoryCanister for TestFactory {}
+
+    const REQUEST_ID: u64 = 7;
+
+    /// Sets up a creation interrupted after the canister was created, with non-default settings
+    /// and a pricing tier. Returns the id of the interrupted operation.
+    fn interrupted_creation(owner: Principal, creator: Principal, canister_id: Principal) -> u64 {
+        state::factory_state().reset(FactoryConfiguration::new(owner, 0, owner, owner));
+        state::factory_state()
+            .check_role_internal::<Upgrader>(owner)
+            .unwrap()
+            .set_canister_wasm(vec![0, 1, 2])
+            .unwrap();
+
+        let settings = CanisterSettings {
+            controllers: Some(vec![creator]),
+            freezing_threshold: Some(1_000_000u32.into()),
+            ..Default::default()
+        };
+        let init_args = encode_args((42u32, "token")).unwrap();
+        let mut record = CreationRecord::new(100, settings, Some("premium".into()), init_args);
+        record.status = CreationStatus::Created(canister_id);
+        creation::insert(creator, REQUEST_ID, &record);
+
+        let mut operation = OperationRecord::new(OperationKind::CreateCanister, creator, vec![]);
+        operation.request_id = Some(REQUEST_ID);
+        operations::start(operation)
+    }
+
+    fn register_status_responder() {
+        register_virtual_responder(
+            Principal::management_canister(),
+            "canister_status",
+            |(_,): (CanisterIDArg,)| CanisterStatus {
+                status: CanisterStatusKind::Running,
+                settings: Default::default(),
+                module_hash: None,
+                memory_size: 0u8.into(),
+                cycles: 0u8.into(),
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn resumed_creation_uses_recorded_arguments() {
+        let owner = Principal::from_slice(&[1]);
+        let creator = Principal::from_slice(&[2]);
+        let canister_id = Principal::from_slice(&[3]);
+        MockContext::new().with_caller(owner).inject();
+        let operation_id = interrupted_creation(owner, creator, canister_id);
+
+        register_status_responder();
+        register_virtual_responder(
+            Principal::management_canister(),
+            "install_code",
+            move |(input,): (InstallCodeInput,)| {
+                assert_eq!(input.canister_id, canister_id);
+                assert_eq!(input.arg, encode_args((42u32, "token")).unwrap());
+            },
+        );
+
+        let mut factory = TestFactory::init_instance();
+        factory.resume_operation(operation_id).await.unwrap();
+
+        assert_eq!(
+            creation::get(creator, REQUEST_ID).unwrap().status,
+            CreationStatus::Completed(canister_id)
+        );
+        assert!(state::factory_state().is_registered(canister_id));
+        assert!(state::factory_state().interrupted_operations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_resume_keeps_operation() {
+        let owner = Principal::from_slice(&[1]);
+        let creator = Principal::from_slice(&[2]);
+        let canister_id = Principal::from_slice(&[3]);
+        MockContext::new().with_caller(owner).inject();
+        let operation_id = interrupted_creation(owner, creator, canister_id);
+
+        register_status_responder();
+        register_failing_virtual_responder(
+            Principal::management_canister(),
+            "install_code",
+            "install failed".into(),
+        );
+
+        let mut factory = TestFactory::init_instance();
+        assert!(factory.resume_operation(operation_id).await.is_err());
+
+        let interrupted = state::factory_state().interrupted_operations();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].0, operation_id);
+        assert_eq!(
+            creation::get(creator, REQUEST_ID).unwrap().status,
+            CreationStatus::Created(canister_id)
+        );
+    }
+}
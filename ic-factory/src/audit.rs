@@ -0,0 +1,283 @@
+//! Append-only log of the factory operations.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::{MemoryId, StableLog, Storable};
+
+use crate::error::FactoryError;
+use crate::state::CanisterHash;
+
+const AUDIT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(8);
+const AUDIT_DATA_MEMORY_ID: MemoryId = MemoryId::new(9);
+
+/// Maximum number of records checked by one [`query`] call.
+const MAX_SCANNED_RECORDS: u64 = 1000;
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    CreateCanister,
+    UpgradeCanister,
+    DropCanister,
+    RegisterExisting,
+    Forget,
+    UpdateCanisterSettings,
+    SetCanisterWasm,
+    SetIcpFee,
+    SetIcpTo,
+    SetController,
+    SetCmcPrincipal,
+    ResetUpdateLock,
+    SetTokenPrice,
+    RemoveTokenPrice,
+    WithdrawTokenRevenue,
+    SetCreationCycles,
+    SetPricingTier,
+    RemovePricingTier,
+    AdoptCanister,
+    ResumeOperation,
+    RollbackOperation,
+    ApplyV1Migration,
+    ExportBackup,
+    ImportBackup,
+    ReconcileControllers,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    Failure(String),
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct AuditRecord {
+    /// Time of the operation in nanoseconds.
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub operation: Operation,
+    /// Canisters affected by the operation.
+    pub canisters: Vec<Principal>,
+    /// Hash of the wasm module the operation used.
+    pub module_hash: Option<Vec<u8>>,
+    /// Operation specific details, e.g. the new value of a changed setting.
+    pub details: Option<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl Storable for AuditRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize audit record")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize audit record")
+    }
+}
+
+/// Filter for the audit log query. Only records matching all the set fields are returned.
+#[derive(Debug, Default, CandidType, Deserialize, Clone)]
+pub struct AuditFilter {
+    pub caller: Option<Principal>,
+    pub operation: Option<Operation>,
+    /// Returns only records which affected the given canister.
+    pub canister: Option<Principal>,
+    /// Returns only records made at this time or later.
+    pub from_timestamp: Option<u64>,
+    /// Returns only records made before this time.
+    pub to_timestamp: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.caller.map_or(true, |caller| caller == record.caller)
+            && self.operation.map_or(true, |op| op == record.operation)
+            && self
+                .canister
+                .map_or(true, |canister| record.canisters.contains(&canister))
+            && self
+                .from_timestamp
+                .map_or(true, |from| record.timestamp >= from)
+            && self.to_timestamp.map_or(true, |to| record.timestamp < to)
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct AuditPage {
+    /// Matching records with their indices in the log.
+    pub records: Vec<(u64, AuditRecord)>,
+    /// Offset to continue the query from. `None` if the end of the log is reached.
+    pub next_offset: Option<u64>,
+}
+
+/// Appends a record to the audit log.
+///
+/// Failed access checks are not recorded, so that anyone cannot fill the log with junk records. For
+/// the same reason, callers must not record other failures that anyone can cause without paying for
+/// the call.
+pub(crate) fn record<T>(
+    caller: Principal,
+    operation: Operation,
+    canisters: Vec<Principal>,
+    module_hash: Option<&CanisterHash>,
+    details: Option<String>,
+    result: &Result<T, FactoryError>,
+) {
+    let outcome = match result {
+        Ok(_) => AuditOutcome::Success,
+        Err(FactoryError::AccessDenied) => return,
+        Err(e) => AuditOutcome::Failure(e.to_string()),
+    };
+
+    let record = AuditRecord {
+        timestamp: ic::time(),
+        caller,
+        operation,
+        canisters,
+        module_hash: module_hash.map(|hash| hash.0.clone()),
+        details,
+        outcome,
+    };
+
+    append(record);
+}
+
+/// Appends an already made record to the audit log.
+pub(crate) fn append(record: AuditRecord) {
+    AUDIT_LOG.with(|log| {
+        log.borrow_mut()
+            .append(record)
+            .expect("failed to append audit record to stable memory")
+    });
+}
+
+/// Returns up to `limit` records matching the filter, starting from the `offset` record of the log.
+///
+/// To limit the amount of work, at most `MAX_SCANNED_RECORDS` records are checked by one call, so
+/// the returned page can contain less than `limit` records even if the log has more matching
+/// records. Use the returned `next_offset` to continue the query.
+pub fn query(filter: &AuditFilter, offset: u64, limit: u64) -> AuditPage {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = log.len().min(offset.saturating_add(MAX_SCANNED_RECORDS));
+
+        let mut records = vec![];
+        let mut idx = offset;
+        while idx < end && (records.len() as u64) < limit {
+            if let Some(record) = log.get(idx) {
+                if filter.matches(&record) {
+                    records.push((idx, record));
+                }
+            }
+            idx += 1;
+        }
+
+        AuditPage {
+            records,
+            next_offset: (idx < log.len()).then_some(idx),
+        }
+    })
+}
+
+/// Number of records in the audit log.
+pub fn len() -> u64 {
+    AUDIT_LOG.with(|log| log.borrow().len())
+}
+
+/// Returns up to `limit` records of the audit log starting from the `offset` record.
+pub(crate) fn records(offset: u64, limit: u64) -> Vec<AuditRecord> {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        (offset..log.len().min(offset.saturating_add(limit)))
+            .filter_map(|idx| log.get(idx))
+            .collect()
+    })
+}
+
+pub(crate) fn clear() {
+    AUDIT_LOG.with(|log| log.borrow_mut().clear());
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<StableLog<AuditRecord>> = {
+        RefCell::new(StableLog::new(AUDIT_INDEX_MEMORY_ID, AUDIT_DATA_MEMORY_ID)
+            .expect("failed to initialize audit log"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn query_filters_and_paginates() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let canister = Principal::from_slice(&[3]);
+
+        for _ in 0..3 {
+            record(
+                alice,
+                Operation::CreateCanister,
+                vec![canister],
+                None,
+                None,
+                &Ok::<(), FactoryError>(()),
+            );
+            record(
+                bob,
+                Operation::SetIcpFee,
+                vec![],
+                None,
+                Some("100".into()),
+                &Err::<(), _>(FactoryError::StateLocked),
+            );
+        }
+
+        record(
+            bob,
+            Operation::SetIcpFee,
+            vec![],
+            None,
+            None,
+            &Err::<(), _>(FactoryError::AccessDenied),
+        );
+        assert_eq!(len(), 6);
+
+        let filter = AuditFilter {
+            caller: Some(bob),
+            ..Default::default()
+        };
+        let page = query(&filter, 0, 2);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.records[0].0, 1);
+        assert_eq!(page.records[1].0, 3);
+        assert!(matches!(
+            page.records[0].1.outcome,
+            AuditOutcome::Failure(_)
+        ));
+        assert_eq!(page.next_offset, Some(4));
+
+        let page = query(&filter, page.next_offset.unwrap(), 2);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.next_offset, None);
+
+        let filter = AuditFilter {
+            canister: Some(canister),
+            operation: Some(Operation::CreateCanister),
+            ..Default::default()
+        };
+        let page = query(&filter, 0, 10);
+        assert_eq!(page.records.len(), 3);
+        assert!(page
+            .records
+            .iter()
+            .all(|(_, record)| record.outcome == AuditOutcome::Success));
+    }
+}
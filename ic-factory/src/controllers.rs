@@ -0,0 +1,583 @@
+//! Reconciliation of the controllers of the factory canisters.
+//!
+//! The factory must stay a controller of its canisters to be able to upgrade them, and the owner
+//! the canister was created for must stay a controller too. The reconciliation reads the
+//! controllers of the canisters with `canister_status` and reports the canisters with missing
+//! controllers, optionally adding the missing controllers back.
+//!
+//! Only controllers can request the canister status, so if the factory was removed from the
+//! controllers, the status request fails and the canister is reported as not controlled. Such
+//! canisters can only be fixed by their remaining controllers.
+//!
+//! The reconciliation runs periodically in batches, in the same way as the cycles monitor, see
+//! [`crate::monitor`]. Timers are not persisted across canister upgrades, so the factory canister
+//! must call [`start_reconcile_timer`] in its `init` and `post_upgrade` methods to run it.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_helpers::management::{CanisterSettings, ManagementPrincipalExt};
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableCell, Storable};
+
+use crate::audit::{self, Operation};
+use crate::core::update_canister_settings;
+use crate::error::FactoryError;
+use crate::monitor::{self, truncate_error};
+use crate::operations::{OperationKind, OperationRecord};
+use crate::registry;
+use crate::state::{factory_state, Authorized, Operator, PrincipalKey};
+
+const CONTROLLER_REPORTS_MEMORY_ID: MemoryId = MemoryId::new(19);
+const RECONCILE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+/// If a reconciliation run takes longer than this, it is considered failed and a new run can be
+/// started.
+const RECONCILE_RUN_TIMEOUT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct ReconcileConfig {
+    /// If `false`, the reconciliation timer is not running. The reconciliation still can be run
+    /// manually.
+    pub enabled: bool,
+    /// Interval between reconciliation runs in seconds.
+    pub interval_secs: u64,
+    /// Number of canisters checked in one run.
+    pub batch_size: u32,
+    /// Add the missing intended controllers back to the canisters.
+    pub restore: bool,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 24 * 60 * 60,
+            batch_size: 20,
+            restore: false,
+        }
+    }
+}
+
+impl Storable for ReconcileConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize reconcile config")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize reconcile config")
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub enum ControllerStatus {
+    /// All the intended controllers are set.
+    Ok,
+    /// The intended controllers are missing.
+    Missing(Vec<Principal>),
+    /// The missing intended controllers were added back.
+    Restored(Vec<Principal>),
+    /// Adding back the missing controllers failed.
+    RestoreFailed {
+        missing: Vec<Principal>,
+        error: String,
+    },
+    /// The canister status request failed, most likely because the factory is not a controller
+    /// of the canister anymore.
+    NotControlled(String),
+}
+
+/// Result of the last controllers check of a factory canister.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct ControllerReport {
+    /// Time of the check in nanoseconds.
+    pub timestamp: u64,
+    /// Controllers of the canister before the check. Empty if the status request failed.
+    pub controllers: Vec<Principal>,
+    pub status: ControllerStatus,
+}
+
+impl ControllerReport {
+    /// Returns `true` if the canister has missing controllers after the check.
+    pub fn is_flagged(&self) -> bool {
+        !matches!(
+            self.status,
+            ControllerStatus::Ok | ControllerStatus::Restored(_)
+        )
+    }
+}
+
+impl Storable for ControllerReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize controller report")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize controller report")
+    }
+}
+
+impl BoundedStorable for ControllerReport {
+    // A canister has at most 10 controllers and error messages are truncated, so the report
+    // always fits.
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Authorized<Operator> {
+    /// Updates the reconciliation configuration and restarts the reconciliation timer.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the interval or the batch size is zero.
+    pub fn set_reconcile_config(&mut self, config: ReconcileConfig) -> Result<(), FactoryError> {
+        if config.interval_secs == 0 || config.batch_size == 0 {
+            return Err(FactoryError::GenericError(
+                "reconciliation interval and batch size must be positive".into(),
+            ));
+        }
+
+        RECONCILE_CONFIG_CELL.with(|cell| {
+            cell.borrow_mut()
+                .set(config)
+                .expect("failed to set reconcile config to stable memory")
+        });
+
+        start_reconcile_timer();
+        Ok(())
+    }
+}
+
+/// Returns the current reconciliation configuration.
+pub fn reconcile_config() -> ReconcileConfig {
+    RECONCILE_CONFIG_CELL.with(|cell| cell.borrow().get().clone())
+}
+
+/// Returns the controllers the canister is intended to have: the factory and the canister owner
+/// recorded in the canister metadata.
+pub fn intended_controllers(canister_id: Principal) -> Vec<Principal> {
+    let mut controllers = vec![ic::id()];
+    if let Some(owner) = registry::metadata(canister_id).and_then(|metadata| metadata.owner) {
+        if owner != ic::id() {
+            controllers.push(owner);
+        }
+    }
+
+    controllers
+}
+
+/// Returns the last controllers report of the canister.
+pub fn report(canister_id: Principal) -> Option<ControllerReport> {
+    REPORTS_MAP.with(|map| map.borrow().get(&PrincipalKey(canister_id)))
+}
+
+/// Returns the last controllers reports of all the checked canisters. If `flagged_only` is
+/// `true`, returns only the reports of the canisters with missing controllers.
+pub fn reports(flagged_only: bool) -> Vec<(Principal, ControllerReport)> {
+    REPORTS_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(k, v)| (k.0, v))
+            .filter(|(_, report)| !flagged_only || report.is_flagged())
+            .collect()
+    })
+}
+
+pub(crate) fn remove_report(canister_id: Principal) {
+    REPORTS_MAP.with(|map| map.borrow_mut().remove(&PrincipalKey(canister_id)));
+}
+
+pub(crate) fn clear() {
+    REPORTS_MAP.with(|map| map.borrow_mut().clear());
+    RECONCILE_CURSOR.with(|cursor| cursor.set(None));
+}
+
+/// Returns the canisters which missing controllers were restored.
+pub(crate) fn restored(reports: &[(Principal, ControllerReport)]) -> Vec<Principal> {
+    reports
+        .iter()
+        .filter(|(_, report)| matches!(report.status, ControllerStatus::Restored(_)))
+        .map(|(canister_id, _)| *canister_id)
+        .collect()
+}
+
+/// Starts (or restarts) the reconciliation timer according to the stored configuration. If the
+/// reconciliation is disabled, stops the timer.
+pub fn start_reconcile_timer() {
+    #[cfg(target_arch = "wasm32")]
+    {
+        use std::time::Duration;
+
+        use ic_exports::ic_cdk_timers;
+
+        let config = reconcile_config();
+        RECONCILE_TIMER.with(|timer| {
+            if let Some(timer_id) = timer.borrow_mut().take() {
+                ic_cdk_timers::clear_timer(timer_id);
+            }
+
+            if config.enabled {
+                let timer_id = ic_cdk_timers::set_timer_interval(
+                    Duration::from_secs(config.interval_secs),
+                    || {
+                        ic_exports::ic_cdk::spawn(async {
+                            run_reconcile_batch().await;
+                        });
+                    },
+                );
+                *timer.borrow_mut() = Some(timer_id);
+            }
+        });
+    }
+}
+
+/// Checks the controllers of the next batch of the factory canisters according to the stored
+/// configuration. Every call continues from the canister the previous call stopped at.
+///
+/// Returns the reports of the checked canisters. If another run is in progress, or if the
+/// controllers should be restored and the factory state is locked by another operation, does
+/// nothing.
+pub async fn run_reconcile_batch() -> Vec<(Principal, ControllerReport)> {
+    let now = ic::time();
+    let is_running = RECONCILE_RUN_STARTED.with(|started| match started.get() {
+        Some(time) if now.saturating_sub(time) < RECONCILE_RUN_TIMEOUT_NANOS => true,
+        _ => {
+            started.set(Some(now));
+            false
+        }
+    });
+
+    if is_running {
+        return vec![];
+    }
+
+    let config = reconcile_config();
+    let state_lock = match config.restore {
+        true => match factory_state().lock_for(OperationRecord::new(
+            OperationKind::UpdateCanisterSettings,
+            ic::id(),
+            vec![],
+        )) {
+            Ok(lock) => Some(lock),
+            Err(_) => {
+                RECONCILE_RUN_STARTED.with(|started| started.set(None));
+                return vec![];
+            }
+        },
+        false => None,
+    };
+
+    let cursor = RECONCILE_CURSOR.with(Cell::get);
+    let batch = monitor::next_batch(cursor, config.batch_size as usize);
+    RECONCILE_CURSOR.with(|cursor| cursor.set(batch.last().copied()));
+
+    let reports = reconcile(batch, config.restore).await;
+    drop(state_lock);
+
+    let restored = restored(&reports);
+    if !restored.is_empty() {
+        audit::record(
+            ic::id(),
+            Operation::ReconcileControllers,
+            restored,
+            None,
+            None,
+            &Ok::<_, FactoryError>(()),
+        );
+    }
+
+    RECONCILE_RUN_STARTED.with(|started| started.set(None));
+    reports
+}
+
+/// Checks the controllers of the canisters and stores the reports. If `restore` is `true`, the
+/// missing intended controllers are added to the canisters, keeping the other controllers.
+///
+/// The caller must check that the canisters are registered in the factory. If `restore` is
+/// `true`, the caller must hold the factory state lock.
+pub(crate) async fn reconcile(
+    canisters: Vec<Principal>,
+    restore: bool,
+) -> Vec<(Principal, ControllerReport)> {
+    let mut results = Vec::with_capacity(canisters.len());
+    for canister_id in canisters {
+        let report = check_canister(canister_id, restore).await;
+
+        // The canister could be dropped while we were waiting for the response.
+        if factory_state().is_registered(canister_id) {
+            REPORTS_MAP.with(|map| {
+                map.borrow_mut()
+                    .insert(PrincipalKey(canister_id), report.clone())
+            });
+        }
+
+        results.push((canister_id, report));
+    }
+
+    results
+}
+
+async fn check_canister(canister_id: Principal, restore: bool) -> ControllerReport {
+    let controllers = match canister_id.status().await {
+        Ok(status) => status.settings.controllers,
+        Err((_, e)) => {
+            return ControllerReport {
+                timestamp: ic::time(),
+                controllers: vec![],
+                status: ControllerStatus::NotControlled(truncate_error(e)),
+            }
+        }
+    };
+
+    let missing = missing_controllers(&intended_controllers(canister_id), &controllers);
+    let status = if missing.is_empty() {
+        ControllerStatus::Ok
+    } else if !restore {
+        ControllerStatus::Missing(missing)
+    } else {
+        let settings = CanisterSettings {
+            controllers: Some(controllers.iter().chain(&missing).copied().collect()),
+            ..Default::default()
+        };
+
+        match update_canister_settings(canister_id, settings).await {
+            Ok(()) => ControllerStatus::Restored(missing),
+            Err(e) => ControllerStatus::RestoreFailed {
+                missing,
+                error: truncate_error(e.to_string()),
+            },
+        }
+    };
+
+    ControllerReport {
+        timestamp: ic::time(),
+        controllers,
+        status,
+    }
+}
+
+fn missing_controllers(intended: &[Principal], actual: &[Principal]) -> Vec<Principal> {
+    intended
+        .iter()
+        .filter(|controller| !actual.contains(controller))
+        .copied()
+        .collect()
+}
+
+thread_local! {
+    static REPORTS_MAP: RefCell<StableBTreeMap<PrincipalKey, ControllerReport>> =
+        RefCell::new(StableBTreeMap::new(CONTROLLER_REPORTS_MEMORY_ID));
+
+    static RECONCILE_CONFIG_CELL: RefCell<StableCell<ReconcileConfig>> = {
+        RefCell::new(StableCell::new(RECONCILE_CONFIG_MEMORY_ID, ReconcileConfig::default())
+            .expect("failed to initialize reconcile config"))
+    };
+
+    static RECONCILE_CURSOR: Cell<Option<Principal>> = Cell::new(None);
+
+    static RECONCILE_RUN_STARTED: Cell<Option<u64>> = Cell::new(None);
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    static RECONCILE_TIMER: RefCell<Option<ic_exports::ic_cdk_timers::TimerId>> =
+        RefCell::new(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_kit::MockContext;
+    use ic_helpers::management::{
+        CanisterIDArg, CanisterStatus, CanisterStatusKind, DefiniteCanisterSettings,
+    };
+
+    use super::*;
+    use crate::registry::CanisterMetadata;
+    use crate::state::{CanisterHash, CANISTERS_MAP};
+
+    #[derive(CandidType, Deserialize)]
+    struct UpdateSettingsArg {
+        canister_id: Principal,
+        settings: CanisterSettings,
+    }
+
+    thread_local! {
+        static UPDATED: RefCell<Vec<(Principal, Vec<Principal>)>> = RefCell::default();
+    }
+
+    fn register(count: u8, owner: Principal) -> Vec<Principal> {
+        let canisters = (0..count)
+            .map(|i| Principal::from_slice(&[10 + i]))
+            .collect::<Vec<_>>();
+        for canister in &canisters {
+            CANISTERS_MAP.with(|map| {
+                map.borrow_mut()
+                    .insert(PrincipalKey(*canister), CanisterHash(vec![]))
+            });
+            registry::insert_metadata(*canister, CanisterMetadata::new(None, Some(owner), None));
+        }
+
+        canisters
+    }
+
+    /// Registers the management canister responders for canisters controlled by the factory only.
+    fn register_responders() {
+        register_virtual_responder(
+            Principal::management_canister(),
+            "canister_status",
+            |(_,): (CanisterIDArg,)| CanisterStatus {
+                status: CanisterStatusKind::Running,
+                settings: DefiniteCanisterSettings {
+                    controllers: vec![ic::id()],
+                    ..Default::default()
+                },
+                module_hash: None,
+                memory_size: 0u8.into(),
+                cycles: 0u8.into(),
+            },
+        );
+        register_virtual_responder(
+            Principal::management_canister(),
+            "update_settings",
+            |(arg,): (UpdateSettingsArg,)| {
+                let controllers = arg.settings.controllers.unwrap_or_default();
+                UPDATED.with(|updated| updated.borrow_mut().push((arg.canister_id, controllers)));
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_missing_controllers() {
+        MockContext::new().inject();
+        let owner = Principal::from_slice(&[1]);
+        let canisters = register(1, owner);
+        register_responders();
+
+        let reports = reconcile(canisters.clone(), false).await;
+
+        assert_eq!(reports[0].1.status, ControllerStatus::Missing(vec![owner]));
+        assert!(report(canisters[0]).unwrap().is_flagged());
+        assert!(UPDATED.with(|updated| updated.borrow().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn reconcile_restores_missing_controllers() {
+        MockContext::new().inject();
+        let owner = Principal::from_slice(&[1]);
+        let canisters = register(1, owner);
+        register_responders();
+
+        let reports = reconcile(canisters.clone(), true).await;
+
+        assert_eq!(reports[0].1.status, ControllerStatus::Restored(vec![owner]));
+        assert!(!report(canisters[0]).unwrap().is_flagged());
+        assert_eq!(restored(&reports), canisters);
+        assert_eq!(
+            UPDATED.with(|updated| updated.borrow().clone()),
+            vec![(canisters[0], vec![ic::id(), owner])]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_not_controlled_canisters() {
+        MockContext::new().inject();
+        let canisters = register(1, Principal::from_slice(&[1]));
+        register_failing_virtual_responder(
+            Principal::management_canister(),
+            "canister_status",
+            "only controllers can request status".into(),
+        );
+
+        let reports = reconcile(canisters, false).await;
+
+        assert!(matches!(
+            reports[0].1.status,
+            ControllerStatus::NotControlled(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_of_unregistered_canisters_are_not_stored() {
+        MockContext::new().inject();
+        let canister_id = Principal::from_slice(&[1]);
+        register_responders();
+
+        let reports = reconcile(vec![canister_id], false).await;
+
+        assert_eq!(reports.len(), 1);
+        assert!(report(canister_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn batches_continue_from_cursor() {
+        MockContext::new().inject();
+        let canisters = register(3, Principal::from_slice(&[1]));
+        register_responders();
+        factory_state()
+            .check_role_internal::<Operator>(Principal::anonymous())
+            .unwrap()
+            .set_reconcile_config(ReconcileConfig {
+                batch_size: 2,
+                restore: true,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let reports = run_reconcile_batch().await;
+        let checked: Vec<_> = reports.iter().map(|(id, _)| *id).collect();
+        assert_eq!(checked, canisters[0..2]);
+
+        let reports = run_reconcile_batch().await;
+        assert_eq!(reports[0].0, canisters[2]);
+        assert_eq!(reports.len(), 1);
+
+        assert_eq!(reports_count(), 3);
+        assert!(factory_state().interrupted_operations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restoring_batch_is_skipped_if_state_is_locked() {
+        MockContext::new().inject();
+        register(1, Principal::from_slice(&[1]));
+        register_responders();
+        factory_state()
+            .check_role_internal::<Operator>(Principal::anonymous())
+            .unwrap()
+            .set_reconcile_config(ReconcileConfig {
+                restore: true,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let _lock = factory_state().lock().unwrap();
+        assert!(run_reconcile_batch().await.is_empty());
+        assert_eq!(reports_count(), 0);
+    }
+
+    fn reports_count() -> usize {
+        reports(false).len()
+    }
+
+    #[test]
+    fn only_intended_controllers_are_missing() {
+        let factory = Principal::from_slice(&[1]);
+        let owner = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[3]);
+
+        assert!(missing_controllers(&[factory, owner], &[other, owner, factory]).is_empty());
+        assert_eq!(
+            missing_controllers(&[factory, owner], &[other, factory]),
+            vec![owner]
+        );
+        assert_eq!(
+            missing_controllers(&[factory, owner], &[]),
+            vec![factory, owner]
+        );
+    }
+}
@@ -1,8 +1,15 @@
-use ic_exports::ic_cdk::api::call::CallResult;
+use ic_canister::virtual_canister_call;
+use ic_exports::ic_cdk::api::call::{CallResult, RejectionCode};
 use ic_exports::ic_cdk::export::candid::utils::ArgumentEncoder;
 use ic_exports::ic_cdk::export::candid::Principal;
-use ic_helpers::management::{CanisterSettings, InstallCodeMode, ManagementPrincipalExt};
+use ic_exports::ic_kit::ic;
+use ic_helpers::management::{
+    CanisterIDArg, CanisterSettings, CanisterStatusKind, InstallCodeInput, InstallCodeMode,
+    ManagementPrincipalExt,
+};
+use ic_helpers::tokens::Tokens128;
 
+use crate::dropping;
 use crate::error::FactoryError;
 
 pub async fn create_canister<T: ArgumentEncoder + Send>(
@@ -16,6 +23,15 @@ pub async fn create_canister<T: ArgumentEncoder + Send>(
         ..Default::default()
     };
 
+    create_canister_with_settings(wasm_module, init_args, cycles, settings).await
+}
+
+pub async fn create_canister_with_settings<T: ArgumentEncoder + Send>(
+    wasm_module: Vec<u8>,
+    init_args: T,
+    cycles: u64,
+    settings: CanisterSettings,
+) -> CallResult<Principal> {
     let canister = <Principal as ManagementPrincipalExt>::create(Some(settings), cycles).await?;
     canister
         .install_code(InstallCodeMode::Install, wasm_module, init_args)
@@ -24,12 +40,167 @@ pub async fn create_canister<T: ArgumentEncoder + Send>(
     Ok(canister)
 }
 
+/// Creates a new canister without installing any code to it.
+pub async fn create_empty_canister(
+    cycles: u64,
+    settings: CanisterSettings,
+) -> CallResult<Principal> {
+    <Principal as ManagementPrincipalExt>::create(Some(settings), cycles).await
+}
+
+/// Installs the wasm module to an empty canister with already candid encoded init arguments.
+pub async fn install_encoded(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>,
+) -> CallResult<()> {
+    virtual_canister_call!(
+        Principal::management_canister(),
+        "install_code",
+        (InstallCodeInput {
+            mode: InstallCodeMode::Install,
+            canister_id,
+            wasm_module,
+            arg,
+        },),
+        ()
+    )
+    .await
+}
+
 pub async fn upgrade_canister(canister_id: Principal, wasm_module: Vec<u8>) -> CallResult<()> {
     canister_id
         .install_code(InstallCodeMode::Upgrade, wasm_module, ())
         .await
 }
 
+pub async fn update_canister_settings(
+    canister_id: Principal,
+    settings: CanisterSettings,
+) -> Result<(), FactoryError> {
+    canister_id
+        .update_settings(settings)
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+/// Sends `cycles` from the factory balance to the canister.
+pub async fn deposit_cycles(canister_id: Principal, cycles: u64) -> Result<(), FactoryError> {
+    virtual_canister_call!(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIDArg { canister_id },),
+        (),
+        cycles
+    )
+    .await
+    .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+/// Calls the `factory_finalize` hook of the canister. See [`crate::dropping`] for the details.
+pub async fn finalize_canister(
+    canister_id: Principal,
+    cycles_receiver: Principal,
+) -> Result<(), FactoryError> {
+    virtual_canister_call!(canister_id, "factory_finalize", (cycles_receiver,), ())
+        .await
+        .map_err(|(_, e)| FactoryError::GenericError(format!("canister finalization failed: {e}")))
+}
+
+/// Replaces the code of the canister with the [`dropping::WITHDRAWAL_MODULE`].
+pub async fn install_withdrawal_module(canister_id: Principal) -> Result<(), FactoryError> {
+    canister_id
+        .install_code(
+            InstallCodeMode::Reinstall,
+            dropping::WITHDRAWAL_MODULE.to_vec(),
+            (),
+        )
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+/// Calls the `withdraw` method of the withdrawal module installed to the canister, which sends
+/// the canister cycles except for [`dropping::MAX_LOST_CYCLES`] to the `cycles_receiver`.
+pub async fn withdraw_cycles(
+    canister_id: Principal,
+    cycles_receiver: Principal,
+) -> Result<(), FactoryError> {
+    virtual_canister_call!(
+        canister_id,
+        "withdraw",
+        (CanisterIDArg {
+            canister_id: cycles_receiver
+        },),
+        ()
+    )
+    .await
+    .map_err(|(_, e)| FactoryError::GenericError(format!("cycles withdrawal failed: {e}")))
+}
+
+/// Returns the cycles balance of the canister.
+pub async fn canister_cycles(canister_id: Principal) -> Result<u128, FactoryError> {
+    let status = canister_id
+        .status()
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))?;
+
+    Ok(Tokens128::from_nat(&status.cycles)
+        .map(|cycles| cycles.amount)
+        .unwrap_or(u128::MAX))
+}
+
+/// Returns the cycles balance of the canister, which is either the factory itself or a canister
+/// controlled by the factory.
+pub async fn controlled_canister_cycles(canister_id: Principal) -> Result<u128, FactoryError> {
+    if canister_id == ic::id() {
+        Ok(ic::balance() as u128)
+    } else {
+        canister_cycles(canister_id).await
+    }
+}
+
+/// Stops the canister. Stopping an already stopped canister succeeds, so that the call can be
+/// retried.
+pub async fn stop_canister(canister_id: Principal) -> Result<(), FactoryError> {
+    let status = canister_id
+        .status()
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))?;
+    if matches!(status.status, CanisterStatusKind::Stopped) {
+        return Ok(());
+    }
+
+    canister_id
+        .stop()
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+pub async fn start_canister(canister_id: Principal) -> Result<(), FactoryError> {
+    canister_id
+        .start()
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+pub async fn uninstall_canister(canister_id: Principal) -> Result<(), FactoryError> {
+    canister_id
+        .uninstall_code()
+        .await
+        .map_err(|(_, e)| FactoryError::ManagementError(e))
+}
+
+/// Deletes the canister. Deleting a canister that doesn't exist succeeds, so that the call can be
+/// retried if the response to the previous call was lost.
+pub async fn delete_canister(canister_id: Principal) -> Result<(), FactoryError> {
+    match canister_id.delete().await {
+        Ok(()) => Ok(()),
+        // The management canister rejects calls to unknown canisters with this code.
+        Err((RejectionCode::DestinationInvalid, _)) => Ok(()),
+        Err((_, e)) => Err(FactoryError::ManagementError(e)),
+    }
+}
+
 pub async fn drop_canister(canister: Principal) -> Result<(), FactoryError> {
     canister
         .stop()
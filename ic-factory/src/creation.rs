@@ -0,0 +1,276 @@
+//! Records of the canister creation requests, used to make canister creation idempotent.
+//!
+//! A creation request is identified by the caller principal and a request id chosen by the caller.
+//! The request record is stored before the canister is created and is updated after each creation
+//! step, so a repeated request with the same id either returns the created canister or continues
+//! the creation from the step it was interrupted at, without charging the caller again. A repeated
+//! request must have the same arguments as the original one.
+//!
+//! Records of completed requests are kept for [`COMPLETED_RECORD_TTL`], and older ones are removed
+//! when the caller makes a new request.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_helpers::management::CanisterSettings;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableMultimap, Storable};
+
+use crate::error::FactoryError;
+use crate::state::PrincipalKey;
+
+const CREATION_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+
+/// Maximum size of the candid encoded init arguments of an idempotent creation request.
+pub const MAX_INIT_ARGS_SIZE: usize = 2048;
+
+/// Maximum length in bytes of a pricing tier name, as the name is stored in the creation records.
+pub const MAX_TIER_NAME_LENGTH: usize = 64;
+
+/// Time in nanoseconds the records of completed requests are kept for (7 days).
+pub const COMPLETED_RECORD_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CreationStatus {
+    /// The creation fee is paid, but the canister is not created yet.
+    Paid,
+    /// The canister is created, but the module is not installed yet.
+    Created(Principal),
+    /// The canister is created, installed and registered in the factory.
+    Completed(Principal),
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct CreationRecord {
+    pub status: CreationStatus,
+    /// Time the request was first received in nanoseconds.
+    pub started_at: u64,
+    pub updated_at: u64,
+    /// Amount of cycles to create the canister with.
+    pub cycles: u64,
+    pub settings: CanisterSettings,
+    pub tier: Option<String>,
+    /// Candid encoded canister init arguments.
+    pub init_args: Vec<u8>,
+}
+
+impl CreationRecord {
+    pub(crate) fn new(
+        cycles: u64,
+        settings: CanisterSettings,
+        tier: Option<String>,
+        init_args: Vec<u8>,
+    ) -> Self {
+        let now = ic::time();
+        Self {
+            status: CreationStatus::Paid,
+            started_at: now,
+            updated_at: now,
+            cycles,
+            settings,
+            tier,
+            init_args,
+        }
+    }
+
+    /// Checks that a repeated request has the same arguments as the recorded one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the arguments differ.
+    pub(crate) fn check_retry(
+        &self,
+        settings: &CanisterSettings,
+        tier: Option<&str>,
+        init_args: &[u8],
+    ) -> Result<(), FactoryError> {
+        let same_settings = Encode!(&self.settings).ok() == Encode!(settings).ok();
+        if same_settings && self.tier.as_deref() == tier && self.init_args == init_args {
+            Ok(())
+        } else {
+            Err(FactoryError::GenericError(
+                "creation request with this id was made with different arguments".into(),
+            ))
+        }
+    }
+}
+
+impl Storable for CreationRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize creation record")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize creation record")
+    }
+}
+
+impl BoundedStorable for CreationRecord {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+struct RequestId(u64);
+
+impl Storable for RequestId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_be_bytes().to_vec().into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes);
+        Self(u64::from_be_bytes(buf))
+    }
+}
+
+impl BoundedStorable for RequestId {
+    const MAX_SIZE: u32 = 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Returns the record of the caller's creation request.
+pub fn get(caller: Principal, request_id: u64) -> Option<CreationRecord> {
+    CREATION_REQUESTS.with(|map| {
+        map.borrow()
+            .get(&PrincipalKey(caller), &RequestId(request_id))
+    })
+}
+
+/// Returns all the creation requests of the caller with their ids.
+pub fn list(caller: Principal) -> Vec<(u64, CreationRecord)> {
+    CREATION_REQUESTS.with(|map| {
+        map.borrow()
+            .range(&PrincipalKey(caller))
+            .map(|(request_id, record)| (request_id.0, record))
+            .collect()
+    })
+}
+
+pub(crate) fn insert(caller: Principal, request_id: u64, record: &CreationRecord) {
+    CREATION_REQUESTS.with(|map| {
+        map.borrow_mut()
+            .insert(&PrincipalKey(caller), &RequestId(request_id), record)
+    });
+}
+
+/// Sets the status of the creation request if the request is recorded.
+pub(crate) fn set_status(caller: Principal, request_id: u64, status: CreationStatus) {
+    if let Some(mut record) = get(caller, request_id) {
+        record.status = status;
+        record.updated_at = ic::time();
+        insert(caller, request_id, &record);
+    }
+}
+
+/// Removes the caller's completed requests that were completed more than
+/// [`COMPLETED_RECORD_TTL`] ago.
+pub(crate) fn prune(caller: Principal) {
+    let now = ic::time();
+    let expired: Vec<_> = list(caller)
+        .into_iter()
+        .filter(|(_, record)| {
+            matches!(record.status, CreationStatus::Completed(_))
+                && record.updated_at.saturating_add(COMPLETED_RECORD_TTL) <= now
+        })
+        .map(|(request_id, _)| request_id)
+        .collect();
+
+    CREATION_REQUESTS.with(|map| {
+        let mut map = map.borrow_mut();
+        for request_id in expired {
+            map.remove(&PrincipalKey(caller), &RequestId(request_id));
+        }
+    });
+}
+
+pub(crate) fn clear() {
+    CREATION_REQUESTS.with(|map| map.borrow_mut().clear());
+}
+
+thread_local! {
+    static CREATION_REQUESTS: RefCell<StableMultimap<PrincipalKey, RequestId, CreationRecord>> =
+        RefCell::new(StableMultimap::new(CREATION_REQUESTS_MEMORY_ID));
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn records_are_scoped_by_caller() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let canister = Principal::from_slice(&[3]);
+
+        let record = CreationRecord::new(100, CanisterSettings::default(), None, vec![1, 2, 3]);
+        insert(alice, 1, &record);
+        insert(alice, 2, &record);
+        assert!(get(bob, 1).is_none());
+
+        set_status(alice, 1, CreationStatus::Created(canister));
+        set_status(bob, 1, CreationStatus::Completed(canister));
+        assert!(get(bob, 1).is_none());
+
+        let requests = list(alice);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, 1);
+        assert_eq!(requests[0].1.status, CreationStatus::Created(canister));
+        assert_eq!(requests[1].1.status, CreationStatus::Paid);
+        assert_eq!(requests[1].1.init_args, vec![1, 2, 3]);
+
+        assert!(record
+            .check_retry(&CanisterSettings::default(), None, &[1, 2, 3])
+            .is_ok());
+        assert!(record
+            .check_retry(&CanisterSettings::default(), Some("tier"), &[1, 2, 3])
+            .is_err());
+    }
+
+    #[test]
+    fn largest_record_fits_max_size() {
+        MockContext::new().inject();
+        let settings = CanisterSettings {
+            controllers: Some(vec![Principal::from_slice(&[0xff; 29]); 10]),
+            compute_allocation: Some(100u8.into()),
+            memory_allocation: Some(u64::MAX.into()),
+            freezing_threshold: Some(u64::MAX.into()),
+        };
+        let mut record = CreationRecord::new(
+            u64::MAX,
+            settings,
+            Some("t".repeat(MAX_TIER_NAME_LENGTH)),
+            vec![0xff; MAX_INIT_ARGS_SIZE],
+        );
+        record.status = CreationStatus::Completed(Principal::from_slice(&[0xff; 29]));
+
+        assert!(record.to_bytes().len() <= CreationRecord::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn completed_records_are_pruned() {
+        let context = MockContext::new().inject();
+        let alice = Principal::from_slice(&[1]);
+        let canister = Principal::from_slice(&[3]);
+
+        let record = CreationRecord::new(100, CanisterSettings::default(), None, vec![]);
+        insert(alice, 1, &record);
+        insert(alice, 2, &record);
+        set_status(alice, 1, CreationStatus::Completed(canister));
+
+        prune(alice);
+        assert_eq!(list(alice).len(), 2);
+
+        context.add_time(COMPLETED_RECORD_TTL);
+        prune(alice);
+        let requests = list(alice);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, 2);
+    }
+}
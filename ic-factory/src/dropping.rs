@@ -0,0 +1,241 @@
+//! Resumable workflow for dropping the factory canisters.
+//!
+//! A canister is dropped in several steps: the optional finalization hook of the canister is
+//! called, then the canister is stopped, its code is uninstalled, its remaining cycles are
+//! withdrawn and the canister is deleted. The
+//! next step of the workflow is stored in stable memory after each successful step, so if any step
+//! fails, dropping the canister again continues from the failed step.
+//!
+//! The finalization hook is the `factory_finalize` method of the dropped canister. It's called
+//! with the principal of the canister to send the remaining cycles to, and is expected to export
+//! the canister data it needs to keep and to send its remaining cycles to the given principal with
+//! [`crate::deposit_cycles`].
+//!
+//! The cycles left on a canister are lost when the canister is deleted. So if the canister has more
+//! than [`MAX_LOST_CYCLES`] after its code is uninstalled, the withdrawal step installs the
+//! [`WITHDRAWAL_MODULE`] to the canister, starts it and calls the `withdraw` method of the module,
+//! which deposits all the canister cycles except for [`MAX_LOST_CYCLES`] to the cycles receiver.
+//! The canister is stopped again before it's deleted. The source of the module is
+//! `dropping/withdrawal.wat`.
+//!
+//! All the steps except for the finalization succeed if they were already done, so a step can be
+//! safely retried if its response was lost.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, Storable};
+
+use crate::state::PrincipalKey;
+
+const DROPS_MEMORY_ID: MemoryId = MemoryId::new(15);
+
+/// Maximum amount of cycles a dropped canister can have when it's deleted. The withdrawal module
+/// keeps this amount on the canister to pay for the withdrawal call.
+pub const MAX_LOST_CYCLES: u128 = 10_000_000_000;
+
+/// The wasm module installed to a dropped canister to withdraw its remaining cycles.
+pub const WITHDRAWAL_MODULE: &[u8] = include_bytes!("dropping/withdrawal.wasm");
+
+#[derive(Debug, Default, CandidType, Deserialize, Clone)]
+pub struct DropOptions {
+    /// Call the `factory_finalize` hook of the canister before stopping it.
+    pub finalize: bool,
+    /// The canister to send the remaining cycles of the dropped canister to. If not set, the
+    /// cycles are sent to the factory. The factory must be a controller of the receiver to check
+    /// the amount of received cycles.
+    pub cycles_receiver: Option<Principal>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DropStep {
+    Finalize,
+    Stop,
+    Uninstall,
+    Withdraw,
+    Delete,
+}
+
+impl DropStep {
+    /// Returns the step following this one, or `None` if this is the last step.
+    pub fn next(self) -> Option<Self> {
+        match self {
+            Self::Finalize => Some(Self::Stop),
+            Self::Stop => Some(Self::Uninstall),
+            Self::Uninstall => Some(Self::Withdraw),
+            Self::Withdraw => Some(Self::Delete),
+            Self::Delete => None,
+        }
+    }
+}
+
+/// Record of a canister being dropped.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct DropRecord {
+    pub options: DropOptions,
+    /// The step to run next.
+    pub next_step: DropStep,
+    /// Time the dropping was started in nanoseconds.
+    pub started_at: u64,
+    pub updated_at: u64,
+    /// Amount of cycles the cycles receiver got during the finalization and the withdrawal.
+    pub reclaimed_cycles: Option<u128>,
+    /// Error of the last failed step.
+    pub last_error: Option<String>,
+}
+
+impl DropRecord {
+    pub(crate) fn new(options: DropOptions) -> Self {
+        let now = ic::time();
+        Self {
+            options,
+            next_step: DropStep::Finalize,
+            started_at: now,
+            updated_at: now,
+            reclaimed_cycles: None,
+            last_error: None,
+        }
+    }
+}
+
+impl Storable for DropRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize drop record")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize drop record")
+    }
+}
+
+impl BoundedStorable for DropRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Returns the drop record of the canister if the canister is being dropped.
+pub fn get(canister_id: Principal) -> Option<DropRecord> {
+    DROPS_MAP.with(|map| map.borrow().get(&PrincipalKey(canister_id)))
+}
+
+/// Returns the records of all the canisters which dropping was started but not completed.
+pub fn list() -> Vec<(Principal, DropRecord)> {
+    DROPS_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(canister_id, record)| (canister_id.0, record))
+            .collect()
+    })
+}
+
+pub(crate) fn insert(canister_id: Principal, record: DropRecord) {
+    DROPS_MAP.with(|map| map.borrow_mut().insert(PrincipalKey(canister_id), record));
+}
+
+/// Applies `f` to the drop record of the canister if it exists.
+pub(crate) fn update<F>(canister_id: Principal, f: F)
+where
+    F: FnOnce(&mut DropRecord),
+{
+    DROPS_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(mut record) = map.get(&PrincipalKey(canister_id)) {
+            f(&mut record);
+            record.updated_at = ic::time();
+            map.insert(PrincipalKey(canister_id), record);
+        }
+    });
+}
+
+pub(crate) fn remove(canister_id: Principal) {
+    DROPS_MAP.with(|map| map.borrow_mut().remove(&PrincipalKey(canister_id)));
+}
+
+pub(crate) fn clear() {
+    DROPS_MAP.with(|map| map.borrow_mut().clear());
+}
+
+thread_local! {
+    static DROPS_MAP: RefCell<StableBTreeMap<PrincipalKey, DropRecord>> =
+        RefCell::new(StableBTreeMap::new(DROPS_MEMORY_ID));
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn steps_follow_workflow_order() {
+        let mut steps = vec![DropStep::Finalize];
+        while let Some(step) = steps.last().and_then(|step| step.next()) {
+            steps.push(step);
+        }
+
+        assert_eq!(
+            steps,
+            vec![
+                DropStep::Finalize,
+                DropStep::Stop,
+                DropStep::Uninstall,
+                DropStep::Withdraw,
+                DropStep::Delete
+            ]
+        );
+    }
+
+    #[test]
+    fn record_keeps_step_to_resume_from() {
+        let context = MockContext::new().inject();
+        let canister_id = Principal::from_slice(&[1]);
+        let options = DropOptions {
+            finalize: true,
+            cycles_receiver: Some(Principal::from_slice(&[2])),
+        };
+        insert(canister_id, DropRecord::new(options));
+        assert!(get(Principal::from_slice(&[3])).is_none());
+
+        context.add_time(10);
+        update(canister_id, |record| {
+            record.next_step = DropStep::Withdraw;
+            record.last_error = Some("withdrawal failed".into());
+        });
+
+        let records = list();
+        assert_eq!(records.len(), 1);
+        let (id, record) = &records[0];
+        assert_eq!(*id, canister_id);
+        assert_eq!(record.next_step, DropStep::Withdraw);
+        assert_eq!(record.last_error.as_deref(), Some("withdrawal failed"));
+        assert_eq!(record.updated_at, record.started_at + 10);
+        assert!(record.options.finalize);
+        assert_eq!(
+            record.options.cycles_receiver,
+            Some(Principal::from_slice(&[2]))
+        );
+
+        remove(canister_id);
+        assert!(get(canister_id).is_none());
+    }
+
+    #[test]
+    fn updating_missing_record_does_nothing() {
+        MockContext::new().inject();
+        let canister_id = Principal::from_slice(&[1]);
+        update(canister_id, |record| record.next_step = DropStep::Delete);
+        assert!(get(canister_id).is_none());
+    }
+
+    #[test]
+    fn withdrawal_module_exports_withdraw_method() {
+        assert!(WITHDRAWAL_MODULE.starts_with(b"\0asm"));
+        assert!(WITHDRAWAL_MODULE
+            .windows(b"canister_update withdraw".len())
+            .any(|name| name == b"canister_update withdraw"));
+    }
+}
@@ -0,0 +1,104 @@
+;; The module installed to a dropped canister to withdraw its remaining cycles before the canister
+;; is deleted, see `ic-factory/src/dropping.rs`. The compiled module is `withdrawal.wasm`.
+;;
+;; The `withdraw` update method can only be called by a controller of the canister. It takes the
+;; candid encoded arguments of the management canister `deposit_cycles` method, and calls it with
+;; all the canister cycles except for the reserve of 10_000_000_000 cycles (`MAX_LOST_CYCLES`),
+;; which pays for the call. The method replies with the empty candid message after the cycles are
+;; deposited.
+(module
+  (import "ic0" "msg_arg_data_size" (func $msg_arg_data_size (result i32)))
+  (import "ic0" "msg_arg_data_copy" (func $msg_arg_data_copy (param i32 i32 i32)))
+  (import "ic0" "msg_caller_size" (func $msg_caller_size (result i32)))
+  (import "ic0" "msg_caller_copy" (func $msg_caller_copy (param i32 i32 i32)))
+  (import "ic0" "is_controller" (func $is_controller (param i32 i32) (result i32)))
+  (import "ic0" "msg_reply_data_append" (func $msg_reply_data_append (param i32 i32)))
+  (import "ic0" "msg_reply" (func $msg_reply))
+  (import "ic0" "msg_reject" (func $msg_reject (param i32 i32)))
+  (import "ic0" "canister_cycle_balance128" (func $canister_cycle_balance128 (param i32)))
+  (import "ic0" "call_new"
+    (func $call_new (param i32 i32 i32 i32 i32 i32 i32 i32)))
+  (import "ic0" "call_data_append" (func $call_data_append (param i32 i32)))
+  (import "ic0" "call_cycles_add128" (func $call_cycles_add128 (param i64 i64)))
+  (import "ic0" "call_perform" (func $call_perform (result i32)))
+
+  ;; Memory layout:
+  ;;   0..96     constant strings
+  ;;   128..160  caller principal
+  ;;   160..176  cycles balance
+  ;;   256..1280 method arguments
+  (memory 1)
+  (data (i32.const 0) "deposit_cycles")
+  (data (i32.const 16) "DIDL\00\00")
+  (data (i32.const 24) "caller is not a controller")
+  (data (i32.const 56) "call failed")
+  (data (i32.const 72) "argument is too large")
+
+  (global $reserve i64 (i64.const 10_000_000_000))
+  (global $max_arg_size i32 (i32.const 1024))
+
+  ;; Callbacks of the `deposit_cycles` call, referenced by their table indices.
+  (table 2 funcref)
+  (elem (i32.const 0) $on_reply $on_reject)
+
+  (func $reply_empty
+    (call $msg_reply_data_append (i32.const 16) (i32.const 6))
+    (call $msg_reply))
+
+  (func $reject_call_failed
+    (call $msg_reject (i32.const 56) (i32.const 11)))
+
+  (func $on_reply (param $env i32)
+    (call $reply_empty))
+
+  (func $on_reject (param $env i32)
+    (call $reject_call_failed))
+
+  (func $withdraw
+    (local $arg_size i32)
+    (local $low i64)
+    (local $high i64)
+
+    (call $msg_caller_copy (i32.const 128) (i32.const 0) (call $msg_caller_size))
+    (if (i32.eqz (call $is_controller (i32.const 128) (call $msg_caller_size)))
+      (then
+        (call $msg_reject (i32.const 24) (i32.const 26))
+        (return)))
+
+    (local.set $arg_size (call $msg_arg_data_size))
+    (if (i32.gt_u (local.get $arg_size) (global.get $max_arg_size))
+      (then
+        (call $msg_reject (i32.const 72) (i32.const 21))
+        (return)))
+    (call $msg_arg_data_copy (i32.const 256) (i32.const 0) (local.get $arg_size))
+
+    ;; The balance is stored as a little endian u128.
+    (call $canister_cycle_balance128 (i32.const 160))
+    (local.set $low (i64.load (i32.const 160)))
+    (local.set $high (i64.load (i32.const 168)))
+    (if (i32.and
+          (i64.eqz (local.get $high))
+          (i64.le_u (local.get $low) (global.get $reserve)))
+      (then
+        (call $reply_empty)
+        (return)))
+
+    (call $call_new
+      ;; The management canister principal is empty.
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i32.const 14)
+      (i32.const 0) (i32.const 0)
+      (i32.const 1) (i32.const 0))
+    (call $call_data_append (i32.const 256) (local.get $arg_size))
+    ;; Subtracts the reserve from the u128 balance.
+    (call $call_cycles_add128
+      (i64.sub
+        (local.get $high)
+        (i64.extend_i32_u (i64.lt_u (local.get $low) (global.get $reserve))))
+      (i64.sub (local.get $low) (global.get $reserve)))
+    (if (call $call_perform)
+      (then
+        (call $reject_call_failed))))
+
+  (export "canister_update withdraw" (func $withdraw))
+)
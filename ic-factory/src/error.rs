@@ -15,7 +15,13 @@ pub enum FactoryError {
     #[error("not enough ICP provided to create a canister. Provided: {0}. Required: {1}")]
     NotEnoughIcp(u64, u64),
 
-    #[error("only the factory controller is allowed to call this method")]
+    #[error("not enough tokens provided to create a canister. Provided: {0}. Required: {1}")]
+    NotEnoughTokens(u128, u128),
+
+    #[error("token payment failed: {0}")]
+    PaymentError(String),
+
+    #[error("the caller doesn't have the role required to call this method")]
     AccessDenied,
 
     #[error("canister is not in factory registry")]
@@ -33,6 +39,9 @@ pub enum FactoryError {
     #[error("failed to create canister: {0}")]
     CanisterCreateFailed(String),
 
+    #[error("the principal doesn't have the role")]
+    RoleNotGranted,
+
     #[error("factory error: {0}")]
     GenericError(String),
 }
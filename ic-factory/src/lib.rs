@@ -1,8 +1,17 @@
+pub mod acl;
 pub mod api;
+pub mod audit;
+pub mod controllers;
 mod core;
+pub mod creation;
+pub mod dropping;
 mod state;
 
 pub mod error;
+pub mod monitor;
+pub mod operations;
+pub mod payments;
+pub mod registry;
 pub mod top_up;
 pub mod types;
 pub mod update_lock;
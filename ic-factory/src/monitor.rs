@@ -0,0 +1,446 @@
+//! Periodic monitoring of the cycles balances of the factory canisters.
+//!
+//! The monitor checks the status of the factory canisters in batches, stores the latest status
+//! snapshot for every canister and tops up the canisters with cycles balance lower than the
+//! configured threshold.
+//!
+//! Timers are not persisted across canister upgrades, so the factory canister must call
+//! [`start_monitor_timer`] in its `init` and `post_upgrade` methods to run the monitor.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_exports::ledger::{Subaccount, DEFAULT_TRANSFER_FEE};
+use ic_helpers::management::{CanisterStatusKind, ManagementPrincipalExt};
+use ic_helpers::tokens::Tokens128;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableCell, Storable};
+
+use crate::core::deposit_cycles;
+use crate::error::FactoryError;
+use crate::registry;
+use crate::state::{factory_state, Authorized, Operator, PrincipalKey};
+use crate::top_up;
+
+const MONITOR_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(3);
+const SNAPSHOTS_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+/// Maximum length in bytes of an error message stored in a snapshot.
+const MAX_ERROR_LENGTH: usize = 128;
+
+/// If a monitor run takes longer than this, it is considered failed and a new run can be started.
+const MONITOR_RUN_TIMEOUT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+/// Source of the cycles for the automatic top ups.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TopUpSource {
+    /// Cycles are sent from the factory cycles balance.
+    FactoryBalance,
+    /// ICP from the factory ledger account are converted to cycles by the CMC.
+    Cmc,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct MonitorConfig {
+    /// If `false`, the monitor timer is not running. The monitor still can be run manually.
+    pub enabled: bool,
+    /// Interval between monitor runs in seconds.
+    pub interval_secs: u64,
+    /// Number of canisters checked in one monitor run.
+    pub batch_size: u32,
+    /// Canisters with cycles balance lower than this value are topped up.
+    pub threshold: u64,
+    /// Amount of cycles to send to a canister in one top up. Zero disables automatic top ups.
+    pub top_up_amount: u64,
+    pub top_up_source: TopUpSource,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 60 * 60,
+            batch_size: 20,
+            threshold: 10u64.pow(12),
+            top_up_amount: 2 * 10u64.pow(12),
+            top_up_source: TopUpSource::FactoryBalance,
+        }
+    }
+}
+
+impl Storable for MonitorConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize monitor config")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize monitor config")
+    }
+}
+
+/// Information about a top up made by the monitor.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct TopUp {
+    /// Time of the top up in nanoseconds.
+    pub timestamp: u64,
+    /// Amount of cycles the canister received.
+    pub cycles: u64,
+    pub error: Option<String>,
+}
+
+/// The latest known status of a factory canister.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct CanisterSnapshot {
+    /// Time of the snapshot in nanoseconds.
+    pub timestamp: u64,
+    /// `None` if the status request failed.
+    pub status: Option<CanisterStatusKind>,
+    pub cycles: u128,
+    pub memory_size: u64,
+    /// Error returned by the status request.
+    pub error: Option<String>,
+    pub last_top_up: Option<TopUp>,
+}
+
+impl Storable for CanisterSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize canister snapshot")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize canister snapshot")
+    }
+}
+
+impl BoundedStorable for CanisterSnapshot {
+    // Error messages are truncated to `MAX_ERROR_LENGTH`, so the snapshot always fits.
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Authorized<Operator> {
+    /// Updates the monitor configuration and restarts the monitor timer.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the interval or the batch size is zero.
+    pub fn set_monitor_config(&mut self, config: MonitorConfig) -> Result<(), FactoryError> {
+        if config.interval_secs == 0 || config.batch_size == 0 {
+            return Err(FactoryError::GenericError(
+                "monitor interval and batch size must be positive".into(),
+            ));
+        }
+
+        MONITOR_CONFIG_CELL.with(|cell| {
+            cell.borrow_mut()
+                .set(config)
+                .expect("failed to set monitor config to stable memory")
+        });
+
+        start_monitor_timer();
+        Ok(())
+    }
+}
+
+/// Returns the current monitor configuration.
+pub fn monitor_config() -> MonitorConfig {
+    MONITOR_CONFIG_CELL.with(|cell| cell.borrow().get().clone())
+}
+
+/// Returns the latest snapshot of the canister.
+pub fn snapshot(canister_id: Principal) -> Option<CanisterSnapshot> {
+    SNAPSHOTS_MAP.with(|map| map.borrow().get(&PrincipalKey(canister_id)))
+}
+
+/// Returns the latest snapshots of all the monitored canisters.
+pub fn snapshots() -> Vec<(Principal, CanisterSnapshot)> {
+    SNAPSHOTS_MAP.with(|map| map.borrow().iter().map(|(k, v)| (k.0, v)).collect())
+}
+
+pub(crate) fn remove_snapshot(canister_id: Principal) {
+    SNAPSHOTS_MAP.with(|map| map.borrow_mut().remove(&PrincipalKey(canister_id)));
+}
+
+pub(crate) fn clear_snapshots() {
+    SNAPSHOTS_MAP.with(|map| map.borrow_mut().clear());
+    MONITOR_CURSOR.with(|cursor| cursor.set(None));
+}
+
+/// Starts (or restarts) the monitor timer according to the stored configuration. If the monitor
+/// is disabled, stops the timer.
+pub fn start_monitor_timer() {
+    #[cfg(target_arch = "wasm32")]
+    {
+        use std::time::Duration;
+
+        use ic_exports::ic_cdk_timers;
+        use ic_storage::IcStorage;
+
+        let config = monitor_config();
+        MONITOR_TIMER.with(|timer| {
+            if let Some(timer_id) = timer.borrow_mut().take() {
+                ic_cdk_timers::clear_timer(timer_id);
+            }
+
+            if config.enabled {
+                let timer_id = ic_cdk_timers::set_timer_interval(
+                    Duration::from_secs(config.interval_secs),
+                    || {
+                        let cmc = crate::CmcConfig::get().borrow().cmc_principal();
+                        ic_exports::ic_cdk::spawn(async move {
+                            run_monitor_batch(cmc).await;
+                        });
+                    },
+                );
+                *timer.borrow_mut() = Some(timer_id);
+            }
+        });
+    }
+}
+
+/// Checks the next batch of the factory canisters, tops them up if needed and stores the
+/// snapshots. Every call continues from the canister the previous call stopped at.
+///
+/// Returns the snapshots of the checked canisters. If another run is in progress, does nothing.
+pub async fn run_monitor_batch(cmc: Principal) -> Vec<(Principal, CanisterSnapshot)> {
+    let now = ic::time();
+    let is_running = MONITOR_RUN_STARTED.with(|started| match started.get() {
+        Some(time) if now.saturating_sub(time) < MONITOR_RUN_TIMEOUT_NANOS => true,
+        _ => {
+            started.set(Some(now));
+            false
+        }
+    });
+
+    if is_running {
+        return vec![];
+    }
+
+    let config = monitor_config();
+    let cursor = MONITOR_CURSOR.with(Cell::get);
+    let batch = next_batch(cursor, config.batch_size as usize);
+    MONITOR_CURSOR.with(|cursor| cursor.set(batch.last().copied()));
+
+    let mut results = Vec::with_capacity(batch.len());
+    for canister_id in batch {
+        let snapshot = check_canister(canister_id, &config, cmc).await;
+
+        // The canister could be dropped while we were waiting for the response.
+        if factory_state().is_registered(canister_id) {
+            SNAPSHOTS_MAP.with(|map| {
+                map.borrow_mut()
+                    .insert(PrincipalKey(canister_id), snapshot.clone())
+            });
+
+            if snapshot.status.is_some() {
+                registry::update_metadata(canister_id, |metadata| {
+                    metadata.last_status = snapshot.status;
+                    metadata.last_status_at = Some(snapshot.timestamp);
+                });
+            }
+        }
+
+        results.push((canister_id, snapshot));
+    }
+
+    MONITOR_RUN_STARTED.with(|started| started.set(None));
+    results
+}
+
+/// Returns up to `size` factory canisters following the `cursor` canister. If the end of the
+/// canister list is reached, starts from the beginning.
+pub(crate) fn next_batch(cursor: Option<Principal>, size: usize) -> Vec<Principal> {
+    let state = factory_state();
+    let batch = state.canisters_after(cursor, size);
+    if batch.is_empty() && cursor.is_some() {
+        state.canisters_after(None, size)
+    } else {
+        batch
+    }
+}
+
+async fn check_canister(
+    canister_id: Principal,
+    config: &MonitorConfig,
+    cmc: Principal,
+) -> CanisterSnapshot {
+    let last_top_up = snapshot(canister_id).and_then(|snapshot| snapshot.last_top_up);
+    let mut snapshot = match canister_id.status().await {
+        Ok(status) => CanisterSnapshot {
+            timestamp: ic::time(),
+            status: Some(status.status),
+            cycles: Tokens128::from_nat(&status.cycles)
+                .map(|cycles| cycles.amount)
+                .unwrap_or(u128::MAX),
+            memory_size: Tokens128::from_nat(&status.memory_size)
+                .and_then(|size| size.to_u64())
+                .unwrap_or(u64::MAX),
+            error: None,
+            last_top_up,
+        },
+        Err((_, e)) => {
+            return CanisterSnapshot {
+                timestamp: ic::time(),
+                status: None,
+                cycles: 0,
+                memory_size: 0,
+                error: Some(truncate_error(e)),
+                last_top_up,
+            }
+        }
+    };
+
+    if config.top_up_amount > 0 && snapshot.cycles < config.threshold as u128 {
+        let result =
+            top_up_canister(canister_id, config.top_up_amount, config.top_up_source, cmc).await;
+
+        snapshot.last_top_up = Some(match result {
+            Ok(cycles) => {
+                snapshot.cycles += cycles as u128;
+                TopUp {
+                    timestamp: ic::time(),
+                    cycles,
+                    error: None,
+                }
+            }
+            Err(e) => TopUp {
+                timestamp: ic::time(),
+                cycles: 0,
+                error: Some(truncate_error(e.to_string())),
+            },
+        });
+    }
+
+    snapshot
+}
+
+/// Sends `cycles` to the canister from the given source. Returns the amount of cycles the canister
+/// received.
+async fn top_up_canister(
+    canister_id: Principal,
+    cycles: u64,
+    source: TopUpSource,
+    cmc: Principal,
+) -> Result<u64, FactoryError> {
+    match source {
+        TopUpSource::FactoryBalance => {
+            // Keep enough cycles in the factory to be able to create canisters.
+            let required = cycles + factory_state().canister_creation_cycle_cost();
+            let balance = ic::balance();
+            if balance < required {
+                return Err(FactoryError::NotEnoughCycles(balance, required));
+            }
+
+            deposit_cycles(canister_id, cycles).await?;
+            Ok(cycles)
+        }
+        TopUpSource::Cmc => {
+            let ledger = factory_state().ledger_principal();
+            let amount =
+                top_up::icp_amount_from_cycles(cmc, cycles).await? + DEFAULT_TRANSFER_FEE.get_e8s();
+            let block_height = top_up::transfer_icp_to_cmc_for(
+                cmc,
+                amount,
+                ledger,
+                Subaccount([0; 32]),
+                canister_id,
+            )
+            .await?;
+
+            let minted = top_up::mint_cycles_to_canister(cmc, block_height, canister_id).await?;
+            Ok(minted as u64)
+        }
+    }
+}
+
+pub(crate) fn truncate_error(mut error: String) -> String {
+    if error.len() > MAX_ERROR_LENGTH {
+        let mut end = MAX_ERROR_LENGTH;
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        error.truncate(end);
+    }
+
+    error
+}
+
+thread_local! {
+    static MONITOR_CONFIG_CELL: RefCell<StableCell<MonitorConfig>> = {
+        RefCell::new(StableCell::new(MONITOR_CONFIG_MEMORY_ID, MonitorConfig::default())
+            .expect("failed to initialize monitor config"))
+    };
+
+    static SNAPSHOTS_MAP: RefCell<StableBTreeMap<PrincipalKey, CanisterSnapshot>> =
+        RefCell::new(StableBTreeMap::new(SNAPSHOTS_MEMORY_ID));
+
+    static MONITOR_CURSOR: Cell<Option<Principal>> = Cell::new(None);
+
+    static MONITOR_RUN_STARTED: Cell<Option<u64>> = Cell::new(None);
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    static MONITOR_TIMER: RefCell<Option<ic_exports::ic_cdk_timers::TimerId>> = RefCell::new(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::state::{CanisterHash, CANISTERS_MAP};
+
+    fn register(count: u8) -> Vec<Principal> {
+        let canisters = (0..count)
+            .map(|i| Principal::from_slice(&[i * 2]))
+            .collect::<Vec<_>>();
+        CANISTERS_MAP.with(|map| {
+            let mut map = map.borrow_mut();
+            for canister in &canisters {
+                map.insert(PrincipalKey(*canister), CanisterHash(vec![]));
+            }
+        });
+
+        canisters
+    }
+
+    #[test]
+    fn next_batch_rotates_over_canisters() {
+        let canisters = register(5);
+
+        let batch = next_batch(None, 2);
+        assert_eq!(batch, canisters[0..2]);
+
+        let batch = next_batch(batch.last().copied(), 2);
+        assert_eq!(batch, canisters[2..4]);
+
+        let batch = next_batch(batch.last().copied(), 2);
+        assert_eq!(batch, canisters[4..5]);
+
+        let batch = next_batch(batch.last().copied(), 2);
+        assert_eq!(batch, canisters[0..2]);
+    }
+
+    #[test]
+    fn next_batch_continues_after_removed_cursor() {
+        let canisters = register(3);
+        let batch = next_batch(Some(Principal::from_slice(&[1])), 2);
+        assert_eq!(batch, canisters[1..3]);
+
+        let batch = next_batch(Some(Principal::from_slice(&[42])), 2);
+        assert_eq!(batch, canisters[0..2]);
+    }
+
+    #[test]
+    fn truncate_long_error() {
+        let error = "ы".repeat(MAX_ERROR_LENGTH);
+        let truncated = truncate_error(error);
+        assert!(truncated.len() <= MAX_ERROR_LENGTH);
+        assert!(truncated.chars().all(|c| c == 'ы'));
+    }
+}
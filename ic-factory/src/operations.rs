@@ -0,0 +1,193 @@
+//! Records of the in-flight factory operations.
+//!
+//! An async factory operation that takes the state update lock with
+//! [`crate::FactoryState::lock_for`] stores a record describing the operation in stable memory.
+//! The record is removed when the lock is released. If the operation is interrupted by a trap after
+//! an `await` point, the lock is never released and the record stays in the memory. After the lock
+//! expires, the operation is considered interrupted, and the factory owners can inspect it and
+//! resume or roll it back. The lock is refreshed at every step of the operation, so it expires only
+//! if no step is started for [`LOCK_TIMEOUT`].
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableCell, Storable};
+
+const OPERATIONS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const OPERATIONS_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(17);
+
+/// Time after which the state update lock expires, in nanoseconds. The time is counted from the
+/// start of the last step of the operation holding the lock.
+pub const LOCK_TIMEOUT: u64 = 15 * 60 * 1_000_000_000;
+
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    CreateCanister,
+    UpgradeCanisters,
+    DropCanister,
+    AdoptCanister,
+    UpdateCanisterSettings,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct OperationRecord {
+    pub kind: OperationKind,
+    pub caller: Principal,
+    /// Canisters the operation works with.
+    pub canisters: Vec<Principal>,
+    /// Id of the creation request for the idempotent canister creation.
+    pub request_id: Option<u64>,
+    /// The last started step of the operation.
+    pub step: Option<String>,
+    /// Time the operation was started in nanoseconds.
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl OperationRecord {
+    pub fn new(kind: OperationKind, caller: Principal, canisters: Vec<Principal>) -> Self {
+        let now = ic::time();
+        Self {
+            kind,
+            caller,
+            canisters,
+            request_id: None,
+            step: None,
+            started_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+impl Storable for OperationRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize operation record")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize operation record")
+    }
+}
+
+impl BoundedStorable for OperationRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct OperationId(u64);
+
+impl Storable for OperationId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_be_bytes().to_vec().into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes);
+        Self(u64::from_be_bytes(buf))
+    }
+}
+
+impl BoundedStorable for OperationId {
+    const MAX_SIZE: u32 = 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Returns the operation record.
+pub fn get(operation_id: u64) -> Option<OperationRecord> {
+    OPERATIONS_MAP.with(|map| map.borrow().get(&OperationId(operation_id)))
+}
+
+/// Returns all the stored operation records, including the record of the running operation.
+pub fn list() -> Vec<(u64, OperationRecord)> {
+    OPERATIONS_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .map(|(id, record)| (id.0, record))
+            .collect()
+    })
+}
+
+/// Stores the record of a started operation. Returns the id of the record.
+pub(crate) fn start(record: OperationRecord) -> u64 {
+    let id = OPERATIONS_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let id = *counter.get();
+        counter
+            .set(OperationId(id.0 + 1))
+            .expect("failed to update operations counter");
+        id
+    });
+
+    OPERATIONS_MAP.with(|map| map.borrow_mut().insert(id, record));
+    id.0
+}
+
+pub(crate) fn set_step(operation_id: u64, step: String) {
+    OPERATIONS_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(mut record) = map.get(&OperationId(operation_id)) {
+            record.step = Some(step);
+            record.updated_at = ic::time();
+            map.insert(OperationId(operation_id), record);
+        }
+    });
+}
+
+/// Removes the operation record.
+pub(crate) fn finish(operation_id: u64) -> Option<OperationRecord> {
+    OPERATIONS_MAP.with(|map| map.borrow_mut().remove(&OperationId(operation_id)))
+}
+
+pub(crate) fn clear() {
+    OPERATIONS_MAP.with(|map| map.borrow_mut().clear());
+}
+
+thread_local! {
+    static OPERATIONS_MAP: RefCell<StableBTreeMap<OperationId, OperationRecord>> =
+        RefCell::new(StableBTreeMap::new(OPERATIONS_MEMORY_ID));
+
+    static OPERATIONS_COUNTER: RefCell<StableCell<OperationId>> = {
+        RefCell::new(StableCell::new(OPERATIONS_COUNTER_MEMORY_ID, OperationId::default())
+            .expect("failed to initialize operations counter"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn records_are_removed_on_finish() {
+        MockContext::new().inject();
+        let caller = Principal::from_slice(&[1]);
+
+        let first = start(OperationRecord::new(
+            OperationKind::CreateCanister,
+            caller,
+            vec![],
+        ));
+        let second = start(OperationRecord::new(
+            OperationKind::DropCanister,
+            caller,
+            vec![Principal::from_slice(&[2])],
+        ));
+        assert_ne!(first, second);
+
+        set_step(second, "stopping".into());
+        assert_eq!(get(second).unwrap().step.as_deref(), Some("stopping"));
+
+        assert!(finish(first).is_some());
+        assert!(finish(first).is_none());
+        let records = list();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1.kind, OperationKind::DropCanister);
+    }
+}
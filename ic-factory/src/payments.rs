@@ -0,0 +1,282 @@
+//! Payment for canister creation with ICRC-1 tokens.
+//!
+//! The factory accepts the tokens set by the [`Billing`] role principals, each with its own price
+//! for canister creation. To pay for a canister, the user transfers tokens to their deposit
+//! interim account in the token (see [`ic_payments::get_deposit_interim_account`]) and then calls
+//! a canister creation method. The factory moves the tokens to its main account, credits them to
+//! the user's balance and charges the price from the balance. The rest of the balance can be
+//! withdrawn by the user at any time.
+//!
+//! The collected payments are stored on the factory's own balance and can be withdrawn by the
+//! `Billing` role principals.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_helpers::tokens::Tokens128;
+use ic_payments::{
+    BalanceError, Balances, PaymentError, StableRecoveryList, TokenConfiguration, TokenTerminal,
+};
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableMultimap, Storable};
+
+use crate::error::FactoryError;
+use crate::state::{Authorized, Billing, PrincipalKey};
+
+const TOKEN_PRICES_MEMORY_ID: MemoryId = MemoryId::new(11);
+const TOKEN_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(12);
+
+/// Memory id of the token terminal recovery list.
+const RECOVERY_LIST_MEMORY_ID: u8 = 13;
+
+pub type FactoryTerminal =
+    TokenTerminal<TokenBalances, StableRecoveryList<RECOVERY_LIST_MEMORY_ID>>;
+
+/// Token accepted by the factory as payment for canister creation.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct TokenPrice {
+    pub config: TokenConfiguration,
+    /// Amount of tokens charged for a canister creation.
+    pub price: Tokens128,
+}
+
+impl Storable for TokenPrice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize token price")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize token price")
+    }
+}
+
+impl BoundedStorable for TokenPrice {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+struct StorableTokens(Tokens128);
+
+impl Storable for StorableTokens {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.amount.to_le_bytes().to_vec().into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut buf = [0u8; 16];
+        buf.copy_from_slice(&bytes);
+        Self(Tokens128::from(u128::from_le_bytes(buf)))
+    }
+}
+
+impl BoundedStorable for StorableTokens {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Balances of the factory users in the given token.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBalances {
+    token: Principal,
+}
+
+impl TokenBalances {
+    pub fn new(token: Principal) -> Self {
+        Self { token }
+    }
+
+    /// Returns the balance of the account owner.
+    pub fn balance_of(&self, account_owner: Principal) -> Tokens128 {
+        TOKEN_BALANCES
+            .with(|map| {
+                map.borrow()
+                    .get(&PrincipalKey(self.token), &PrincipalKey(account_owner))
+            })
+            .map(|balance| balance.0)
+            .unwrap_or_default()
+    }
+
+    fn set_balance(&self, account_owner: Principal, balance: Tokens128) {
+        TOKEN_BALANCES.with(|map| {
+            let mut map = map.borrow_mut();
+            let (token, owner) = (PrincipalKey(self.token), PrincipalKey(account_owner));
+            if balance == Tokens128::ZERO {
+                map.remove(&token, &owner);
+            } else {
+                map.insert(&token, &owner, &StorableTokens(balance));
+            }
+        });
+    }
+}
+
+impl Balances for TokenBalances {
+    fn credit(
+        &mut self,
+        account_owner: Principal,
+        amount: Tokens128,
+    ) -> Result<Tokens128, BalanceError> {
+        let balance = (self.balance_of(account_owner) + amount)
+            .ok_or_else(|| BalanceError::Fatal("balance overflow".into()))?;
+        self.set_balance(account_owner, balance);
+        Ok(balance)
+    }
+
+    fn debit(
+        &mut self,
+        account_owner: Principal,
+        amount: Tokens128,
+    ) -> Result<Tokens128, BalanceError> {
+        let balance =
+            (self.balance_of(account_owner) - amount).ok_or(BalanceError::InsufficientFunds)?;
+        self.set_balance(account_owner, balance);
+        Ok(balance)
+    }
+}
+
+impl Authorized<Billing> {
+    /// Sets the canister creation price in the given token. If the token was not accepted before,
+    /// it is added to the list of accepted tokens.
+    pub fn set_token_price(
+        &mut self,
+        config: TokenConfiguration,
+        price: Tokens128,
+    ) -> Result<(), FactoryError> {
+        TOKEN_PRICES.with(|map| {
+            map.borrow_mut()
+                .insert(PrincipalKey(config.principal), TokenPrice { config, price })
+        });
+        Ok(())
+    }
+
+    /// Removes the token from the list of accepted tokens. The users still can withdraw their
+    /// balances in this token.
+    pub fn remove_token_price(&mut self, token: Principal) -> Result<(), FactoryError> {
+        TOKEN_PRICES
+            .with(|map| map.borrow_mut().remove(&PrincipalKey(token)))
+            .map(|_| ())
+            .ok_or(FactoryError::NotFound)
+    }
+
+    /// Moves `amount` of the collected payments in the token to the `to` principal balance, from
+    /// which it can be withdrawn.
+    pub fn take_revenue(
+        &mut self,
+        token: Principal,
+        to: Principal,
+        amount: Tokens128,
+    ) -> Result<(), FactoryError> {
+        let mut balances = TokenBalances::new(token);
+        balances
+            .debit(ic::id(), amount)
+            .map_err(|e| FactoryError::PaymentError(e.to_string()))?;
+        balances
+            .credit(to, amount)
+            .map_err(|e| FactoryError::PaymentError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+/// Returns the canister creation price in the token, or `None` if the token is not accepted.
+pub fn token_price(token: Principal) -> Option<TokenPrice> {
+    TOKEN_PRICES.with(|map| map.borrow().get(&PrincipalKey(token)))
+}
+
+/// Returns the prices of all the accepted tokens.
+pub fn token_prices() -> Vec<TokenPrice> {
+    TOKEN_PRICES.with(|map| map.borrow().iter().map(|(_, price)| price).collect())
+}
+
+/// Returns a token terminal to work with the given token.
+///
+/// # Errors
+///
+/// Returns `FactoryError::NotFound` if the token is not accepted by the factory.
+pub fn terminal(token: Principal) -> Result<FactoryTerminal, FactoryError> {
+    let config = token_price(token).ok_or(FactoryError::NotFound)?.config;
+    Ok(
+        FactoryTerminal::new(config, TokenBalances::new(token)).on_config_update(|config| {
+            TOKEN_PRICES.with(|map| {
+                let mut map = map.borrow_mut();
+                if let Some(mut price) = map.get(&PrincipalKey(config.principal)) {
+                    price.config = config.clone();
+                    map.insert(PrincipalKey(config.principal), price);
+                }
+            })
+        }),
+    )
+}
+
+/// Deposits the tokens from the caller's deposit interim account to the caller's balance and
+/// charges the canister creation price from the balance. Returns the charged amount.
+pub(crate) async fn charge(caller: Principal, token: Principal) -> Result<Tokens128, FactoryError> {
+    let mut terminal = terminal(token)?;
+    match terminal.deposit_all(caller).await {
+        // Invalid parameters means that the deposit account balance is too small to make a
+        // transfer, so we just use the current caller balance.
+        Ok(_) | Err(PaymentError::InvalidParameters(_)) => {}
+        Err(e) => return Err(FactoryError::PaymentError(e.to_string())),
+    }
+
+    let price = token_price(token).ok_or(FactoryError::NotFound)?.price;
+    let mut balances = TokenBalances::new(token);
+    balances.debit(caller, price).map_err(|e| match e {
+        BalanceError::InsufficientFunds => {
+            FactoryError::NotEnoughTokens(balances.balance_of(caller).amount, price.amount)
+        }
+        BalanceError::Fatal(e) => FactoryError::PaymentError(e),
+    })?;
+    balances
+        .credit(ic::id(), price)
+        .map_err(|e| FactoryError::PaymentError(e.to_string()))?;
+
+    Ok(price)
+}
+
+/// Returns the charged `amount` back to the caller balance.
+pub(crate) fn refund(caller: Principal, token: Principal, amount: Tokens128) {
+    let mut balances = TokenBalances::new(token);
+    balances
+        .debit(ic::id(), amount)
+        .and_then(|_| balances.credit(caller, amount))
+        .expect("charged amount must be refundable");
+}
+
+thread_local! {
+    static TOKEN_PRICES: RefCell<StableBTreeMap<PrincipalKey, TokenPrice>> =
+        RefCell::new(StableBTreeMap::new(TOKEN_PRICES_MEMORY_ID));
+
+    static TOKEN_BALANCES: RefCell<StableMultimap<PrincipalKey, PrincipalKey, StorableTokens>> =
+        RefCell::new(StableMultimap::new(TOKEN_BALANCES_MEMORY_ID));
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn credit_and_debit_balances() {
+        MockContext::new().inject();
+        let token = Principal::from_slice(&[1]);
+        let user = Principal::from_slice(&[2]);
+        let mut balances = TokenBalances::new(token);
+
+        assert_eq!(balances.credit(user, 100.into()), Ok(100.into()));
+        assert_eq!(balances.credit(user, 50.into()), Ok(150.into()));
+        assert_eq!(balances.debit(user, 150.into()), Ok(Tokens128::ZERO));
+        assert_eq!(
+            balances.debit(user, 1.into()),
+            Err(BalanceError::InsufficientFunds)
+        );
+
+        let other_token = TokenBalances::new(Principal::from_slice(&[3]));
+        balances.credit(user, 10.into()).unwrap();
+        assert_eq!(other_token.balance_of(user), Tokens128::ZERO);
+        assert_eq!(balances.balance_of(user), 10.into());
+    }
+}
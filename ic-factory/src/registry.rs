@@ -0,0 +1,370 @@
+//! Metadata of the factory canisters and paginated canister listing.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_helpers::management::CanisterStatusKind;
+use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, Storable};
+
+use crate::state::{CanisterHash, PrincipalKey, CANISTERS_MAP};
+
+const METADATA_MEMORY_ID: MemoryId = MemoryId::new(10);
+
+/// Maximum number of canisters returned by one [`list`] call.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Maximum number of canisters checked by one [`list`] call.
+const MAX_SCANNED_CANISTERS: usize = 1000;
+
+/// Information about a factory canister stored alongside its module hash.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct CanisterMetadata {
+    /// The principal that created the canister. `None` for canisters added with
+    /// `register_existing`.
+    pub creator: Option<Principal>,
+    /// The additional controller of the canister set on creation.
+    pub owner: Option<Principal>,
+    /// Time the canister was added to the factory in nanoseconds.
+    pub created_at: u64,
+    /// Version of the module the canister runs.
+    pub module_version: Option<u32>,
+    pub last_upgraded_at: Option<u64>,
+    /// Last status of the canister reported by the cycles monitor.
+    pub last_status: Option<CanisterStatusKind>,
+    pub last_status_at: Option<u64>,
+}
+
+impl CanisterMetadata {
+    pub(crate) fn new(
+        creator: Option<Principal>,
+        owner: Option<Principal>,
+        module_version: Option<u32>,
+    ) -> Self {
+        Self {
+            creator,
+            owner,
+            created_at: ic::time(),
+            module_version,
+            last_upgraded_at: None,
+            last_status: None,
+            last_status_at: None,
+        }
+    }
+}
+
+impl Storable for CanisterMetadata {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize canister metadata")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize canister metadata")
+    }
+}
+
+impl BoundedStorable for CanisterMetadata {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Result of adopting an existing canister by the factory.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct AdoptedCanister {
+    pub canister_id: Principal,
+    /// Hash of the module the canister runs after the adoption.
+    pub module_hash: Vec<u8>,
+    /// Version of the factory module the canister runs. `None` if the canister runs a module
+    /// different from the current factory module.
+    pub module_version: Option<u32>,
+    /// `true` if the canister was upgraded to the current factory module.
+    pub upgraded: bool,
+}
+
+/// Canister listing request. Only canisters matching all the set filters are returned.
+#[derive(Debug, Default, CandidType, Deserialize, Clone)]
+pub struct CanisterListRequest {
+    /// Return canisters following this one. Use the `next_cursor` value of the previous page.
+    pub cursor: Option<Principal>,
+    /// Maximum number of canisters to return. Limited by `MAX_PAGE_SIZE`. Zero is treated as one.
+    pub limit: u32,
+    pub module_hash: Option<Vec<u8>>,
+    pub module_version: Option<u32>,
+    pub owner: Option<Principal>,
+    /// Return only canisters created at this time or later.
+    pub created_after: Option<u64>,
+    /// Return only canisters created before this time.
+    pub created_before: Option<u64>,
+}
+
+impl CanisterListRequest {
+    fn matches(&self, hash: &CanisterHash, metadata: Option<&CanisterMetadata>) -> bool {
+        if matches!(&self.module_hash, Some(module_hash) if *module_hash != hash.0) {
+            return false;
+        }
+
+        let needs_metadata = self.module_version.is_some()
+            || self.owner.is_some()
+            || self.created_after.is_some()
+            || self.created_before.is_some();
+
+        match metadata {
+            None => !needs_metadata,
+            Some(metadata) => {
+                self.module_version
+                    .map_or(true, |version| metadata.module_version == Some(version))
+                    && self
+                        .owner
+                        .map_or(true, |owner| metadata.owner == Some(owner))
+                    && self
+                        .created_after
+                        .map_or(true, |time| metadata.created_at >= time)
+                    && self
+                        .created_before
+                        .map_or(true, |time| metadata.created_at < time)
+            }
+        }
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct CanisterEntry {
+    pub canister_id: Principal,
+    pub module_hash: Vec<u8>,
+    /// `None` for canisters registered before the metadata was introduced.
+    pub metadata: Option<CanisterMetadata>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct CanisterListPage {
+    pub canisters: Vec<CanisterEntry>,
+    /// Cursor to request the next page with. `None` if there are no more canisters.
+    pub next_cursor: Option<Principal>,
+}
+
+/// Returns a page of the factory canisters matching the request filters, ordered by principal.
+///
+/// To limit the amount of work, at most `MAX_SCANNED_CANISTERS` canisters are checked by one call,
+/// so the returned page can contain less than `limit` canisters even if there are more matching
+/// canisters. Use the returned `next_cursor` to continue the listing.
+pub fn list(request: &CanisterListRequest) -> CanisterListPage {
+    let limit = request.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    CANISTERS_MAP.with(|map| {
+        let map = map.borrow();
+        let mut canisters = vec![];
+        let mut last_scanned = None;
+        let mut has_more = false;
+
+        let start = match request.cursor {
+            Some(cursor) => Bound::Excluded(PrincipalKey(cursor)),
+            None => Bound::Unbounded,
+        };
+        let iter = map.range((start, Bound::Unbounded));
+
+        for (scanned, (key, hash)) in iter.enumerate() {
+            if canisters.len() >= limit || scanned >= MAX_SCANNED_CANISTERS {
+                has_more = true;
+                break;
+            }
+
+            let metadata = metadata(key.0);
+            if request.matches(&hash, metadata.as_ref()) {
+                canisters.push(CanisterEntry {
+                    canister_id: key.0,
+                    module_hash: hash.0,
+                    metadata,
+                });
+            }
+
+            last_scanned = Some(key.0);
+        }
+
+        CanisterListPage {
+            canisters,
+            next_cursor: if has_more { last_scanned } else { None },
+        }
+    })
+}
+
+/// Returns the metadata of the canister.
+pub fn metadata(canister_id: Principal) -> Option<CanisterMetadata> {
+    METADATA_MAP.with(|map| map.borrow().get(&PrincipalKey(canister_id)))
+}
+
+pub(crate) fn insert_metadata(canister_id: Principal, metadata: CanisterMetadata) {
+    METADATA_MAP.with(|map| map.borrow_mut().insert(PrincipalKey(canister_id), metadata));
+}
+
+/// Applies `f` to the canister metadata if the canister has one.
+pub(crate) fn update_metadata<F>(canister_id: Principal, f: F)
+where
+    F: FnOnce(&mut CanisterMetadata),
+{
+    METADATA_MAP.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(mut metadata) = map.get(&PrincipalKey(canister_id)) {
+            f(&mut metadata);
+            map.insert(PrincipalKey(canister_id), metadata);
+        }
+    });
+}
+
+pub(crate) fn remove_metadata(canister_id: Principal) {
+    METADATA_MAP.with(|map| map.borrow_mut().remove(&PrincipalKey(canister_id)));
+}
+
+pub(crate) fn clear() {
+    METADATA_MAP.with(|map| map.borrow_mut().clear());
+}
+
+thread_local! {
+    static METADATA_MAP: RefCell<StableBTreeMap<PrincipalKey, CanisterMetadata>> =
+        RefCell::new(StableBTreeMap::new(METADATA_MEMORY_ID));
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    fn canister(i: u16) -> Principal {
+        Principal::from_slice(&i.to_be_bytes())
+    }
+
+    fn add_canister(i: u16, hash: &[u8], metadata: Option<CanisterMetadata>) {
+        CANISTERS_MAP.with(|map| {
+            map.borrow_mut()
+                .insert(PrincipalKey(canister(i)), CanisterHash(hash.to_vec()))
+        });
+        if let Some(metadata) = metadata {
+            insert_metadata(canister(i), metadata);
+        }
+    }
+
+    fn owned_metadata(owner: Principal, created_at: u64, module_version: u32) -> CanisterMetadata {
+        CanisterMetadata {
+            creator: None,
+            owner: Some(owner),
+            created_at,
+            module_version: Some(module_version),
+            last_upgraded_at: None,
+            last_status: None,
+            last_status_at: None,
+        }
+    }
+
+    fn ids(page: &CanisterListPage) -> Vec<Principal> {
+        page.canisters
+            .iter()
+            .map(|entry| entry.canister_id)
+            .collect()
+    }
+
+    #[test]
+    fn pages_follow_cursor() {
+        MockContext::new().inject();
+        for i in 0..5 {
+            add_canister(i, &[1], None);
+        }
+
+        let mut request = CanisterListRequest {
+            limit: 2,
+            ..Default::default()
+        };
+        let page = list(&request);
+        assert_eq!(ids(&page), vec![canister(0), canister(1)]);
+        assert_eq!(page.next_cursor, Some(canister(1)));
+
+        request.cursor = page.next_cursor;
+        let page = list(&request);
+        assert_eq!(ids(&page), vec![canister(2), canister(3)]);
+
+        request.cursor = page.next_cursor;
+        let page = list(&request);
+        assert_eq!(ids(&page), vec![canister(4)]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn scan_stops_at_limit() {
+        MockContext::new().inject();
+        let scanned = MAX_SCANNED_CANISTERS as u16;
+        for i in 0..scanned {
+            add_canister(i, &[1], None);
+        }
+        add_canister(scanned, &[2], None);
+
+        let mut request = CanisterListRequest {
+            limit: 10,
+            module_hash: Some(vec![2]),
+            ..Default::default()
+        };
+        let page = list(&request);
+        assert!(page.canisters.is_empty());
+        assert_eq!(page.next_cursor, Some(canister(scanned - 1)));
+
+        request.cursor = page.next_cursor;
+        let page = list(&request);
+        assert_eq!(ids(&page), vec![canister(scanned)]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn filters_are_applied() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[0xa1]);
+        let bob = Principal::from_slice(&[0xb0]);
+        add_canister(0, &[1], Some(owned_metadata(alice, 100, 1)));
+        add_canister(1, &[2], Some(owned_metadata(bob, 200, 2)));
+        add_canister(2, &[1], Some(owned_metadata(alice, 300, 2)));
+        add_canister(3, &[1], None);
+
+        let filtered = |request: CanisterListRequest| {
+            ids(&list(&CanisterListRequest {
+                limit: MAX_PAGE_SIZE,
+                ..request
+            }))
+        };
+
+        assert_eq!(
+            filtered(CanisterListRequest {
+                module_hash: Some(vec![1]),
+                ..Default::default()
+            }),
+            vec![canister(0), canister(2), canister(3)]
+        );
+        assert_eq!(
+            filtered(CanisterListRequest {
+                module_version: Some(2),
+                ..Default::default()
+            }),
+            vec![canister(1), canister(2)]
+        );
+        assert_eq!(
+            filtered(CanisterListRequest {
+                owner: Some(alice),
+                ..Default::default()
+            }),
+            vec![canister(0), canister(2)]
+        );
+        assert_eq!(
+            filtered(CanisterListRequest {
+                created_after: Some(200),
+                ..Default::default()
+            }),
+            vec![canister(1), canister(2)]
+        );
+        assert_eq!(
+            filtered(CanisterListRequest {
+                created_before: Some(200),
+                ..Default::default()
+            }),
+            vec![canister(0)]
+        );
+    }
+}
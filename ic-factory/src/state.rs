@@ -1,7 +1,8 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::ops::Bound;
 
 use candid::{Decode, Encode};
 use ic_canister::virtual_canister_call;
@@ -14,14 +15,29 @@ use ic_exports::ledger::{
 };
 use ic_exports::{ic_kit, BlockHeight};
 use ic_helpers::ledger::LedgerPrincipalExt;
+use ic_helpers::management::CanisterSettings;
 use ic_stable_structures::{BoundedStorable, MemoryId, StableBTreeMap, StableCell, Storable};
 use ic_storage::IcStorage;
 
-use crate::core::{create_canister, drop_canister, upgrade_canister};
+use crate::acl::{self, Role};
+use crate::audit::{self, Operation};
+use crate::controllers;
+use crate::core::{
+    canister_cycles, controlled_canister_cycles, create_canister_with_settings,
+    create_empty_canister, delete_canister, drop_canister, finalize_canister, install_encoded,
+    install_withdrawal_module, start_canister, stop_canister, uninstall_canister,
+    update_canister_settings, upgrade_canister, withdraw_cycles,
+};
+use crate::creation;
+use crate::dropping::{self, DropOptions, DropStep};
 use crate::error::FactoryError;
+use crate::monitor;
+use crate::operations::{self, OperationRecord};
+use crate::registry::{self, CanisterMetadata};
 use crate::top_up::{self, CYCLES_MINTING_CANISTER};
 use crate::update_lock::UpdateLock;
 
+pub mod backup;
 pub mod v1;
 
 pub const DEFAULT_ICP_FEE: u64 = 10u64.pow(8) * 2;
@@ -130,6 +146,15 @@ impl FactoryState {
             map.borrow_mut().clear();
         });
 
+        monitor::clear_snapshots();
+        controllers::clear();
+        acl::clear();
+        audit::clear();
+        registry::clear();
+        creation::clear();
+        dropping::clear();
+        operations::clear();
+
         UPDATE_LOCK.with(|lock| lock.replace(UpdateLock::default()));
     }
 
@@ -137,10 +162,9 @@ impl FactoryState {
     ///
     /// # Errors
     ///
-    /// Returns `FactoryError::AccessDenied` if the caller is not the factory controller.
+    /// Returns `FactoryError::AccessDenied` if the caller is not the factory owner.
     pub fn check_is_owner(&mut self) -> Result<Authorized<Owner>, FactoryError> {
-        let caller = ic_exports::ic_kit::ic::caller();
-        self.check_is_owner_internal(caller)
+        self.check_role()
     }
 
     /// This is needed to deal with ic peculiarity, where we cannot call `ic_cdk::caller()`
@@ -153,13 +177,51 @@ impl FactoryState {
         &mut self,
         caller: Principal,
     ) -> Result<Authorized<Owner>, FactoryError> {
-        if with_config(|cfg| cfg.controller == caller) {
-            Ok(Authorized::<Owner> { _auth: Owner {} })
+        self.check_role_internal(caller)
+    }
+
+    /// Checks if the request caller has the role `R`. Factory owners pass all the role checks.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::AccessDenied` if the caller doesn't have the role.
+    pub fn check_role<R: AccessRole>(&mut self) -> Result<Authorized<R>, FactoryError> {
+        let caller = ic_exports::ic_kit::ic::caller();
+        self.check_role_internal(caller)
+    }
+
+    /// Same as [`check_role`], but with the explicitly given caller. See
+    /// [`check_is_owner_internal`] for the reasons this is needed.
+    pub fn check_role_internal<R: AccessRole>(
+        &mut self,
+        caller: Principal,
+    ) -> Result<Authorized<R>, FactoryError> {
+        if self.has_role(caller, R::ROLE) {
+            Ok(Authorized::<R> {
+                _auth: R::default(),
+            })
         } else {
             Err(FactoryError::AccessDenied)
         }
     }
 
+    /// Returns `true` if the principal has the role. The factory controller and principals with
+    /// the `Owner` role have all the roles, see [`Role::includes`] for the other implied roles.
+    pub fn has_role(&self, principal: Principal, role: Role) -> bool {
+        principal == self.controller()
+            || acl::roles(principal)
+                .into_iter()
+                .any(|granted| granted.includes(role))
+    }
+
+    /// Returns all the roles the principal has, including the implied ones.
+    pub fn roles(&self, principal: Principal) -> Vec<Role> {
+        Role::ALL
+            .into_iter()
+            .filter(|role| self.has_role(principal, *role))
+            .collect()
+    }
+
     /// Returns the controller (owner) of the factory.
     pub fn controller(&self) -> Principal {
         with_config(|cfg| cfg.controller)
@@ -200,6 +262,114 @@ impl FactoryState {
         update_config(|cfg| cfg.icp_to = to);
     }
 
+    /// Returns the amount of cycles transferred to a newly created canister.
+    pub fn initial_canister_cycles(&self) -> u64 {
+        with_config(|cfg| {
+            cfg.initial_canister_cycles
+                .unwrap_or(INITIAL_CANISTER_CYCLES)
+        })
+    }
+
+    /// Returns the amount of cycles the factory charges to cover its canister creation expenses.
+    pub fn canister_creation_cycle_cost(&self) -> u64 {
+        with_config(|cfg| {
+            cfg.canister_creation_cycle_cost
+                .unwrap_or(CANISTER_CREATION_CYCLE_COST)
+        })
+    }
+
+    /// Returns the total amount of cycles required to create a canister.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the amount overflows `u64`.
+    pub fn creation_cycles_required(&self) -> Result<u64, FactoryError> {
+        self.pricing(None)?.cycles_required()
+    }
+
+    /// Returns the pricing tiers the canisters can be created with.
+    pub fn pricing_tiers(&self) -> Vec<PricingTier> {
+        with_config(|cfg| cfg.pricing_tiers.clone().unwrap_or_default())
+    }
+
+    /// Returns the cycles and fees of canister creation with the given pricing tier. If `tier` is
+    /// `None`, the default factory configuration is used.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if there is no tier with the given name.
+    pub fn pricing(&self, tier: Option<&str>) -> Result<CreationPricing, FactoryError> {
+        let creation_cost = self.canister_creation_cycle_cost();
+        match tier {
+            None => Ok(CreationPricing {
+                initial_cycles: self.initial_canister_cycles(),
+                creation_cost,
+                icp_fee: self.icp_fee(),
+            }),
+            Some(name) => self
+                .pricing_tiers()
+                .into_iter()
+                .find(|tier| tier.name == name)
+                .map(|tier| CreationPricing {
+                    initial_cycles: tier.initial_cycles,
+                    creation_cost,
+                    icp_fee: tier.icp_fee,
+                })
+                .ok_or_else(|| {
+                    FactoryError::GenericError(format!("pricing tier {name} not found"))
+                }),
+        }
+    }
+
+    /// Adds the pricing tier or replaces the tier with the same name.
+    fn set_pricing_tier(&mut self, tier: PricingTier) -> Result<(), FactoryError> {
+        self.update_pricing(|cfg| {
+            let tiers = cfg.pricing_tiers.get_or_insert_with(Vec::new);
+            match tiers.iter_mut().find(|existing| existing.name == tier.name) {
+                Some(existing) => *existing = tier,
+                None => tiers.push(tier),
+            }
+        })
+    }
+
+    /// Removes the pricing tier. Returns `false` if there is no tier with the given name.
+    fn remove_pricing_tier(&mut self, name: &str) -> bool {
+        let tiers_count = self.pricing_tiers().len();
+        update_config(|cfg| {
+            if let Some(tiers) = &mut cfg.pricing_tiers {
+                tiers.retain(|tier| tier.name != name);
+            }
+        });
+
+        self.pricing_tiers().len() != tiers_count
+    }
+
+    /// Sets the canister creation cycles configuration.
+    fn set_creation_cycles(
+        &mut self,
+        initial_cycles: u64,
+        creation_cost: u64,
+    ) -> Result<(), FactoryError> {
+        self.update_pricing(|cfg| {
+            cfg.initial_canister_cycles = Some(initial_cycles);
+            cfg.canister_creation_cycle_cost = Some(creation_cost);
+        })
+    }
+
+    /// Applies `f` to a copy of the factory configuration, and stores the updated configuration if
+    /// its pricing is valid. See [`FactoryConfiguration::validate_pricing`].
+    fn update_pricing<F>(&mut self, f: F) -> Result<(), FactoryError>
+    where
+        F: FnOnce(&mut FactoryConfiguration),
+    {
+        let mut config = with_config(|cfg| cfg.clone());
+        f(&mut config);
+        config.validate_pricing()?;
+        update_config(|cfg| *cfg = config.clone());
+
+        Ok(())
+    }
+
     /// Creates a new canister with the wasm code stored in the factory state.
     ///
     /// Arguments:
@@ -235,17 +405,81 @@ impl FactoryState {
         cycles: u64,
         lock: &UpdateLock,
         controller: Option<Principal>,
+    ) -> Result<impl Future<Output = CallResult<Principal>>, FactoryError> {
+        let settings = CanisterSettings {
+            controllers: controller.map(|p| vec![p]),
+            ..Default::default()
+        };
+
+        self.create_canister_with_settings(init_args, cycles, lock, settings)
+    }
+
+    /// Creates a new canister with the wasm code stored in the factory state and the given
+    /// canister settings.
+    ///
+    /// This method works in the same way as [`create_canister`], see its documentation for the
+    /// details. If the `settings.controllers` list is set, the factory principal is added to it
+    /// in case it's not there, so the factory never loses control over its canisters.
+    pub(crate) fn create_canister_with_settings<A: ArgumentEncoder + Send>(
+        &self,
+        init_args: A,
+        cycles: u64,
+        lock: &UpdateLock,
+        settings: CanisterSettings,
     ) -> Result<impl Future<Output = CallResult<Principal>>, FactoryError> {
         self.check_lock(lock);
 
-        Ok(create_canister(
+        Ok(create_canister_with_settings(
             self.module()?.wasm,
             init_args,
             cycles,
-            controller.map(|p| vec![ic_exports::ic_kit::ic::id(), p]),
+            with_factory_controller(settings),
         ))
     }
 
+    /// Creates a new canister without installing the module to it. The canister is created with
+    /// the given settings, and the factory principal is added to the controllers in the same way
+    /// as in [`create_canister_with_settings`].
+    ///
+    /// This is the first step of the resumable canister creation, the second one is
+    /// [`install_created`]. Both steps work in the same way as [`create_canister`], see its
+    /// documentation for the details.
+    ///
+    /// # Panics
+    ///
+    /// If the given lock is not the factory's lock. This should never happen if the factory code
+    /// is written correctly.
+    pub(crate) fn create_empty_canister(
+        &self,
+        cycles: u64,
+        lock: &UpdateLock,
+        settings: CanisterSettings,
+    ) -> impl Future<Output = CallResult<Principal>> {
+        self.check_lock(lock);
+        create_empty_canister(cycles, with_factory_controller(settings))
+    }
+
+    /// Installs the wasm code stored in the factory state to the canister created with
+    /// [`create_empty_canister`]. `init_args` are the candid encoded canister init arguments.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::CanisterWasmNotSet` if the canister code is not set.
+    ///
+    /// # Panics
+    ///
+    /// If the given lock is not the factory's lock. This should never happen if the factory code
+    /// is written correctly.
+    pub(crate) fn install_created(
+        &self,
+        canister_id: Principal,
+        init_args: Vec<u8>,
+        lock: &UpdateLock,
+    ) -> Result<impl Future<Output = CallResult<()>>, FactoryError> {
+        self.check_lock(lock);
+        Ok(install_encoded(canister_id, self.module()?.wasm, init_args))
+    }
+
     /// Writes a new canister to the list of the factory canisters. It assumes that the canister
     /// was created with the wasm that is currently in the `FactoryState::module` field.
     ///
@@ -260,12 +494,18 @@ impl FactoryState {
     pub(crate) fn register_created(
         &mut self,
         canister_id: Principal,
+        creator: Principal,
+        owner: Option<Principal>,
         lock: &UpdateLock,
     ) -> Result<(), FactoryError> {
         self.check_lock(lock);
 
-        let hash = self.module()?.hash;
-        self.insert_canister(canister_id, hash);
+        let module = self.module()?;
+        self.insert_canister(canister_id, module.hash);
+        registry::insert_metadata(
+            canister_id,
+            CanisterMetadata::new(Some(creator), owner, Some(module.version)),
+        );
 
         Ok(())
     }
@@ -275,9 +515,18 @@ impl FactoryState {
     }
 
     fn remove_canister(&mut self, canister_id: Principal) -> Option<CanisterHash> {
+        monitor::remove_snapshot(canister_id);
+        controllers::remove_report(canister_id);
+        registry::remove_metadata(canister_id);
+        dropping::remove(canister_id);
         CANISTERS_MAP.with(|map| map.borrow_mut().remove(&PrincipalKey(canister_id)))
     }
 
+    /// Returns `true` if the canister is in the list of the factory canisters.
+    pub fn is_registered(&self, canister_id: Principal) -> bool {
+        CANISTERS_MAP.with(|map| map.borrow().get(&PrincipalKey(canister_id)).is_some())
+    }
+
     /// Returns information about the wasm code the factory uses to create canisters.
     pub fn module(&self) -> Result<CanisterModule, FactoryError> {
         UPGRADING_MODULE_CELL
@@ -304,6 +553,26 @@ impl FactoryState {
         CANISTERS_MAP.with(|map| map.borrow().iter().map(|(k, _)| k.0).collect())
     }
 
+    /// Returns up to `limit` canisters following the `cursor` canister in the principal order, or
+    /// the first canisters if `cursor` is `None`.
+    pub(crate) fn canisters_after(
+        &self,
+        cursor: Option<Principal>,
+        limit: usize,
+    ) -> Vec<Principal> {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(PrincipalKey(cursor)),
+            None => Bound::Unbounded,
+        };
+        CANISTERS_MAP.with(|map| {
+            map.borrow()
+                .range((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(k, _)| k.0)
+                .collect()
+        })
+    }
+
     /// HashMap of canisters the factory keeps track of with their code hashes.
     pub fn canisters(&self) -> HashMap<Principal, CanisterHash> {
         CANISTERS_MAP.with(|map| map.borrow().iter().map(|(k, v)| (k.0, v)).collect())
@@ -311,8 +580,38 @@ impl FactoryState {
 
     /// Locks the `FactoryState`, prohibiting any update operations on it until the returned lock
     /// object is dropped. See [`UpdateLock`] documentation for more details about how and why this works.
+    ///
+    /// If the state is locked for more than [`operations::LOCK_TIMEOUT`], the lock is considered
+    /// expired and is taken over.
     pub fn lock(&mut self) -> Result<UpdateLock, FactoryError> {
-        UPDATE_LOCK.with(|lock| lock.borrow_mut().lock())
+        let now = ic_kit::ic::time();
+        UPDATE_LOCK.with(|lock| {
+            lock.borrow_mut()
+                .lock_at(now, Some(operations::LOCK_TIMEOUT))
+        })
+    }
+
+    /// Same as [`lock`], but also stores the record of the operation the lock is taken for. The
+    /// record is removed when the lock is dropped. See [`crate::operations`] for the details.
+    pub fn lock_for(&mut self, operation: OperationRecord) -> Result<UpdateLock, FactoryError> {
+        let lock = self.lock()?;
+        let operation_id = operations::start(operation);
+        Ok(lock.with_operation(operation_id))
+    }
+
+    /// Returns the records of the operations that were interrupted without releasing the state
+    /// lock, or which lock has expired.
+    pub fn interrupted_operations(&self) -> Vec<(u64, OperationRecord)> {
+        let running = UPDATE_LOCK.with(|lock| {
+            let lock = lock.borrow();
+            let expired = lock.is_expired(ic_kit::ic::time(), Some(operations::LOCK_TIMEOUT));
+            lock.operation_id().filter(|_| !expired)
+        });
+
+        operations::list()
+            .into_iter()
+            .filter(|(id, _)| Some(*id) != running)
+            .collect()
     }
 
     /// Locks the `FactoryState`, prohibiting any update operations on it until the returned lock
@@ -322,7 +621,13 @@ impl FactoryState {
     }
 
     fn check_update_allowed(&self) -> Result<(), FactoryError> {
-        match UPDATE_LOCK.with(|lock| lock.borrow().is_locked()) {
+        let now = ic_kit::ic::time();
+        let is_locked = UPDATE_LOCK.with(|lock| {
+            let lock = lock.borrow();
+            lock.is_locked() && !lock.is_expired(now, Some(operations::LOCK_TIMEOUT))
+        });
+
+        match is_locked {
             true => Err(FactoryError::StateLocked),
             false => Ok(()),
         }
@@ -333,45 +638,148 @@ impl FactoryState {
     // this check is to guard against creating a completely different `UpdateLock` object and
     // giving it to a factory method. In such case we simply panic to make it clear that the code
     // that did such a thing is broken and must be fixed.
+    //
+    // If the lock has expired and was taken over by another operation, we panic as well, so the
+    // operation that lost the lock is aborted instead of changing the state concurrently with the
+    // new lock owner.
     fn check_lock(&self, lock: &UpdateLock) {
         UPDATE_LOCK.with(|inner_lock| {
             assert_eq!(*inner_lock.borrow(), *lock, "invalid update lock usage")
         });
+        assert!(lock.is_held(), "update lock has expired");
     }
 
     /// Consumes the fee for canister creation in the form of cycles (if provided by the call) or
     /// ICP in other case. Returns an error in case nor cycles nor ICP are provided and the caller
     /// is not the factory controller.
+    ///
+    /// The provided cycles are only checked by this method. Call [`accept_creation_cycles`] after
+    /// the canister is created to accept them, so that they are refunded to the caller if the
+    /// creation fails.
     pub fn consume_provided_cycles_or_icp(
         &self,
         caller: Principal,
         cmc: Principal,
+    ) -> impl Future<Output = Result<u64, FactoryError>> {
+        let pricing = self
+            .pricing(None)
+            .expect("default pricing is always available");
+        self.consume_creation_fee(caller, cmc, pricing)
+    }
+
+    /// Same as [`consume_provided_cycles_or_icp`], but with the cycles and fees of the given
+    /// pricing.
+    pub fn consume_creation_fee(
+        &self,
+        caller: Principal,
+        cmc: Principal,
+        pricing: CreationPricing,
     ) -> impl Future<Output = Result<u64, FactoryError>> {
         let ledger = self.ledger_principal();
         let icp_to = self.icp_to();
-        let icp_fee = self.icp_fee();
+        let icp_fee = pricing.icp_fee;
         let controller = self.controller();
+        let initial_cycles = pricing.initial_cycles;
+        let required_cycles = pricing.cycles_required();
+
+        let cycles_consumed = match (ic_kit::ic::msg_cycles_available(), &required_cycles) {
+            (0, _) | (_, Err(_)) => None,
+            (_, Ok(required_cycles)) => Some(check_provided_cycles(*required_cycles)),
+        };
 
-        consume_provided_icp(caller, ledger, cmc, icp_to, icp_fee, controller)
+        async move {
+            let required_cycles = required_cycles?;
+            match cycles_consumed {
+                Some(result) => result,
+                None => {
+                    consume_provided_icp(
+                        caller,
+                        ledger,
+                        cmc,
+                        icp_to,
+                        icp_fee,
+                        controller,
+                        initial_cycles,
+                        required_cycles,
+                    )
+                    .await
+                }
+            }
+        }
+    }
+
+    /// Accepts the cycles attached to the call that were checked by [`consume_creation_fee`].
+    /// Returns the amount of accepted cycles, which is zero if the fee was paid with ICP.
+    ///
+    /// Cycles attached to the call remain available across the async calls made by the update
+    /// method, so this method can be called after the canister is created.
+    pub fn accept_creation_cycles(&self, pricing: CreationPricing) -> u64 {
+        let available = ic_kit::ic::msg_cycles_available();
+        // The pricing was already checked by `consume_creation_fee`, so it cannot overflow.
+        let required_cycles = pricing.cycles_required().unwrap_or(u64::MAX);
+        ic_kit::ic::msg_cycles_accept(available.min(required_cycles))
     }
 
     /// Adds an existing canister to the canister list. This method does not have any information
     /// about the canister it is adding to the list, so it is responsibility of the caller to check
     /// if the canister exists and of correct type.
-    pub fn register_existing(&mut self, canister_id: Principal) -> Result<(), FactoryError> {
-        let _lock = self.lock()?;
-        self.insert_canister(canister_id, self.module()?.hash);
-
-        Ok(())
+    ///
+    /// Use [`FactoryCanister::adopt_canister`] to add a canister with the factory control and the
+    /// module hash verified.
+    ///
+    /// `caller` is recorded to the audit log.
+    pub fn register_existing(
+        &mut self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<(), FactoryError> {
+        let module = self.module().ok();
+        let result = self.register_existing_internal(canister_id);
+        audit::record(
+            caller,
+            Operation::RegisterExisting,
+            vec![canister_id],
+            module.as_ref().map(CanisterModule::hash),
+            None,
+            &result,
+        );
+
+        result
     }
 
-    /// Removes the canister from the list of the factory canisters.
-    pub fn forget(&mut self, canister_id: Principal) -> Result<(), FactoryError> {
+    fn register_existing_internal(&mut self, canister_id: Principal) -> Result<(), FactoryError> {
         let _lock = self.lock()?;
-        self.remove_canister(canister_id);
+        let module = self.module()?;
+        self.insert_canister(canister_id, module.hash);
+        registry::insert_metadata(
+            canister_id,
+            CanisterMetadata::new(None, None, Some(module.version)),
+        );
 
         Ok(())
     }
+
+    /// Removes the canister from the list of the factory canisters. `caller` is recorded to the
+    /// audit log.
+    pub fn forget(
+        &mut self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<(), FactoryError> {
+        let result = self.lock().map(|_lock| {
+            self.remove_canister(canister_id);
+        });
+        audit::record(
+            caller,
+            Operation::Forget,
+            vec![canister_id],
+            None,
+            None,
+            &result,
+        );
+
+        result
+    }
 }
 
 /// Abstraction to provided compile time checks for factory method access.
@@ -379,28 +787,73 @@ pub struct Authorized<T> {
     _auth: T,
 }
 
+/// Marker type of a factory role, used to check the access to the factory methods.
+pub trait AccessRole: Default {
+    const ROLE: Role;
+}
+
 /// The operation caller is the factory controller (owner).
+#[derive(Default)]
 pub struct Owner {}
 
+/// The operation caller can set the canister wasm and upgrade the factory canisters.
+#[derive(Default)]
+pub struct Upgrader {}
+
+/// The operation caller can manage the factory canisters.
+#[derive(Default)]
+pub struct Operator {}
+
+/// The operation caller can change the canister creation fees.
+#[derive(Default)]
+pub struct Billing {}
+
+/// The operation caller can read the factory logs.
+#[derive(Default)]
+pub struct Viewer {}
+
+impl AccessRole for Owner {
+    const ROLE: Role = Role::Owner;
+}
+
+impl AccessRole for Upgrader {
+    const ROLE: Role = Role::Upgrader;
+}
+
+impl AccessRole for Operator {
+    const ROLE: Role = Role::Operator;
+}
+
+impl AccessRole for Billing {
+    const ROLE: Role = Role::Billing;
+}
+
+impl AccessRole for Viewer {
+    const ROLE: Role = Role::Viewer;
+}
+
 impl Authorized<Owner> {
-    /// Sets the new version of the wasm code that is used to create new canisters.
-    pub fn set_canister_wasm(&mut self, wasm: Vec<u8>) -> Result<u32, FactoryError> {
-        FactoryState::default().check_update_allowed()?;
-        let module_version = FactoryState::default()
-            .module()
-            .map(|module| module.version)
-            .unwrap_or(0);
+    /// Owners have all the roles, so they can act in any of them.
+    pub fn as_role<R: AccessRole>(&self) -> Authorized<R> {
+        Authorized::<R> {
+            _auth: R::default(),
+        }
+    }
 
-        let hash = get_canister_hash(&wasm);
+    /// Sets the new version of the wasm code that is used to create new canisters. See
+    /// [`Authorized<Upgrader>::set_canister_wasm`].
+    pub fn set_canister_wasm(&mut self, wasm: Vec<u8>) -> Result<u32, FactoryError> {
+        self.as_role::<Upgrader>().set_canister_wasm(wasm)
+    }
 
-        let module = CanisterModule {
-            wasm,
-            hash,
-            version: module_version,
-        };
+    /// Update the icp_fee configuration. See [`Authorized<Billing>::set_icp_fee`].
+    pub fn set_icp_fee(&mut self, fee: u64) -> Result<(), FactoryError> {
+        self.as_role::<Billing>().set_icp_fee(fee)
+    }
 
-        factory_state().set_upgrading_module(Some(module));
-        Ok(module_version)
+    /// Update the icp_to configuration. See [`Authorized<Billing>::set_fee_to`].
+    pub fn set_fee_to(&mut self, fee_to: Principal) -> Result<(), FactoryError> {
+        self.as_role::<Billing>().set_fee_to(fee_to)
     }
 
     /// Update the factory controller.
@@ -422,20 +875,83 @@ impl Authorized<Owner> {
         Ok(())
     }
 
-    /// Update the icp_fee configuration.
-    pub fn set_icp_fee(&mut self, fee: u64) -> Result<(), FactoryError> {
-        let mut state = factory_state();
-        state.check_update_allowed()?;
-        state.set_icp_fee(fee);
-        Ok(())
+    /// Grants the role to the principal. `changed_by` is recorded to the role changes log.
+    pub fn grant_role(
+        &mut self,
+        changed_by: Principal,
+        principal: Principal,
+        role: Role,
+    ) -> Result<(), FactoryError> {
+        factory_state().check_update_allowed()?;
+        acl::grant(changed_by, principal, role)
     }
 
-    /// Update the icp_to configuration.
-    pub fn set_fee_to(&mut self, fee_to: Principal) -> Result<(), FactoryError> {
+    /// Revokes the role from the principal. `changed_by` is recorded to the role changes log.
+    ///
+    /// Note, that the factory controller always has all the roles, so its roles cannot be revoked.
+    pub fn revoke_role(
+        &mut self,
+        changed_by: Principal,
+        principal: Principal,
+        role: Role,
+    ) -> Result<(), FactoryError> {
+        factory_state().check_update_allowed()?;
+        acl::revoke(changed_by, principal, role)
+    }
+
+    /// Resets the factory state update lock to unlocked state. This method can be only called by
+    /// the factory controller and is supposed to be used only in case the state was broken by some
+    /// disaster.
+    pub(crate) fn release_update_lock(&mut self) {
+        factory_state().unlock()
+    }
+
+    /// Removes the record of the interrupted operation. If the operation still holds the expired
+    /// state lock, the lock is released.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::NotFound` if there is no interrupted operation with the given id.
+    pub(crate) fn discard_operation(
+        &mut self,
+        operation_id: u64,
+    ) -> Result<OperationRecord, FactoryError> {
         let mut state = factory_state();
-        state.check_update_allowed()?;
-        state.set_icp_to(fee_to);
-        Ok(())
+        if !state
+            .interrupted_operations()
+            .iter()
+            .any(|(id, _)| *id == operation_id)
+        {
+            return Err(FactoryError::NotFound);
+        }
+
+        if UPDATE_LOCK.with(|lock| lock.borrow().operation_id()) == Some(operation_id) {
+            state.unlock();
+        }
+
+        operations::finish(operation_id).ok_or(FactoryError::NotFound)
+    }
+}
+
+impl Authorized<Upgrader> {
+    /// Sets the new version of the wasm code that is used to create new canisters.
+    pub fn set_canister_wasm(&mut self, wasm: Vec<u8>) -> Result<u32, FactoryError> {
+        FactoryState::default().check_update_allowed()?;
+        let module_version = FactoryState::default()
+            .module()
+            .map(|module| module.version)
+            .unwrap_or(0);
+
+        let hash = get_canister_hash(&wasm);
+
+        let module = CanisterModule {
+            wasm,
+            hash,
+            version: module_version,
+        };
+
+        factory_state().set_upgrading_module(Some(module));
+        Ok(module_version)
     }
 
     /// Upgrade the code of the canister to the current module wasm code.
@@ -462,19 +978,81 @@ impl Authorized<Owner> {
     ) -> Result<(), FactoryError> {
         let mut state = factory_state();
         state.check_lock(lock);
-        let hash = state.module()?.hash;
-        state.insert_canister(canister_id, hash);
+        let module = state.module()?;
+        state.insert_canister(canister_id, module.hash);
+        registry::update_metadata(canister_id, |metadata| {
+            metadata.module_version = Some(module.version);
+            metadata.last_upgraded_at = Some(ic_kit::ic::time());
+        });
 
         Ok(())
     }
+}
 
-    /// Resets the factory state update lock to unlocked state. This method can be only called by
-    /// the factory controller and is supposed to be used only in case the state was broken by some
-    /// disaster.
-    pub(crate) fn release_update_lock(&mut self) {
-        factory_state().unlock()
+impl Authorized<Billing> {
+    /// Update the amount of cycles transferred to the new canisters and the amount of cycles
+    /// charged by the factory for canister creation.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if `initial_cycles` is zero or the total cycles
+    /// required to create a canister with any pricing overflow `u64`.
+    pub fn set_creation_cycles(
+        &mut self,
+        initial_cycles: u64,
+        creation_cost: u64,
+    ) -> Result<(), FactoryError> {
+        let mut state = factory_state();
+        state.check_update_allowed()?;
+        state.set_creation_cycles(initial_cycles, creation_cost)
+    }
+
+    /// Adds a new pricing tier or replaces the existing tier with the same name.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the tier name is empty, the tier initial cycles
+    /// are zero or the total cycles required to create a canister with the tier overflow `u64`.
+    pub fn set_pricing_tier(&mut self, tier: PricingTier) -> Result<(), FactoryError> {
+        let mut state = factory_state();
+        state.check_update_allowed()?;
+        state.set_pricing_tier(tier)
+    }
+
+    /// Removes the pricing tier. Canisters can no longer be created with this tier.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if there is no tier with the given name.
+    pub fn remove_pricing_tier(&mut self, name: &str) -> Result<(), FactoryError> {
+        let mut state = factory_state();
+        state.check_update_allowed()?;
+        match state.remove_pricing_tier(name) {
+            true => Ok(()),
+            false => Err(FactoryError::GenericError(format!(
+                "pricing tier {name} not found"
+            ))),
+        }
     }
 
+    /// Update the icp_fee configuration.
+    pub fn set_icp_fee(&mut self, fee: u64) -> Result<(), FactoryError> {
+        let mut state = factory_state();
+        state.check_update_allowed()?;
+        state.set_icp_fee(fee);
+        Ok(())
+    }
+
+    /// Update the icp_to configuration.
+    pub fn set_fee_to(&mut self, fee_to: Principal) -> Result<(), FactoryError> {
+        let mut state = factory_state();
+        state.check_update_allowed()?;
+        state.set_icp_to(fee_to);
+        Ok(())
+    }
+}
+
+impl Authorized<Operator> {
     /// Drops the canister.
     ///
     /// This method works in a similar way to [`create_canister`], see its documentation for the
@@ -488,6 +1066,141 @@ impl Authorized<Owner> {
         drop_canister(canister_id)
     }
 
+    /// Runs one step of the canister dropping workflow, see [`crate::dropping`] for the details.
+    /// For the `Finalize` and `Withdraw` steps returns the amount of cycles the cycles receiver got
+    /// during the step.
+    ///
+    /// This method works in a similar way to [`create_canister`], see its documentation for the
+    /// details. [`register_dropped`] must be called after the last step is completed.
+    pub(crate) fn run_drop_step(
+        &mut self,
+        canister_id: Principal,
+        step: DropStep,
+        options: &DropOptions,
+        lock: &UpdateLock,
+    ) -> impl Future<Output = Result<Option<u128>, FactoryError>> {
+        factory_state().check_lock(lock);
+        let finalize = options.finalize;
+        let cycles_receiver = options
+            .cycles_receiver
+            .unwrap_or_else(ic_exports::ic_kit::ic::id);
+
+        async move {
+            match step {
+                DropStep::Finalize if finalize => {
+                    let cycles_before = controlled_canister_cycles(cycles_receiver).await?;
+                    finalize_canister(canister_id, cycles_receiver).await?;
+                    let cycles_after = controlled_canister_cycles(cycles_receiver).await?;
+                    Ok(Some(cycles_after.saturating_sub(cycles_before)))
+                }
+                DropStep::Finalize => Ok(None),
+                DropStep::Stop => stop_canister(canister_id).await.map(|_| None),
+                DropStep::Uninstall => uninstall_canister(canister_id).await.map(|_| None),
+                DropStep::Withdraw => {
+                    let mut reclaimed = None;
+                    if canister_cycles(canister_id).await? > dropping::MAX_LOST_CYCLES {
+                        install_withdrawal_module(canister_id).await?;
+                        start_canister(canister_id).await?;
+                        let cycles_before = controlled_canister_cycles(cycles_receiver).await?;
+                        withdraw_cycles(canister_id, cycles_receiver).await?;
+                        let cycles_after = controlled_canister_cycles(cycles_receiver).await?;
+                        reclaimed = Some(cycles_after.saturating_sub(cycles_before));
+                    }
+
+                    // The step is retried if the canister is left running or with too many
+                    // cycles, as it can't be deleted in the first case and its cycles would be
+                    // lost in the second one.
+                    stop_canister(canister_id).await?;
+                    let remaining = canister_cycles(canister_id).await?;
+                    if remaining > dropping::MAX_LOST_CYCLES {
+                        return Err(FactoryError::GenericError(format!(
+                            "canister {canister_id} still has {remaining} cycles after the \
+                            withdrawal"
+                        )));
+                    }
+
+                    Ok(reclaimed)
+                }
+                DropStep::Delete => delete_canister(canister_id).await.map(|_| None),
+            }
+        }
+    }
+
+    /// Updates the settings of the canister created by the factory.
+    ///
+    /// If the `settings.controllers` list is set, the factory principal is added to it in case
+    /// it's not there, so the factory never loses control over its canisters.
+    ///
+    /// This method works in a similar way to [`create_canister`], see its documentation for the
+    /// details.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::NotFound` if the canister is not in the factory canister list.
+    pub(crate) fn update_canister_settings(
+        &mut self,
+        canister_id: Principal,
+        settings: CanisterSettings,
+        lock: &UpdateLock,
+    ) -> Result<impl Future<Output = Result<(), FactoryError>>, FactoryError> {
+        let state = factory_state();
+        state.check_lock(lock);
+        if !state.is_registered(canister_id) {
+            return Err(FactoryError::NotFound);
+        }
+
+        Ok(update_canister_settings(
+            canister_id,
+            with_factory_controller(settings),
+        ))
+    }
+
+    /// Adds a canister not created by the factory to the list of the factory canisters with its
+    /// actual module hash. The canister module version is recorded only if the hash matches the
+    /// current factory module. Returns the recorded module version.
+    ///
+    /// The caller must check that the factory controls the canister and read its module hash
+    /// before calling this method.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the canister is already registered or the module
+    /// hash is not a SHA-256 hash.
+    pub(crate) fn register_adopted(
+        &mut self,
+        canister_id: Principal,
+        module_hash: Vec<u8>,
+        lock: &UpdateLock,
+    ) -> Result<Option<u32>, FactoryError> {
+        let mut state = factory_state();
+        state.check_lock(lock);
+        if state.is_registered(canister_id) {
+            return Err(FactoryError::GenericError(format!(
+                "canister {canister_id} is already registered in the factory"
+            )));
+        }
+
+        if module_hash.len() != CanisterHash::MAX_SIZE as usize {
+            return Err(FactoryError::GenericError(format!(
+                "invalid module hash length: {}",
+                module_hash.len()
+            )));
+        }
+
+        let module_version = state
+            .module()
+            .ok()
+            .filter(|module| module.hash.0 == module_hash)
+            .map(|module| module.version);
+        state.insert_canister(canister_id, CanisterHash(module_hash));
+        registry::insert_metadata(
+            canister_id,
+            CanisterMetadata::new(None, None, module_version),
+        );
+
+        Ok(module_version)
+    }
+
     /// Removes the canister from the list of tracked canisters.
     pub(crate) fn register_dropped(
         &mut self,
@@ -503,6 +1216,18 @@ impl Authorized<Owner> {
     }
 }
 
+/// Adds the factory principal to the controllers list of the settings if the list is set.
+fn with_factory_controller(mut settings: CanisterSettings) -> CanisterSettings {
+    let factory_id = ic_exports::ic_kit::ic::id();
+    if let Some(controllers) = &mut settings.controllers {
+        if !controllers.contains(&factory_id) {
+            controllers.insert(0, factory_id);
+        }
+    }
+
+    settings
+}
+
 fn get_canister_hash(wasm: &[u8]) -> CanisterHash {
     use sha2::{Digest, Sha256};
 
@@ -517,6 +1242,14 @@ pub struct FactoryConfiguration {
     pub icp_fee: u64,
     pub icp_to: Principal,
     pub controller: Principal,
+    /// Amount of cycles to transfer to the newly created canister. If not set,
+    /// `INITIAL_CANISTER_CYCLES` is used.
+    pub initial_canister_cycles: Option<u64>,
+    /// Amount of cycles charged by the factory to cover the canister creation expenses. If not
+    /// set, `CANISTER_CREATION_CYCLE_COST` is used.
+    pub canister_creation_cycle_cost: Option<u64>,
+    /// Pricing tiers the canisters can be created with in addition to the default configuration.
+    pub pricing_tiers: Option<Vec<PricingTier>>,
 }
 
 impl FactoryConfiguration {
@@ -531,10 +1264,78 @@ impl FactoryConfiguration {
             icp_fee,
             icp_to,
             controller,
+            initial_canister_cycles: None,
+            canister_creation_cycle_cost: None,
+            pricing_tiers: None,
         }
     }
 }
 
+impl FactoryConfiguration {
+    /// Checks that the initial cycles of all the pricings are not zero, the total cycles required
+    /// to create a canister don't overflow `u64` and the pricing tiers have unique non-empty names
+    /// not longer than [`creation::MAX_TIER_NAME_LENGTH`].
+    pub(crate) fn validate_pricing(&self) -> Result<(), FactoryError> {
+        let creation_cost = self
+            .canister_creation_cycle_cost
+            .unwrap_or(CANISTER_CREATION_CYCLE_COST);
+        let initial_cycles = self
+            .initial_canister_cycles
+            .unwrap_or(INITIAL_CANISTER_CYCLES);
+        check_creation_cycles("default pricing", initial_cycles, creation_cost)?;
+
+        let mut names = HashSet::new();
+        for tier in self.pricing_tiers.iter().flatten() {
+            if tier.name.trim().is_empty() {
+                return Err(FactoryError::GenericError(
+                    "pricing tier name must not be empty".into(),
+                ));
+            }
+
+            if tier.name.len() > creation::MAX_TIER_NAME_LENGTH {
+                return Err(FactoryError::GenericError(format!(
+                    "pricing tier name must not be longer than {} bytes",
+                    creation::MAX_TIER_NAME_LENGTH
+                )));
+            }
+
+            if !names.insert(tier.name.as_str()) {
+                return Err(FactoryError::GenericError(format!(
+                    "duplicate pricing tier {}",
+                    tier.name
+                )));
+            }
+
+            check_creation_cycles(
+                &format!("pricing tier {}", tier.name),
+                tier.initial_cycles,
+                creation_cost,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn check_creation_cycles(
+    pricing_name: &str,
+    initial_cycles: u64,
+    creation_cost: u64,
+) -> Result<(), FactoryError> {
+    if initial_cycles == 0 {
+        return Err(FactoryError::GenericError(format!(
+            "initial cycles of the {pricing_name} must not be zero"
+        )));
+    }
+
+    let pricing = CreationPricing {
+        initial_cycles,
+        creation_cost,
+        icp_fee: 0,
+    };
+    pricing.cycles_required().map(|_| ())
+}
+
 impl Default for FactoryConfiguration {
     fn default() -> Self {
         Self {
@@ -542,10 +1343,56 @@ impl Default for FactoryConfiguration {
             icp_fee: DEFAULT_ICP_FEE,
             icp_to: Principal::anonymous(),
             controller: Principal::anonymous(),
+            initial_canister_cycles: None,
+            canister_creation_cycle_cost: None,
+            pricing_tiers: None,
         }
     }
 }
 
+/// Canister creation plan with its own amount of initial cycles and ICP fee.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub struct PricingTier {
+    pub name: String,
+    /// Amount of cycles to transfer to the canisters created with this tier.
+    pub initial_cycles: u64,
+    /// ICP fee for canister creation with this tier.
+    pub icp_fee: u64,
+}
+
+/// Cycles and fees of a canister creation.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct CreationPricing {
+    /// Amount of cycles to transfer to the new canister.
+    pub initial_cycles: u64,
+    /// Amount of cycles charged by the factory to cover the canister creation expenses.
+    pub creation_cost: u64,
+    pub icp_fee: u64,
+}
+
+impl CreationPricing {
+    /// Total amount of cycles required to create a canister.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the amount overflows `u64`.
+    pub fn cycles_required(&self) -> Result<u64, FactoryError> {
+        self.initial_cycles
+            .checked_add(self.creation_cost)
+            .ok_or_else(|| {
+                FactoryError::GenericError("canister creation cycles overflow u64".into())
+            })
+    }
+}
+
+/// ICP price quote for canister creation.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct CreationQuote {
+    pub pricing: CreationPricing,
+    /// Amount of ICP the CMC mints the required cycles for, at the current conversion rate.
+    pub icp_amount: u64,
+}
+
 impl Storable for FactoryConfiguration {
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Encode!(self)
@@ -558,6 +1405,19 @@ impl Storable for FactoryConfiguration {
     }
 }
 
+/// Checks that at least `required_cycles` are attached to the call. The cycles are accepted by
+/// [`FactoryState::accept_creation_cycles`], the rest of the attached cycles are refunded to the
+/// caller when the call returns.
+fn check_provided_cycles(required_cycles: u64) -> Result<u64, FactoryError> {
+    let provided = ic_kit::ic::msg_cycles_available();
+    if provided < required_cycles {
+        return Err(FactoryError::NotEnoughCycles(provided, required_cycles));
+    }
+
+    Ok(required_cycles)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn consume_provided_icp(
     caller: Principal,
     ledger: Principal,
@@ -565,16 +1425,27 @@ async fn consume_provided_icp(
     icp_to: Principal,
     icp_fee: u64,
     controller: Principal,
+    initial_cycles: u64,
+    required_cycles: u64,
 ) -> Result<u64, FactoryError> {
     if caller != controller {
         // If the caller is not the controller, we require the caller to provide cycles.
-        return transfer_and_top_up(icp_fee, ledger, cmc, caller, icp_to).await;
+        return transfer_and_top_up(
+            icp_fee,
+            ledger,
+            cmc,
+            caller,
+            icp_to,
+            initial_cycles,
+            required_cycles,
+        )
+        .await;
     }
 
-    Ok(CANISTER_CREATION_CYCLE_COST + INITIAL_CANISTER_CYCLES)
+    Ok(required_cycles)
 }
 
-/// Converts the `INITIAL_CANISTER_CYCLES + CANISTER_CREATION_CYCLE_COST` to ICP tokens, and the caller sends
+/// Converts the `required_cycles` to ICP tokens, and the caller sends
 /// the tokens to the cycles-minting-canister, the factory canister
 /// is topped up with cycles and the the icp_fee is sent to the
 /// `icp_to` principal.
@@ -584,6 +1455,8 @@ async fn transfer_and_top_up(
     cmc: Principal,
     caller: Principal,
     icp_to: Principal,
+    initial_cycles: u64,
+    required_cycles: u64,
 ) -> Result<u64, FactoryError> {
     let id = ic_kit::ic::id();
     let balance = ledger
@@ -595,13 +1468,11 @@ async fn transfer_and_top_up(
         Err(FactoryError::NotEnoughIcp(balance, icp_fee))?;
     }
 
-    let top_up_fee =
-        top_up::icp_amount_from_cycles(cmc, INITIAL_CANISTER_CYCLES + CANISTER_CREATION_CYCLE_COST)
-            .await?;
+    let top_up_fee = top_up::icp_amount_from_cycles(cmc, required_cycles).await?;
     if top_up_fee > icp_fee {
         return Err(FactoryError::GenericError(format!(
             "The fee {} required to create {} cycles is greater than the ICP FEE {}",
-            top_up_fee, INITIAL_CANISTER_CYCLES, icp_fee
+            top_up_fee, initial_cycles, icp_fee
         )))?;
     }
 
@@ -646,7 +1517,7 @@ async fn send_remaining_fee_to(
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-struct PrincipalKey(Principal);
+pub(crate) struct PrincipalKey(pub(crate) Principal);
 
 impl Storable for PrincipalKey {
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
@@ -680,7 +1551,7 @@ thread_local! {
             .expect("failed to initialize factory upgrading module"))
     };
 
-    static CANISTERS_MAP: RefCell<StableBTreeMap<PrincipalKey, CanisterHash>> =
+    pub(crate) static CANISTERS_MAP: RefCell<StableBTreeMap<PrincipalKey, CanisterHash>> =
         RefCell::new(StableBTreeMap::new(CANISTERS_MEMORY_ID));
 
     static UPDATE_LOCK: RefCell<UpdateLock> = RefCell::new(UpdateLock::default());
@@ -709,3 +1580,197 @@ where
 pub fn factory_state() -> FactoryState {
     FactoryState::default()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candid::Nat;
+    use ic_canister::register_virtual_responder;
+    use ic_exports::ic_kit::MockContext;
+    use ic_helpers::management::{
+        CanisterIDArg, CanisterStatus, CanisterStatusKind, InstallCodeInput,
+    };
+
+    use super::*;
+
+    thread_local! {
+        static CANISTERS: RefCell<HashMap<Principal, (u128, CanisterStatusKind)>> =
+            RefCell::default();
+        static CALLS: RefCell<Vec<&'static str>> = RefCell::default();
+    }
+
+    fn canister(id: u8, cycles: u128, status: CanisterStatusKind) -> Principal {
+        let canister_id = Principal::from_slice(&[id]);
+        CANISTERS.with(|canisters| canisters.borrow_mut().insert(canister_id, (cycles, status)));
+        canister_id
+    }
+
+    fn cycles(canister_id: Principal) -> u128 {
+        CANISTERS.with(|canisters| canisters.borrow()[&canister_id].0)
+    }
+
+    fn calls() -> Vec<&'static str> {
+        CALLS.with(|calls| calls.borrow().clone())
+    }
+
+    fn set_status(canister_id: Principal, method: &'static str, status: CanisterStatusKind) {
+        CALLS.with(|calls| calls.borrow_mut().push(method));
+        CANISTERS.with(|canisters| {
+            if let Some(canister) = canisters.borrow_mut().get_mut(&canister_id) {
+                canister.1 = status;
+            }
+        });
+    }
+
+    /// Registers the management canister responders and the `withdraw` method of the withdrawal
+    /// module, which moves `withdrawn` cycles to the receiver.
+    fn register_responders(canister_id: Principal, withdrawn: u128) {
+        let management = Principal::management_canister();
+        register_virtual_responder(management, "canister_status", |(arg,): (CanisterIDArg,)| {
+            let (cycles, status) = CANISTERS.with(|canisters| canisters.borrow()[&arg.canister_id]);
+            CanisterStatus {
+                status,
+                settings: Default::default(),
+                module_hash: None,
+                memory_size: Nat::from(0u8),
+                cycles: Nat::from(cycles),
+            }
+        });
+        register_virtual_responder(management, "install_code", |(arg,): (InstallCodeInput,)| {
+            assert_eq!(arg.wasm_module, dropping::WITHDRAWAL_MODULE);
+            CALLS.with(|calls| calls.borrow_mut().push("install_code"));
+        });
+        register_virtual_responder(management, "start_canister", |(arg,): (CanisterIDArg,)| {
+            set_status(
+                arg.canister_id,
+                "start_canister",
+                CanisterStatusKind::Running,
+            )
+        });
+        register_virtual_responder(management, "stop_canister", |(arg,): (CanisterIDArg,)| {
+            set_status(
+                arg.canister_id,
+                "stop_canister",
+                CanisterStatusKind::Stopped,
+            )
+        });
+        register_virtual_responder(canister_id, "withdraw", move |(arg,): (CanisterIDArg,)| {
+            CALLS.with(|calls| calls.borrow_mut().push("withdraw"));
+            CANISTERS.with(|canisters| {
+                let mut canisters = canisters.borrow_mut();
+                canisters.get_mut(&canister_id).unwrap().0 -= withdrawn;
+                canisters.get_mut(&arg.canister_id).unwrap().0 += withdrawn;
+            });
+        });
+    }
+
+    async fn run_step(
+        canister_id: Principal,
+        step: DropStep,
+        options: DropOptions,
+    ) -> Result<Option<u128>, FactoryError> {
+        let lock = factory_state().lock().unwrap();
+        let mut operator = Authorized::<Operator> {
+            _auth: Operator::default(),
+        };
+        operator
+            .run_drop_step(canister_id, step, &options, &lock)
+            .await
+    }
+
+    fn receiver_options(receiver: Principal) -> DropOptions {
+        DropOptions {
+            finalize: false,
+            cycles_receiver: Some(receiver),
+        }
+    }
+
+    #[tokio::test]
+    async fn withdraw_step_sends_cycles_to_receiver() {
+        MockContext::new().inject();
+        let canister_id = canister(1, 50_000_000_000, CanisterStatusKind::Stopped);
+        let receiver = canister(2, 0, CanisterStatusKind::Running);
+        register_responders(canister_id, 40_000_000_000);
+
+        let reclaimed = run_step(canister_id, DropStep::Withdraw, receiver_options(receiver))
+            .await
+            .unwrap();
+
+        assert_eq!(reclaimed, Some(40_000_000_000));
+        assert_eq!(cycles(receiver), 40_000_000_000);
+        assert_eq!(
+            calls(),
+            vec![
+                "install_code",
+                "start_canister",
+                "withdraw",
+                "stop_canister"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn withdraw_step_only_stops_canister_with_few_cycles() {
+        MockContext::new().inject();
+        let canister_id = canister(1, dropping::MAX_LOST_CYCLES, CanisterStatusKind::Running);
+        let receiver = canister(2, 0, CanisterStatusKind::Running);
+        register_responders(canister_id, 0);
+
+        let reclaimed = run_step(canister_id, DropStep::Withdraw, receiver_options(receiver))
+            .await
+            .unwrap();
+
+        assert_eq!(reclaimed, None);
+        assert_eq!(calls(), vec!["stop_canister"]);
+    }
+
+    #[tokio::test]
+    async fn withdraw_step_fails_if_cycles_remain() {
+        MockContext::new().inject();
+        let canister_id = canister(1, 50_000_000_000, CanisterStatusKind::Stopped);
+        let receiver = canister(2, 0, CanisterStatusKind::Running);
+        register_responders(canister_id, 1_000_000_000);
+
+        let result = run_step(canister_id, DropStep::Withdraw, receiver_options(receiver)).await;
+        assert!(result.is_err());
+
+        // A retry withdraws the remaining cycles again.
+        register_responders(canister_id, 39_000_000_000);
+        let reclaimed = run_step(canister_id, DropStep::Withdraw, receiver_options(receiver))
+            .await
+            .unwrap();
+        assert_eq!(reclaimed, Some(39_000_000_000));
+        assert_eq!(cycles(canister_id), dropping::MAX_LOST_CYCLES);
+    }
+
+    #[tokio::test]
+    async fn finalize_step_is_skipped_without_finalization() {
+        MockContext::new().inject();
+        let canister_id = canister(1, 50_000_000_000, CanisterStatusKind::Running);
+        register_responders(canister_id, 0);
+
+        let reclaimed = run_step(canister_id, DropStep::Finalize, DropOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(reclaimed, None);
+        assert!(calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_step_can_be_retried() {
+        MockContext::new().inject();
+        let canister_id = canister(1, 0, CanisterStatusKind::Running);
+        register_responders(canister_id, 0);
+
+        run_step(canister_id, DropStep::Stop, DropOptions::default())
+            .await
+            .unwrap();
+        run_step(canister_id, DropStep::Stop, DropOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(calls(), vec!["stop_canister"]);
+    }
+}
@@ -0,0 +1,604 @@
+//! Export and import of the factory state, used to move the factory registry to a new factory
+//! canister or to back it up offchain.
+//!
+//! The encoded backup is a sequence of parts. Each part is a candid encoded [`BackupPart`]
+//! prefixed with its length as a big endian `u32`. The first part is the header with the factory
+//! configuration and roles, and it is followed by the parts with up to [`EXPORT_BATCH_SIZE`]
+//! canisters or audit records. Every export call encodes one part, so exporting a large factory
+//! doesn't exceed the instructions limit of a single call.
+//!
+//! The encoded backup is too large to be transferred in one message, so it is exported and
+//! imported by chunks of [`CHUNK_SIZE`] bytes. The SHA-256 checksum of the encoded backup is
+//! checked before the import, and the backup is imported only if it is complete and valid, so the
+//! factory state is never partially imported. The parts are decoded one by one right from the
+//! uploaded chunks, without joining them into one buffer.
+//!
+//! The export and import buffers are kept in the heap memory and are discarded on upgrade.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::Bound;
+
+use candid::{Decode, Encode};
+use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::BoundedStorable;
+use sha2::{Digest, Sha256};
+
+use super::{
+    factory_state, update_config, with_config, Authorized, CanisterHash, Owner, PrincipalKey,
+    CANISTERS_MAP,
+};
+use crate::acl::{self, Role};
+use crate::audit::{self, AuditRecord};
+use crate::error::FactoryError;
+use crate::registry::{self, CanisterMetadata};
+use crate::FactoryConfiguration;
+
+/// Size of one backup chunk in bytes.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Maximum size of the backup which can be imported. Both the uploaded chunks and the decoded
+/// backup are kept in the heap memory during the import.
+pub const MAX_BACKUP_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Maximum number of canisters or audit records encoded by one export call.
+pub const EXPORT_BATCH_SIZE: usize = 1000;
+
+/// Factory state decoded from a backup.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct FactoryBackup {
+    /// Time the backup was made in nanoseconds.
+    pub created_at: u64,
+    pub configuration: FactoryConfiguration,
+    pub module: Option<BackupModule>,
+    pub canisters: Vec<BackupCanister>,
+    pub roles: Vec<(Principal, Vec<Role>)>,
+    pub audit: Vec<AuditRecord>,
+}
+
+/// Metadata of the factory canister module. The wasm itself is not included into the backup, so
+/// the same wasm must be set to the importing factory before the import.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct BackupModule {
+    pub hash: Vec<u8>,
+    pub version: u32,
+}
+
+/// Part of the encoded backup, see the module documentation.
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub enum BackupPart {
+    Header(BackupHeader),
+    Canisters(Vec<BackupCanister>),
+    Audit(Vec<AuditRecord>),
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct BackupHeader {
+    /// Time the backup was started in nanoseconds.
+    pub created_at: u64,
+    pub configuration: FactoryConfiguration,
+    pub module: Option<BackupModule>,
+    pub roles: Vec<(Principal, Vec<Role>)>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct BackupCanister {
+    pub canister_id: Principal,
+    pub hash: Vec<u8>,
+    pub metadata: Option<CanisterMetadata>,
+}
+
+/// Description of the encoded backup.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// Size of the encoded backup in bytes.
+    pub size: u64,
+    pub chunk_count: u32,
+    /// SHA-256 hash of the encoded backup.
+    pub checksum: Vec<u8>,
+}
+
+impl BackupInfo {
+    fn new(bytes: &[u8]) -> Self {
+        Self {
+            size: bytes.len() as u64,
+            chunk_count: bytes.chunks(CHUNK_SIZE).count() as u32,
+            checksum: Sha256::digest(bytes).to_vec(),
+        }
+    }
+}
+
+/// Progress of the backup export.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub enum ExportStatus {
+    /// The export is not completed yet. `size` is the number of bytes encoded so far.
+    InProgress { size: u64 },
+    /// The backup is encoded and can be read with [`export_chunk`].
+    Completed(BackupInfo),
+}
+
+/// The next part of the backup to encode.
+#[derive(Debug, Clone, Copy)]
+enum ExportCursor {
+    /// Canisters following the given one.
+    Canisters(Option<Principal>),
+    /// Audit records starting from the given index.
+    Audit(u64),
+    Completed,
+}
+
+struct ExportBuffer {
+    bytes: Vec<u8>,
+    cursor: ExportCursor,
+}
+
+impl ExportBuffer {
+    fn status(&self) -> ExportStatus {
+        match self.cursor {
+            ExportCursor::Completed => ExportStatus::Completed(BackupInfo::new(&self.bytes)),
+            _ => ExportStatus::InProgress {
+                size: self.bytes.len() as u64,
+            },
+        }
+    }
+
+    fn push(&mut self, part: &BackupPart) {
+        let encoded = Encode!(part).expect("failed to serialize backup part");
+        self.bytes
+            .extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        self.bytes.extend_from_slice(&encoded);
+    }
+}
+
+struct ImportBuffer {
+    info: BackupInfo,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+/// Reads the uploaded chunks as one continuous sequence of bytes.
+struct ChunksReader<'a> {
+    chunks: Vec<&'a [u8]>,
+    chunk: usize,
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> ChunksReader<'a> {
+    fn new(chunks: Vec<&'a [u8]>) -> Self {
+        let remaining = chunks.iter().map(|chunk| chunk.len()).sum();
+        Self {
+            chunks,
+            chunk: 0,
+            offset: 0,
+            remaining,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>, FactoryError> {
+        if len > self.remaining {
+            return Err(FactoryError::GenericError(
+                "unexpected end of the backup".into(),
+            ));
+        }
+
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let available = &self.chunks[self.chunk][self.offset..];
+            let count = available.len().min(len - bytes.len());
+            bytes.extend_from_slice(&available[..count]);
+            self.offset += count;
+            if self.offset == self.chunks[self.chunk].len() {
+                self.chunk += 1;
+                self.offset = 0;
+            }
+        }
+
+        self.remaining -= len;
+        Ok(bytes)
+    }
+}
+
+thread_local! {
+    static EXPORT_BUFFER: RefCell<Option<ExportBuffer>> = RefCell::new(None);
+    static IMPORT_BUFFER: RefCell<Option<ImportBuffer>> = RefCell::new(None);
+}
+
+/// Returns the chunk of the last completely exported backup.
+pub fn export_chunk(index: u32) -> Option<Vec<u8>> {
+    EXPORT_BUFFER.with(|buffer| {
+        buffer
+            .borrow()
+            .as_ref()
+            .filter(|buffer| matches!(buffer.cursor, ExportCursor::Completed))
+            .and_then(|buffer| buffer.bytes.chunks(CHUNK_SIZE).nth(index as usize))
+            .map(|chunk| chunk.to_vec())
+    })
+}
+
+/// Returns up to `EXPORT_BATCH_SIZE` canisters following the `after` one.
+fn canisters_after(after: Option<Principal>) -> Vec<BackupCanister> {
+    let start = match after {
+        Some(canister_id) => Bound::Excluded(PrincipalKey(canister_id)),
+        None => Bound::Unbounded,
+    };
+
+    CANISTERS_MAP.with(|map| {
+        map.borrow()
+            .range((start, Bound::Unbounded))
+            .take(EXPORT_BATCH_SIZE)
+            .map(|(canister_id, hash)| BackupCanister {
+                canister_id: canister_id.0,
+                hash: hash.0,
+                metadata: registry::metadata(canister_id.0),
+            })
+            .collect()
+    })
+}
+
+impl Authorized<Owner> {
+    /// Starts a new backup export, discarding the previous one, and encodes the backup header.
+    /// Call [`continue_export`] until the export is completed, and then use [`export_chunk`] to
+    /// read the backup.
+    pub fn export_backup(&mut self) -> Result<ExportStatus, FactoryError> {
+        let state = factory_state();
+        state.check_update_allowed()?;
+        let header = BackupHeader {
+            created_at: ic::time(),
+            configuration: with_config(|cfg| cfg.clone()),
+            module: state.module().ok().map(|module| BackupModule {
+                hash: module.hash.0,
+                version: module.version,
+            }),
+            roles: acl::all_roles(),
+        };
+
+        let mut buffer = ExportBuffer {
+            bytes: vec![],
+            cursor: ExportCursor::Canisters(None),
+        };
+        buffer.push(&BackupPart::Header(header));
+        let status = buffer.status();
+        EXPORT_BUFFER.with(|export| export.replace(Some(buffer)));
+
+        Ok(status)
+    }
+
+    /// Encodes the next part of the backup started with [`export_backup`].
+    ///
+    /// The canisters are exported in the order of their principals, so the canisters added or
+    /// removed while the export is in progress may be missing from the backup or be exported
+    /// after they were removed. Make sure no canisters are created or dropped during the export.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the export is not started.
+    pub fn continue_export(&mut self) -> Result<ExportStatus, FactoryError> {
+        factory_state().check_update_allowed()?;
+        EXPORT_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let buffer = buffer
+                .as_mut()
+                .ok_or_else(|| FactoryError::GenericError("export is not started".into()))?;
+
+            match buffer.cursor {
+                ExportCursor::Canisters(after) => {
+                    let canisters = canisters_after(after);
+                    buffer.cursor = match canisters.len() < EXPORT_BATCH_SIZE {
+                        true => ExportCursor::Audit(0),
+                        false => ExportCursor::Canisters(
+                            canisters.last().map(|canister| canister.canister_id),
+                        ),
+                    };
+                    if !canisters.is_empty() {
+                        buffer.push(&BackupPart::Canisters(canisters));
+                    }
+                }
+                ExportCursor::Audit(offset) => {
+                    let records = audit::records(offset, EXPORT_BATCH_SIZE as u64);
+                    buffer.cursor = match records.len() < EXPORT_BATCH_SIZE {
+                        true => ExportCursor::Completed,
+                        false => ExportCursor::Audit(offset + records.len() as u64),
+                    };
+                    if !records.is_empty() {
+                        buffer.push(&BackupPart::Audit(records));
+                    }
+                }
+                ExportCursor::Completed => {}
+            }
+
+            Ok(buffer.status())
+        })
+    }
+
+    /// Starts a new import, discarding the chunks of the previous one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if the factory already has canisters or the backup is
+    /// too large.
+    pub fn begin_import(&mut self, info: BackupInfo) -> Result<(), FactoryError> {
+        check_is_empty()?;
+        if info.size > MAX_BACKUP_SIZE
+            || info.chunk_count as u64 != info.size.div_ceil(CHUNK_SIZE as u64)
+        {
+            return Err(FactoryError::GenericError(format!(
+                "invalid backup size {} with {} chunks",
+                info.size, info.chunk_count
+            )));
+        }
+
+        let chunks = vec![None; info.chunk_count as usize];
+        IMPORT_BUFFER.with(|buffer| buffer.replace(Some(ImportBuffer { info, chunks })));
+
+        Ok(())
+    }
+
+    /// Stores the chunk of the backup being imported.
+    pub fn import_chunk(&mut self, index: u32, chunk: Vec<u8>) -> Result<(), FactoryError> {
+        IMPORT_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let buffer = buffer
+                .as_mut()
+                .ok_or_else(|| FactoryError::GenericError("import is not started".into()))?;
+            let slot = buffer.chunks.get_mut(index as usize).ok_or_else(|| {
+                FactoryError::GenericError(format!("invalid chunk index {index}"))
+            })?;
+            *slot = Some(chunk);
+
+            Ok(())
+        })
+    }
+
+    /// Validates the imported backup and writes it to the factory state. Returns the ids of the
+    /// imported canisters.
+    ///
+    /// The factory configuration is replaced with the imported one, except for the factory
+    /// controller. Imported roles are granted by the `caller`, and the imported audit records are
+    /// appended to the audit log.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::GenericError` if some chunks are missing, the checksum doesn't match,
+    /// the factory already has canisters or the module hash of the backup differs from the current
+    /// factory module hash. Nothing is imported in this case.
+    pub fn finish_import(&mut self, caller: Principal) -> Result<Vec<Principal>, FactoryError> {
+        factory_state().check_update_allowed()?;
+        let backup = IMPORT_BUFFER.with(|buffer| decode_import(buffer.borrow().as_ref()))?;
+        validate(&backup)?;
+        IMPORT_BUFFER.with(|buffer| buffer.replace(None));
+
+        apply(caller, backup)
+    }
+}
+
+fn check_is_empty() -> Result<(), FactoryError> {
+    match factory_state().canister_count() {
+        0 => Ok(()),
+        _ => Err(FactoryError::GenericError(
+            "backup can only be imported into a factory without canisters".into(),
+        )),
+    }
+}
+
+fn decode_import(buffer: Option<&ImportBuffer>) -> Result<FactoryBackup, FactoryError> {
+    let buffer =
+        buffer.ok_or_else(|| FactoryError::GenericError("import is not started".into()))?;
+
+    let mut chunks = Vec::with_capacity(buffer.chunks.len());
+    let mut hasher = Sha256::new();
+    for (index, chunk) in buffer.chunks.iter().enumerate() {
+        let chunk = chunk
+            .as_deref()
+            .ok_or_else(|| FactoryError::GenericError(format!("chunk {index} is missing")))?;
+        hasher.update(chunk);
+        chunks.push(chunk);
+    }
+
+    let size = chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+    if size != buffer.info.size || hasher.finalize().as_slice() != buffer.info.checksum {
+        return Err(FactoryError::GenericError(
+            "backup checksum doesn't match".into(),
+        ));
+    }
+
+    let mut reader = ChunksReader::new(chunks);
+    let mut header = None;
+    let mut canisters = vec![];
+    let mut audit = vec![];
+    while !reader.is_empty() {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&reader.read(len.len())?);
+        let bytes = reader.read(u32::from_be_bytes(len) as usize)?;
+        let part = Decode!(&bytes, BackupPart)
+            .map_err(|e| FactoryError::GenericError(format!("failed to decode backup: {e}")))?;
+
+        match part {
+            BackupPart::Header(part) if header.is_none() => header = Some(part),
+            BackupPart::Header(_) => {
+                return Err(FactoryError::GenericError(
+                    "backup has more than one header".into(),
+                ))
+            }
+            BackupPart::Canisters(mut part) => canisters.append(&mut part),
+            BackupPart::Audit(mut part) => audit.append(&mut part),
+        }
+    }
+
+    let header =
+        header.ok_or_else(|| FactoryError::GenericError("backup header is missing".into()))?;
+
+    Ok(FactoryBackup {
+        created_at: header.created_at,
+        configuration: header.configuration,
+        module: header.module,
+        canisters,
+        roles: header.roles,
+        audit,
+    })
+}
+
+fn validate(backup: &FactoryBackup) -> Result<(), FactoryError> {
+    check_is_empty()?;
+    backup.configuration.validate_pricing()?;
+
+    if let Some(backup_module) = &backup.module {
+        let module_hash = factory_state().module().ok().map(|module| module.hash.0);
+        if module_hash.as_ref() != Some(&backup_module.hash) {
+            return Err(FactoryError::GenericError(format!(
+                "factory module hash doesn't match the backup module hash {}",
+                hex::encode(&backup_module.hash)
+            )));
+        }
+    }
+
+    let mut granted = HashSet::new();
+    for (principal, roles) in &backup.roles {
+        for role in roles {
+            if !granted.insert((*principal, *role)) {
+                return Err(FactoryError::GenericError(format!(
+                    "duplicate {role:?} role of principal {principal}"
+                )));
+            }
+        }
+    }
+
+    let mut canister_ids = HashSet::new();
+    for canister in &backup.canisters {
+        if canister.hash.len() != CanisterHash::MAX_SIZE as usize {
+            return Err(FactoryError::GenericError(format!(
+                "invalid module hash of canister {}",
+                canister.canister_id
+            )));
+        }
+
+        if !canister_ids.insert(canister.canister_id) {
+            return Err(FactoryError::GenericError(format!(
+                "duplicate canister {}",
+                canister.canister_id
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the validated backup to the factory state. The roles are granted first, as granting is
+/// the only write that can fail, so a failed import doesn't leave the state partially imported.
+fn apply(caller: Principal, backup: FactoryBackup) -> Result<Vec<Principal>, FactoryError> {
+    for (principal, roles) in backup.roles {
+        for role in roles {
+            // The role can be already granted in the importing factory.
+            if !acl::has_role(principal, role) {
+                acl::grant(caller, principal, role)?;
+            }
+        }
+    }
+
+    let mut state = factory_state();
+    let controller = state.controller();
+    update_config(|cfg| {
+        *cfg = backup.configuration.clone();
+        cfg.controller = controller;
+    });
+
+    if let (Some(backup_module), Ok(mut module)) = (&backup.module, state.module()) {
+        module.version = backup_module.version;
+        state.set_upgrading_module(Some(module));
+    }
+
+    let mut canister_ids = vec![];
+    for canister in backup.canisters {
+        state.insert_canister(
+            canister.canister_id,
+            CanisterHash::from(canister.hash.as_slice()),
+        );
+        if let Some(metadata) = canister.metadata {
+            registry::insert_metadata(canister.canister_id, metadata);
+        }
+        canister_ids.push(canister.canister_id);
+    }
+
+    for record in backup.audit {
+        audit::append(record);
+    }
+
+    Ok(canister_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn export_import_round_trip() {
+        MockContext::new().inject();
+        let canister = Principal::from_slice(&[1]);
+        let operator = Principal::from_slice(&[2]);
+        let mut state = factory_state();
+        state.insert_canister(canister, CanisterHash(vec![3; 32]));
+        acl::grant(Principal::anonymous(), operator, Role::Operator).unwrap();
+
+        let mut owner = state
+            .check_is_owner_internal(Principal::anonymous())
+            .unwrap();
+        let mut status = owner.export_backup().unwrap();
+        let info = loop {
+            match status {
+                ExportStatus::Completed(info) => break info,
+                ExportStatus::InProgress { .. } => {
+                    assert!(export_chunk(0).is_none());
+                    status = owner.continue_export().unwrap();
+                }
+            }
+        };
+        assert_eq!(info.chunk_count, 1);
+        let chunk = export_chunk(0).unwrap();
+        assert!(export_chunk(1).is_none());
+
+        let mut corrupted = chunk.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+
+        state.reset(FactoryConfiguration::default());
+        assert!(owner.finish_import(Principal::anonymous()).is_err());
+        owner.begin_import(info.clone()).unwrap();
+        assert!(owner.finish_import(Principal::anonymous()).is_err());
+        owner.import_chunk(0, corrupted).unwrap();
+        assert!(owner.finish_import(Principal::anonymous()).is_err());
+        assert_eq!(state.canister_count(), 0);
+
+        owner.import_chunk(0, chunk).unwrap();
+        assert_eq!(
+            owner.finish_import(Principal::anonymous()).unwrap(),
+            vec![canister]
+        );
+        assert_eq!(state.canisters()[&canister].0, vec![3; 32]);
+        assert!(acl::has_role(operator, Role::Operator));
+    }
+
+    #[test]
+    fn duplicate_roles_are_rejected() {
+        MockContext::new().inject();
+        let operator = Principal::from_slice(&[2]);
+        let backup = FactoryBackup {
+            created_at: 0,
+            configuration: FactoryConfiguration::default(),
+            module: None,
+            canisters: vec![],
+            roles: vec![
+                (operator, vec![Role::Operator]),
+                (operator, vec![Role::Viewer, Role::Operator]),
+            ],
+            audit: vec![],
+        };
+
+        assert!(validate(&backup).is_err());
+        assert!(!acl::has_role(operator, Role::Viewer));
+    }
+}
@@ -1,12 +1,32 @@
+//! Migration of the factories that keep their state in the `FactoryStateV1` format.
+//!
+//! V1 state is stored as a single candid value written with [`ic_storage::stable::write`] at the
+//! start of the stable memory. The current state is kept in stable structures, which use the same
+//! stable memory through the memory manager, so the V1 state must be read in the `post_upgrade`
+//! method before any stable structure is accessed. Call [`migrate`] as the first thing in the
+//! `post_upgrade` method of the factory canister.
+//!
+//! The migration can be done as a dry run: the V1 configuration and canister entries are kept
+//! pending without changing the factory state, and the owner of the V1 state reviews them with
+//! `get_v1_migration_plan` and writes them with `apply_v1_migration`.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
 
+use candid::{Decode, Encode};
 use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{BoundedStorable, MemoryId, StableCell, Storable};
 use ic_storage::stable::Versioned;
 use ic_storage::IcStorage;
 
-use super::FactoryConfiguration;
+use super::{factory_state, update_config, Authorized, CanisterHash, FactoryConfiguration, Owner};
+use crate::error::FactoryError;
+use crate::registry::{self, CanisterMetadata};
 use crate::types::{Canister, Checksum};
 
+const PENDING_MIGRATION_MEMORY_ID: MemoryId = MemoryId::new(18);
+
 #[derive(CandidType, Deserialize, IcStorage, Default)]
 pub struct FactoryStateV1 {
     pub configuration: FactoryConfiguration,
@@ -26,3 +46,286 @@ pub struct Factory {
     pub canisters: HashMap<Principal, Canister>,
     pub checksum: Checksum,
 }
+
+/// A canister entry of the V1 state converted to the current state layout.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq, Eq)]
+pub struct MigratedCanister {
+    pub canister_id: Principal,
+    /// Version of the canister module.
+    pub version: u32,
+    /// Hash of the canister module. V1 state stores only the hash of the latest module version,
+    /// so the hash of the outdated canisters is unknown. Such canisters are registered with a
+    /// zeroed hash and are upgraded by the next `upgrade_canister` call.
+    pub hash: Option<Vec<u8>>,
+    /// The canister is already in the factory registry and will be overwritten.
+    pub registered: bool,
+}
+
+/// Configuration and canister entries of the V1 state to be written to the factory state.
+#[derive(Debug, CandidType, Deserialize, Clone, Default)]
+pub struct V1Migration {
+    pub configuration: FactoryConfiguration,
+    /// Version of the latest canister module in V1 state.
+    pub module_version: u32,
+    pub canisters: Vec<MigratedCanister>,
+}
+
+impl V1Migration {
+    /// Converts the V1 state, with the canister entries ordered by canister id.
+    pub fn new(state: &FactoryStateV1) -> Self {
+        let factory = &state.factory;
+        let latest_hash = (factory.checksum.hash.len() == CanisterHash::MAX_SIZE as usize)
+            .then(|| factory.checksum.hash.clone());
+
+        let mut canisters: Vec<_> = factory
+            .canisters
+            .values()
+            .map(|canister| MigratedCanister {
+                canister_id: canister.identity(),
+                version: to_u32(canister.version()),
+                hash: latest_hash
+                    .clone()
+                    .filter(|_| canister.version() == factory.checksum.version),
+                registered: false,
+            })
+            .collect();
+        canisters.sort_by_key(|canister| canister.canister_id);
+
+        Self {
+            configuration: state.configuration.clone(),
+            module_version: to_u32(factory.checksum.version),
+            canisters,
+        }
+    }
+
+    /// Updates the `registered` flags with the current factory registry.
+    fn refresh(mut self) -> Self {
+        let state = factory_state();
+        for canister in &mut self.canisters {
+            canister.registered = state.is_registered(canister.canister_id);
+        }
+
+        self
+    }
+
+    fn apply(&self) {
+        update_config(|cfg| *cfg = self.configuration.clone());
+        let mut state = factory_state();
+        for canister in &self.canisters {
+            let hash = canister
+                .hash
+                .as_deref()
+                .map(CanisterHash::from)
+                .unwrap_or_else(|| CanisterHash(vec![0; CanisterHash::MAX_SIZE as usize]));
+            state.insert_canister(canister.canister_id, hash);
+            registry::insert_metadata(
+                canister.canister_id,
+                CanisterMetadata::new(None, None, Some(canister.version)),
+            );
+        }
+    }
+}
+
+fn to_u32(version: usize) -> u32 {
+    u32::try_from(version).unwrap_or(u32::MAX)
+}
+
+/// Reads the V1 state from the stable memory. Returns `None` if the stable memory doesn't contain
+/// V1 state. See the module documentation for the restrictions on when it can be called.
+pub fn read_state() -> Option<FactoryStateV1> {
+    ic_storage::stable::read::<FactoryStateV1>()
+        .ok()
+        .filter(|state| state.configuration.controller != Principal::anonymous())
+}
+
+/// Returns the migration of the V1 state in the stable memory without changing the factory state,
+/// or `None` if there is no V1 state. See the module documentation for the restrictions on when it
+/// can be called.
+pub fn plan() -> Option<V1Migration> {
+    read_state().map(|state| V1Migration::new(&state))
+}
+
+/// Migrates the V1 state to the current state layout. Must be called in the `post_upgrade` method
+/// before any stable structure is accessed.
+///
+/// If `apply` is `true`, the factory configuration and the canister entries are written to the
+/// factory state. Otherwise the migration is a dry run: the factory state is not changed, and the
+/// migration is kept pending, so the owner of the V1 state can review it with
+/// `get_v1_migration_plan` and apply it with `apply_v1_migration`.
+///
+/// Returns `None` if there is no V1 state in the stable memory.
+pub fn migrate(apply: bool) -> Option<V1Migration> {
+    let migration = plan()?;
+    match apply {
+        true => migration.apply(),
+        false => set_pending(Some(migration.clone())),
+    }
+
+    Some(migration)
+}
+
+/// Returns the canister entries of the V1 state that are not written to the factory registry yet.
+pub fn pending_migration() -> Option<V1Migration> {
+    PENDING_MIGRATION_CELL
+        .with(|cell| cell.borrow().get().0.clone())
+        .map(V1Migration::refresh)
+}
+
+/// Checks that the caller can review and apply the pending migration. Besides the factory owners,
+/// the controller of the V1 state can do it, as it becomes the factory owner only when the
+/// migration is applied.
+///
+/// # Errors
+///
+/// Returns `FactoryError::AccessDenied` if the caller is neither the factory owner nor the V1
+/// state controller.
+pub fn check_migration_owner(caller: Principal) -> Result<Authorized<Owner>, FactoryError> {
+    let v1_controller = PENDING_MIGRATION_CELL.with(|cell| {
+        cell.borrow()
+            .get()
+            .0
+            .as_ref()
+            .map(|migration| migration.configuration.controller)
+    });
+
+    match v1_controller {
+        Some(controller) if controller == caller => Ok(Authorized {
+            _auth: Owner::default(),
+        }),
+        _ => factory_state().check_is_owner_internal(caller),
+    }
+}
+
+fn set_pending(migration: Option<V1Migration>) {
+    PENDING_MIGRATION_CELL.with(|cell| {
+        cell.borrow_mut()
+            .set(PendingMigration(migration))
+            .expect("failed to set pending migration to stable storage")
+    });
+}
+
+impl Authorized<Owner> {
+    /// Writes the pending V1 configuration and canister entries to the factory state.
+    ///
+    /// # Errors
+    ///
+    /// Returns `FactoryError::NotFound` if there is no pending migration.
+    pub fn apply_v1_migration(&mut self) -> Result<V1Migration, FactoryError> {
+        factory_state().check_update_allowed()?;
+        let migration = pending_migration().ok_or(FactoryError::NotFound)?;
+        migration.apply();
+        set_pending(None);
+
+        Ok(migration)
+    }
+}
+
+#[derive(Debug, Default, CandidType, Deserialize)]
+struct PendingMigration(Option<V1Migration>);
+
+impl Storable for PendingMigration {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Encode!(self)
+            .expect("failed to serialize pending migration")
+            .into()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to deserialize pending migration")
+    }
+}
+
+thread_local! {
+    static PENDING_MIGRATION_CELL: RefCell<StableCell<PendingMigration>> = {
+        RefCell::new(StableCell::new(PENDING_MIGRATION_MEMORY_ID, PendingMigration(None))
+            .expect("failed to initialize pending migration"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    #[test]
+    fn outdated_canisters_have_no_hash() {
+        MockContext::new().inject();
+        let current = Principal::from_slice(&[1]);
+        let outdated = Principal::from_slice(&[2]);
+        let state = FactoryStateV1 {
+            configuration: FactoryConfiguration::default(),
+            factory: Factory {
+                canisters: HashMap::from([
+                    (outdated, Canister::new(outdated, 1)),
+                    (current, Canister::new(current, 2)),
+                ]),
+                checksum: Checksum {
+                    version: 2,
+                    hash: vec![7; 32],
+                },
+            },
+        };
+
+        let migration = V1Migration::new(&state);
+        assert_eq!(migration.module_version, 2);
+        assert_eq!(migration.canisters[0].canister_id, current);
+        assert_eq!(migration.canisters[0].hash, Some(vec![7; 32]));
+        assert_eq!(migration.canisters[1].version, 1);
+        assert_eq!(migration.canisters[1].hash, None);
+
+        set_pending(Some(migration));
+        factory_state()
+            .check_is_owner_internal(Principal::anonymous())
+            .unwrap()
+            .apply_v1_migration()
+            .unwrap();
+
+        assert!(pending_migration().is_none());
+        let canisters = factory_state().canisters();
+        assert_eq!(canisters[&current].0, vec![7; 32]);
+        assert_eq!(canisters[&outdated].0, vec![0; 32]);
+    }
+
+    #[test]
+    fn dry_run_keeps_configuration() {
+        MockContext::new().inject();
+        let v1_controller = Principal::from_slice(&[1]);
+        let canister_id = Principal::from_slice(&[2]);
+        let state = FactoryStateV1 {
+            configuration: FactoryConfiguration::new(
+                Principal::from_slice(&[3]),
+                100,
+                v1_controller,
+                v1_controller,
+            ),
+            factory: Factory {
+                canisters: HashMap::from([(canister_id, Canister::new(canister_id, 1))]),
+                checksum: Checksum {
+                    version: 1,
+                    hash: vec![7; 32],
+                },
+            },
+        };
+        ic_storage::stable::write(&state).unwrap();
+
+        let planned = plan().unwrap();
+        assert_eq!(planned.configuration.controller, v1_controller);
+        let migration = migrate(false).unwrap();
+        assert_eq!(migration.canisters, planned.canisters);
+
+        assert_eq!(factory_state().controller(), Principal::anonymous());
+        assert!(!factory_state().is_registered(canister_id));
+        assert!(check_migration_owner(Principal::from_slice(&[4])).is_err());
+
+        check_migration_owner(v1_controller)
+            .unwrap()
+            .apply_v1_migration()
+            .unwrap();
+
+        assert_eq!(factory_state().controller(), v1_controller);
+        assert_eq!(factory_state().icp_fee(), 100);
+        assert!(factory_state().is_registered(canister_id));
+        assert!(pending_migration().is_none());
+    }
+}
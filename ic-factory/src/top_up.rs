@@ -1,15 +1,8 @@
 use candid::Principal;
-use ic_canister::virtual_canister_call;
-use ic_exports::cycles_minting_canister::{
-    IcpXdrConversionRateCertifiedResponse, NotifyError, NotifyTopUp, DEFAULT_CYCLES_PER_XDR,
-    MEMO_TOP_UP_CANISTER,
-};
-use ic_exports::ic_base_types::{CanisterId, PrincipalId};
-use ic_exports::ledger::{
-    AccountIdentifier, Subaccount, Tokens, TransferArgs, TransferError, DEFAULT_TRANSFER_FEE,
-    TOKEN_SUBDIVIDABLE_BY,
-};
+use ic_exports::cycles_minting_canister::DEFAULT_CYCLES_PER_XDR;
+use ic_exports::ledger::{Subaccount, TOKEN_SUBDIVIDABLE_BY};
 use ic_exports::BlockHeight;
+use ic_helpers::cmc;
 
 use crate::error::FactoryError;
 
@@ -19,7 +12,9 @@ pub(crate) const CYCLES_MINTING_CANISTER: Principal =
 
 /// Calculates amount of ICP that can be converted to the given amount of cycles
 pub async fn icp_amount_from_cycles(cmc: Principal, cycles: u64) -> Result<u64, FactoryError> {
-    let rate = get_conversion_rate(cmc).await?.data;
+    let rate = cmc::conversion_rate(cmc)
+        .await
+        .map_err(FactoryError::GenericError)?;
 
     if rate.xdr_permyriad_per_icp == 0 {
         return Err(FactoryError::GenericError(
@@ -36,71 +31,61 @@ fn calculate_icp(cycles: u64, xdr_permyriad_per_icp: u64) -> u64 {
         / xdr_permyriad_per_icp as u128) as u64
 }
 
-async fn get_conversion_rate(
+pub(crate) async fn transfer_icp_to_cmc(
     cmc: Principal,
-) -> Result<IcpXdrConversionRateCertifiedResponse, FactoryError> {
-    virtual_canister_call!(
+    amount: u64,
+    ledger: Principal,
+    caller_subaccount: Subaccount,
+) -> Result<BlockHeight, FactoryError> {
+    transfer_icp_to_cmc_for(
         cmc,
-        "get_icp_xdr_conversion_rate",
-        (),
-        IcpXdrConversionRateCertifiedResponse
+        amount,
+        ledger,
+        caller_subaccount,
+        ic_exports::ic_cdk::id(),
     )
     .await
-    .map_err(|e| FactoryError::GenericError(e.1))
 }
 
-pub(crate) async fn transfer_icp_to_cmc(
+/// Transfers ICP to the CMC top up account of the `canister_id` canister. Cycles can be minted
+/// to the canister after this with [`mint_cycles_to_canister`].
+pub(crate) async fn transfer_icp_to_cmc_for(
     cmc: Principal,
     amount: u64,
     ledger: Principal,
-    caller_subaccount: Subaccount,
+    from_subaccount: Subaccount,
+    canister_id: Principal,
 ) -> Result<BlockHeight, FactoryError> {
-    let canister_id = ic_exports::ic_cdk::id();
-    let to = AccountIdentifier::new(cmc.into(), Some((&PrincipalId::from(canister_id)).into()))
-        .to_address();
-
-    let args = TransferArgs {
-        memo: MEMO_TOP_UP_CANISTER,
-        amount: Tokens::from_e8s(amount - DEFAULT_TRANSFER_FEE.get_e8s()),
-        fee: DEFAULT_TRANSFER_FEE,
-        from_subaccount: Some(caller_subaccount),
-        to,
-        created_at_time: None,
-    };
-
-    virtual_canister_call!(ledger, "transfer", (args,), Result<BlockHeight, TransferError>)
+    cmc::transfer_icp_to_cmc(ledger, cmc, amount, Some(from_subaccount), canister_id)
         .await
-        .map_err(|e| FactoryError::LedgerError(e.1))?
-        .map_err(|e| FactoryError::LedgerError(format!("{:?}", e)))
+        .map_err(FactoryError::LedgerError)
 }
 
 pub(crate) async fn mint_cycles_to_factory(
     cmc: Principal,
     block_height: BlockHeight,
 ) -> Result<u128, FactoryError> {
-    let to_canister =
-        CanisterId::new(ic_exports::ic_kit::ic::id().into()).expect("const conversion");
-
-    let notify_details = NotifyTopUp {
-        block_index: block_height,
-        canister_id: to_canister,
-    };
+    mint_cycles_to_canister(cmc, block_height, ic_exports::ic_kit::ic::id()).await
+}
 
-    virtual_canister_call!(
-        cmc,
-        "notify_top_up",
-        (notify_details,),
-        Result<u128, NotifyError>
-    )
-    .await
-    .map_err(|e| FactoryError::GenericError(e.1))?
-    .map_err(|e| FactoryError::GenericError(format!("{:?}", e)))
+/// Notifies the CMC about the top up transfer made by [`transfer_icp_to_cmc_for`], so that the
+/// cycles are minted to the `canister_id` canister.
+pub(crate) async fn mint_cycles_to_canister(
+    cmc: Principal,
+    block_height: BlockHeight,
+    canister_id: Principal,
+) -> Result<u128, FactoryError> {
+    cmc::notify_top_up(cmc, block_height, canister_id)
+        .await
+        .map_err(FactoryError::GenericError)
 }
 
 #[cfg(test)]
 mod tests {
     use ic_canister::register_virtual_responder;
-    use ic_exports::cycles_minting_canister::IcpXdrConversionRate;
+    use ic_exports::cycles_minting_canister::{
+        IcpXdrConversionRate, IcpXdrConversionRateCertifiedResponse, NotifyError,
+    };
     use ic_exports::ic_kit::MockContext;
 
     use super::*;
@@ -2,8 +2,10 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use ic_exports::ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_exports::ic_kit::ic;
 
 use crate::error::FactoryError;
+use crate::operations;
 
 /// A guard to prevent factory state changes while an async operation is in process.
 ///
@@ -14,10 +16,24 @@ use crate::error::FactoryError;
 /// We need to use this lock type instead of relying on `RefCell` borrowed `Ref` as a lock because
 /// it is possible in IC environment to get the canister to a state when the `Ref` object is lost
 /// without dropping it, which makes the state locked forever. The `UpdateLock` allows to fix such
-/// state by calling the [`unlock`] method.
+/// state by calling the [`unlock`] method, or by taking the lock with [`lock_at`] after the lock
+/// timeout. The timeout is counted from the last time the lock was locked or refreshed, and the
+/// lock is refreshed every time its operation starts a new step with [`set_step`].
 #[derive(Debug, Default)]
 pub struct UpdateLock {
-    is_locked: Rc<RefCell<bool>>,
+    state: Rc<RefCell<LockState>>,
+    /// Number of the locking this object was returned by.
+    generation: u64,
+    /// Id of the operation record removed when this object is dropped.
+    operation_id: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    is_locked: bool,
+    generation: u64,
+    locked_at: u64,
+    operation_id: Option<u64>,
 }
 
 impl UpdateLock {
@@ -29,19 +45,83 @@ impl UpdateLock {
     /// If the lock is already in the locked state, calling `lock` will return
     /// `Err(FactoryError::StateLocked)` error.
     pub fn lock(&self) -> Result<Self, FactoryError> {
-        if *self.is_locked.borrow() {
+        self.lock_at(0, None)
+    }
+
+    /// Same as [`lock`], but if the lock is not locked or refreshed for more than `timeout`
+    /// nanoseconds at time `now`, the lock is considered expired and is taken over. The expired
+    /// lock object stops holding the lock, so dropping it doesn't unlock the new lock.
+    pub fn lock_at(&self, now: u64, timeout: Option<u64>) -> Result<Self, FactoryError> {
+        if self.is_locked() && !self.is_expired(now, timeout) {
             return Err(FactoryError::StateLocked);
         }
 
-        self.is_locked.replace(true);
+        let mut state = self.state.borrow_mut();
+        state.is_locked = true;
+        state.generation += 1;
+        state.locked_at = now;
+        state.operation_id = None;
+
         Ok(Self {
-            is_locked: self.is_locked.clone(),
+            state: self.state.clone(),
+            generation: state.generation,
+            operation_id: None,
         })
     }
 
+    /// Attaches the operation record to the lock. The record is removed when the lock object is
+    /// dropped.
+    pub(crate) fn with_operation(mut self, operation_id: u64) -> Self {
+        if self.is_held() {
+            self.state.borrow_mut().operation_id = Some(operation_id);
+        }
+
+        self.operation_id = Some(operation_id);
+        self
+    }
+
     /// Returns if the lock is locked.
     pub fn is_locked(&self) -> bool {
-        *self.is_locked.borrow()
+        self.state.borrow().is_locked
+    }
+
+    /// Returns `true` if the lock is locked and was not locked or refreshed for more than `timeout`
+    /// nanoseconds at time `now`.
+    pub fn is_expired(&self, now: u64, timeout: Option<u64>) -> bool {
+        let state = self.state.borrow();
+        state.is_locked
+            && timeout.map_or(false, |timeout| {
+                now.saturating_sub(state.locked_at) > timeout
+            })
+    }
+
+    /// Returns `true` if this object is the one holding the lock. This is `false` for the lock
+    /// objects which lock was expired and taken over or reset with [`unlock`].
+    pub fn is_held(&self) -> bool {
+        let state = self.state.borrow();
+        state.is_locked && state.generation == self.generation
+    }
+
+    /// Id of the operation record attached to the lock, if the lock is locked.
+    pub fn operation_id(&self) -> Option<u64> {
+        let state = self.state.borrow();
+        state.operation_id.filter(|_| state.is_locked)
+    }
+
+    /// Moves the time the lock expiration is counted from to `now`, if this object holds the lock.
+    pub fn refresh_at(&self, now: u64) {
+        if self.is_held() {
+            self.state.borrow_mut().locked_at = now;
+        }
+    }
+
+    /// Updates the current step of the operation attached to the lock, and refreshes the lock, so
+    /// that it doesn't expire while the operation makes progress.
+    pub(crate) fn set_step(&self, step: String) {
+        self.refresh_at(ic::time());
+        if let Some(operation_id) = self.operation_id {
+            operations::set_step(operation_id, step);
+        }
     }
 
     /// Resets the state of the lock to be unlocked.
@@ -50,19 +130,27 @@ impl UpdateLock {
     /// panic occurs after `await` in an async update method, while the lock is not released. That
     /// is the reason why this method is `pub(crate)` - to limit the places where it can be used.
     pub(crate) fn unlock(&self) {
-        self.is_locked.replace(false);
+        let mut state = self.state.borrow_mut();
+        state.is_locked = false;
+        state.operation_id = None;
     }
 }
 
 impl Drop for UpdateLock {
     fn drop(&mut self) {
-        self.is_locked.replace(false);
+        if self.is_held() {
+            self.unlock();
+        }
+
+        if let Some(operation_id) = self.operation_id {
+            operations::finish(operation_id);
+        }
     }
 }
 
 impl PartialEq for UpdateLock {
     fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.is_locked, &other.is_locked)
+        Rc::ptr_eq(&self.state, &other.state)
     }
 }
 
@@ -75,7 +163,7 @@ impl CandidType for UpdateLock {
     where
         S: candid::types::Serializer,
     {
-        (*self.is_locked.borrow()).idl_serialize(serializer)
+        self.is_locked().idl_serialize(serializer)
     }
 }
 
@@ -86,7 +174,12 @@ impl<'de> Deserialize<'de> for UpdateLock {
     {
         let val = bool::deserialize(deserializer)?;
         Ok(Self {
-            is_locked: Rc::new(RefCell::new(val)),
+            state: Rc::new(RefCell::new(LockState {
+                is_locked: val,
+                ..Default::default()
+            })),
+            generation: 0,
+            operation_id: None,
         })
     }
 }
@@ -129,6 +222,41 @@ mod tests {
         assert!(matches!(original.lock(), Err(FactoryError::StateLocked)));
     }
 
+    #[test]
+    fn expired_lock_takeover() {
+        let original = UpdateLock::default();
+        let old_lock = original.lock_at(100, Some(10)).unwrap();
+
+        assert!(matches!(
+            original.lock_at(110, Some(10)),
+            Err(FactoryError::StateLocked)
+        ));
+
+        let new_lock = original.lock_at(111, Some(10)).unwrap();
+        assert!(!old_lock.is_held());
+        assert!(new_lock.is_held());
+
+        drop(old_lock);
+        assert!(original.is_locked());
+
+        drop(new_lock);
+        assert!(!original.is_locked());
+    }
+
+    #[test]
+    fn refreshed_lock_is_not_expired() {
+        let original = UpdateLock::default();
+        let lock = original.lock_at(100, Some(10)).unwrap();
+        lock.refresh_at(105);
+
+        assert!(!original.is_expired(111, Some(10)));
+        assert!(matches!(
+            original.lock_at(111, Some(10)),
+            Err(FactoryError::StateLocked)
+        ));
+        assert!(original.is_expired(116, Some(10)));
+    }
+
     #[test]
     fn admin_unlocking() {
         let original = UpdateLock::default();
@@ -0,0 +1,98 @@
+//! Minting cycles for ICP with the cycles minting canister (CMC).
+//!
+//! To mint cycles to a canister, ICP is transferred to the CMC top up account of the canister with
+//! [`transfer_icp_to_cmc`], and then the CMC is notified about the transfer with
+//! [`notify_top_up`].
+
+use ic_canister::virtual_canister_call;
+use ic_exports::cycles_minting_canister::{
+    IcpXdrConversionRate, IcpXdrConversionRateCertifiedResponse, NotifyError, NotifyTopUp,
+    DEFAULT_CYCLES_PER_XDR, MEMO_TOP_UP_CANISTER,
+};
+use ic_exports::ic_base_types::{CanisterId, PrincipalId};
+use ic_exports::ic_cdk::export::candid::Principal;
+use ic_exports::ledger::{
+    AccountIdentifier, Subaccount, Tokens, TransferArgs, TransferError, DEFAULT_TRANSFER_FEE,
+    TOKEN_SUBDIVIDABLE_BY,
+};
+use ic_exports::BlockHeight;
+
+/// Returns the current ICP/XDR conversion rate of the CMC.
+pub async fn conversion_rate(cmc: Principal) -> Result<IcpXdrConversionRate, String> {
+    virtual_canister_call!(
+        cmc,
+        "get_icp_xdr_conversion_rate",
+        (),
+        IcpXdrConversionRateCertifiedResponse
+    )
+    .await
+    .map(|response| response.data)
+    .map_err(|e| e.1)
+}
+
+/// Returns the amount of cycles the CMC mints for `icp` e8s with the given conversion rate.
+pub fn icp_to_cycles(icp: u64, xdr_permyriad_per_icp: u64) -> u128 {
+    icp as u128 * xdr_permyriad_per_icp as u128 * DEFAULT_CYCLES_PER_XDR
+        / (TOKEN_SUBDIVIDABLE_BY as u128 * 10_000)
+}
+
+/// Transfers `amount` of ICP e8s from the `from_subaccount` of the calling canister to the CMC
+/// top up account of the `canister_id` canister. The ledger fee is deducted from the amount.
+/// Returns the block height of the transfer.
+pub async fn transfer_icp_to_cmc(
+    ledger: Principal,
+    cmc: Principal,
+    amount: u64,
+    from_subaccount: Option<Subaccount>,
+    canister_id: Principal,
+) -> Result<BlockHeight, String> {
+    if amount < DEFAULT_TRANSFER_FEE.get_e8s() {
+        return Err(format!(
+            "cannot transfer tokens: amount '{}' is less then the fee '{}'",
+            amount,
+            DEFAULT_TRANSFER_FEE.get_e8s()
+        ));
+    }
+
+    let to = AccountIdentifier::new(cmc.into(), Some((&PrincipalId::from(canister_id)).into()))
+        .to_address();
+
+    let args = TransferArgs {
+        memo: MEMO_TOP_UP_CANISTER,
+        amount: Tokens::from_e8s(amount - DEFAULT_TRANSFER_FEE.get_e8s()),
+        fee: DEFAULT_TRANSFER_FEE,
+        from_subaccount,
+        to,
+        created_at_time: None,
+    };
+
+    virtual_canister_call!(ledger, "transfer", (args,), Result<BlockHeight, TransferError>)
+        .await
+        .map_err(|e| e.1)?
+        .map_err(|e| format!("{e:?}"))
+}
+
+/// Notifies the CMC about the top up transfer made by [`transfer_icp_to_cmc`], so that the
+/// cycles are minted to the `canister_id` canister. Returns the amount of minted cycles.
+///
+/// The CMC returns the same result for repeated notifications about the same transfer.
+pub async fn notify_top_up(
+    cmc: Principal,
+    block_height: BlockHeight,
+    canister_id: Principal,
+) -> Result<u128, String> {
+    let notify_details = NotifyTopUp {
+        block_index: block_height,
+        canister_id: CanisterId::new(canister_id.into()).expect("const conversion"),
+    };
+
+    virtual_canister_call!(
+        cmc,
+        "notify_top_up",
+        (notify_details,),
+        Result<u128, NotifyError>
+    )
+    .await
+    .map_err(|e| e.1)?
+    .map_err(|e| format!("{e:?}"))
+}
@@ -10,3 +10,5 @@ pub mod types;
 pub use types::*;
 
 pub mod tokens;
+
+pub mod cmc;
@@ -27,7 +27,7 @@ pub type CanisterID = Principal;
 pub type UserID = Principal;
 pub type WasmModule = Vec<u8>;
 
-#[derive(CandidType, Clone, Deserialize, Default)]
+#[derive(Debug, CandidType, Clone, Deserialize, Default)]
 pub struct CanisterSettings {
     pub controllers: Option<Vec<Principal>>,
     pub compute_allocation: Option<Nat>,
@@ -53,7 +53,7 @@ pub enum InstallCodeMode {
     Upgrade,
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum CanisterStatusKind {
     #[serde(rename = "running")]
     Running,
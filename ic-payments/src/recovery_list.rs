@@ -1,5 +1,6 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 use candid::Encode;
 use ic_stable_structures::{
@@ -14,9 +15,12 @@ pub trait RecoveryList: Sync + Send {
     fn list(&self) -> Vec<Transfer>;
 }
 
+type TransferMap = StableUnboundedMap<TransferKey, TransferValue>;
+
 thread_local! {
-    static RECOVERY_LIST_STORAGE: RefCell<Option<StableUnboundedMap<TransferKey, TransferValue>>> =
-        RefCell::new(None);
+    /// Recovery lists by their memory ids.
+    static RECOVERY_LIST_STORAGE: RefCell<BTreeMap<u8, TransferMap>> =
+        RefCell::new(BTreeMap::new());
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -69,20 +73,44 @@ impl SlicedStorable for TransferValue {
 #[derive(Debug)]
 pub struct StableRecoveryList<const MEM_ID: u8>;
 
-impl<const MEM_ID: u8> StableRecoveryList<MEM_ID> {
-    fn with_storage<R>(
-        &self,
-        f: impl Fn(&mut StableUnboundedMap<TransferKey, TransferValue>) -> R,
-    ) -> R {
+impl<const MEM_ID: u8> RecoveryList for StableRecoveryList<MEM_ID> {
+    fn push(&mut self, transfer: Transfer) {
+        DynamicStableRecoveryList::new(MEM_ID).push(transfer)
+    }
+
+    fn take_all(&mut self) -> Vec<Transfer> {
+        DynamicStableRecoveryList::new(MEM_ID).take_all()
+    }
+
+    fn list(&self) -> Vec<Transfer> {
+        DynamicStableRecoveryList::new(MEM_ID).list()
+    }
+}
+
+/// Recovery list stored in the stable memory like [`StableRecoveryList`], but with the memory id
+/// chosen at runtime.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicStableRecoveryList {
+    memory_id: u8,
+}
+
+impl DynamicStableRecoveryList {
+    pub fn new(memory_id: u8) -> Self {
+        Self { memory_id }
+    }
+
+    fn with_storage<R>(&self, f: impl Fn(&mut TransferMap) -> R) -> R {
         RECOVERY_LIST_STORAGE.with(|v| {
-            let mut map = v.borrow_mut();
-            let map = map.get_or_insert_with(|| StableUnboundedMap::new(MemoryId::new(MEM_ID)));
+            let mut storage = v.borrow_mut();
+            let map = storage
+                .entry(self.memory_id)
+                .or_insert_with(|| StableUnboundedMap::new(MemoryId::new(self.memory_id)));
             f(map)
         })
     }
 }
 
-impl<const MEM_ID: u8> RecoveryList for StableRecoveryList<MEM_ID> {
+impl RecoveryList for DynamicStableRecoveryList {
     fn push(&mut self, transfer: Transfer) {
         self.with_storage(|m| {
             let key = TransferKey::new(&transfer);
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::ops::RangeBounds;
 
 use ic_exports::ic_kit::ic;
 use ic_exports::stable_structures::memory_manager::MemoryId;
@@ -68,6 +69,11 @@ where
         self.get_inner().iter()
     }
 
+    /// Iterate over the key-value pairs with keys in the given range, in the key order.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> btreemap::Iter<'_, K, V, Memory> {
+        self.get_inner().range(key_range)
+    }
+
     /// Count of items in the map.
     pub fn len(&self) -> u64 {
         self.get_inner().len()
@@ -123,6 +129,10 @@ mod tests {
         assert_eq!(iter.next(), Some((10, 100)));
         assert_eq!(iter.next(), None);
 
+        let mut range = map.range(1..);
+        assert_eq!(range.next(), Some((10, 100)));
+        assert_eq!(range.next(), None);
+
         assert_eq!(map.remove(&10), Some(100));
 
         assert_eq!(map.len(), 1);
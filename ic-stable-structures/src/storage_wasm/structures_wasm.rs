@@ -1,3 +1,5 @@
+use std::ops::RangeBounds;
+
 use ic_exports::stable_structures::memory_manager::MemoryId;
 use ic_exports::stable_structures::{btreemap, cell, log, BoundedStorable, Storable};
 
@@ -70,6 +72,11 @@ where
         self.0.iter()
     }
 
+    /// Iterate over the key-value pairs with keys in the given range, in the key order.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> btreemap::Iter<'_, K, V, Memory> {
+        self.0.range(key_range)
+    }
+
     /// Count of items in the map.
     pub fn len(&self) -> u64 {
         self.0.len()